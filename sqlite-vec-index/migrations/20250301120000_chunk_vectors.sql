@@ -0,0 +1,11 @@
+-- Embeddings for every chunk in `chunk_cache`, one row per chunk.
+--
+-- Rows here are only ever written in the same transaction as the matching
+-- `chunk_cache` row, see `FileCache::batched_embed_or_flush_queue`.
+CREATE VIRTUAL TABLE IF NOT EXISTS chunk_vectors USING vec0(
+    chunk_id TEXT PRIMARY KEY,
+    repo_ref TEXT PARTITION KEY,
+    content_hash TEXT,
+    embedding FLOAT[384] distance_metric=cosine,
+    +payload TEXT
+);
@@ -0,0 +1,44 @@
+-- Quantised storage for repositories which opt into it, see
+-- `VectorStorage`. The KNN runs over these, and the candidates are
+-- rescored with the full precision vectors in `chunk_vectors_full`.
+CREATE VIRTUAL TABLE IF NOT EXISTS chunk_vectors_int8 USING vec0(
+    chunk_id TEXT PRIMARY KEY,
+    repo_ref TEXT PARTITION KEY,
+    content_hash TEXT,
+    embedding INT8[384],
+    +payload TEXT
+);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS chunk_vectors_bit USING vec0(
+    chunk_id TEXT PRIMARY KEY,
+    repo_ref TEXT PARTITION KEY,
+    content_hash TEXT,
+    embedding BIT[384],
+    +payload TEXT
+);
+
+CREATE TABLE IF NOT EXISTS chunk_vectors_full (
+    chunk_id TEXT PRIMARY KEY NOT NULL,
+    repo_ref TEXT NOT NULL,
+    content_hash TEXT NOT NULL,
+    embedding BLOB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS chunk_vectors_full_repo_ref
+    ON chunk_vectors_full (repo_ref, content_hash);
+
+-- Every vector we have, whichever way it is stored
+CREATE VIEW IF NOT EXISTS chunk_vectors_all AS
+    SELECT chunk_id, repo_ref, content_hash, payload,
+           embedding AS full_embedding,
+           vec_length(embedding) AS dimensions,
+           'float' AS storage
+      FROM chunk_vectors
+    UNION ALL
+    SELECT q.chunk_id, q.repo_ref, q.content_hash, q.payload,
+           f.embedding, vec_length(q.embedding), 'int8'
+      FROM chunk_vectors_int8 q LEFT JOIN chunk_vectors_full f USING (chunk_id)
+    UNION ALL
+    SELECT q.chunk_id, q.repo_ref, q.content_hash, q.payload,
+           f.embedding, vec_length(q.embedding), 'binary'
+      FROM chunk_vectors_bit q LEFT JOIN chunk_vectors_full f USING (chunk_id);
@@ -0,0 +1,11 @@
+-- Files which an unfinished sync has fully chunked and embedded. They are
+-- moved into `file_cache` when the sync completes, and let an interrupted
+-- sync skip them when it starts again.
+CREATE TABLE IF NOT EXISTS index_checkpoints (
+    repo_ref TEXT NOT NULL,
+    semantic_search_hash TEXT NOT NULL,
+    commit_hash TEXT NOT NULL,
+    file_path TEXT NOT NULL,
+    file_content_hash TEXT NOT NULL,
+    PRIMARY KEY (repo_ref, semantic_search_hash)
+);
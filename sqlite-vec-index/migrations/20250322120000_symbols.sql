@@ -0,0 +1,16 @@
+-- Symbol definitions found while indexing. Rows are keyed by the same
+-- absolute file path as `code_snippet_cache`, so they're replaced exactly
+-- when the file they came from is re-indexed.
+CREATE TABLE IF NOT EXISTS symbols (
+    repo_ref TEXT NOT NULL,
+    file_path TEXT NOT NULL,
+    relative_path TEXT NOT NULL,
+    name TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    container TEXT,
+    start_line INTEGER NOT NULL,
+    end_line INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS symbols_by_name ON symbols (repo_ref, name COLLATE NOCASE);
+CREATE INDEX IF NOT EXISTS symbols_by_file ON symbols (repo_ref, file_path);
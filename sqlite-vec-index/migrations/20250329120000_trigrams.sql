@@ -0,0 +1,21 @@
+-- Trigrams of the files in the snippet index, for narrowing down which
+-- files a regex search has to read. Maintained together with
+-- `code_snippet_cache`, and keyed by the same absolute file path.
+CREATE TABLE IF NOT EXISTS trigram_files (
+    repo_ref TEXT NOT NULL,
+    file_path TEXT NOT NULL,
+    relative_path TEXT NOT NULL,
+    PRIMARY KEY (repo_ref, file_path)
+);
+
+-- One row for every distinct trigram of a file, case folded to ASCII
+-- lowercase and packed into an integer.
+CREATE TABLE IF NOT EXISTS trigram_postings (
+    repo_ref TEXT NOT NULL,
+    trigram INTEGER NOT NULL,
+    file_path TEXT NOT NULL,
+    PRIMARY KEY (repo_ref, trigram, file_path)
+) WITHOUT ROWID;
+
+-- Files are re-indexed one at a time, which replaces all of their postings
+CREATE INDEX IF NOT EXISTS trigram_postings_by_file ON trigram_postings (repo_ref, file_path);
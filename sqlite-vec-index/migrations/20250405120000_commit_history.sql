@@ -0,0 +1,25 @@
+-- The commit history of repositories which opt into it, see
+-- `semantic_search::history`. Touched paths are a JSON array.
+CREATE TABLE IF NOT EXISTS commits (
+    repo_ref TEXT NOT NULL,
+    commit_hash TEXT NOT NULL,
+    author_name TEXT NOT NULL,
+    author_email TEXT NOT NULL,
+    committed_at INTEGER NOT NULL,
+    summary TEXT NOT NULL,
+    message TEXT NOT NULL,
+    paths TEXT NOT NULL,
+    PRIMARY KEY (repo_ref, commit_hash)
+);
+
+CREATE INDEX IF NOT EXISTS commits_by_date ON commits (repo_ref, committed_at);
+
+-- One vector for the message of every commit, and optionally one for the
+-- changes to each file it touched, in which case `relative_path` is set.
+CREATE VIRTUAL TABLE IF NOT EXISTS commit_vectors USING vec0(
+    entry_id TEXT PRIMARY KEY,
+    repo_ref TEXT PARTITION KEY,
+    commit_hash TEXT,
+    embedding FLOAT[384] distance_metric=cosine,
+    +relative_path TEXT
+);
@@ -1,27 +1,114 @@
 use either::Either;
-use std::collections::VecDeque;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{future::Future, pin::Pin};
 use thread_priority::ThreadBuilderExt;
+use tokio::sync::Notify;
 use tokio::sync::OwnedSemaphorePermit;
 use tokio::sync::Semaphore;
 use tracing::debug;
 use tracing::error;
 use tracing::info;
-
+use tracing::trace;
+
+use crate::indexes::gc::{self, GcReport};
+use crate::indexes::integrity::{self, IntegrityReport};
+use crate::indexes;
+use crate::indexes::snapshot::{self, SnapshotSummary};
+use crate::indexes::snippet::SnippetDocument;
+use crate::indexes::symbols::{self, Symbol, SymbolMatch};
+use crate::indexes::trigram;
+use crate::metrics::METRICS;
+use crate::query::parser::ParsedQuery;
+use crate::repo::filesystem::{nested_repositories, SubmodulePolicy};
 use crate::repo::state::RepoError;
 use crate::repo::types::RepoMetadata;
 use crate::repo::types::Repository;
-use crate::repo::types::{RepoRef, SyncStatus};
-use crate::webserver::repos::QueueState;
-use crate::webserver::repos::QueuedRepoStatus;
+use crate::repo::types::{Backend, RepoRef, SyncStatus};
+use crate::semantic_search::duplicates::{self, DuplicateOptions, DuplicateReport};
+use crate::semantic_search::history::{self, CommitMatch, HistoryOptions};
+use crate::semantic_search::recall::{self, RecallReport};
+use crate::semantic_search::schema::Payload;
 
 use super::application::Application;
 use super::config::configuration::Configuration;
 
+/// Give up on a repository after this many failed attempts in a row
+const MAX_SYNC_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry, doubled for every further attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(10);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// A queued repository gains one priority level for every interval it
+/// spends waiting, so background refreshes are not starved by a steady
+/// stream of interactive requests.
+const AGING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the queue re-checks entries it had to skip, because they are
+/// waiting to be retried or the repository is already being synced.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPriority {
+    /// Periodic refreshes, startup scans, nested repositories
+    Background,
+
+    /// Someone is waiting for the result
+    Interactive,
+}
+
+impl SyncPriority {
+    fn level(&self) -> u64 {
+        match self {
+            Self::Background => 0,
+            Self::Interactive => 5,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueueState {
+    Active,
+    Queued,
+
+    /// Failed with a transient error, and waiting to be retried
+    Retrying,
+
+    /// Stopped by a pause, and waiting to be resumed
+    Paused,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct RetrySchedule {
+    /// The attempt which will run next, starting from 1
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub retry_at_unix_secs: u64,
+    pub last_error: String,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct QueuedRepoStatus {
+    pub reporef: RepoRef,
+    pub state: QueueState,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<SyncPriority>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetrySchedule>,
+}
+
 #[derive(serde::Serialize, Clone)]
 pub struct Progress {
     #[serde(rename = "ref")]
@@ -35,6 +122,128 @@ pub struct Progress {
 pub enum ProgressEvent {
     IndexPercent(u8),
     StatusChange(SyncStatus),
+    Phase(PhaseProgress),
+}
+
+/// Don't broadcast phase progress more often than this, unless the phase
+/// changes
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The stages of a sync, in the order they start in.
+///
+/// Files are hashed, chunked and embedded concurrently, so a sync is in the
+/// furthest phase any file has reached, and the counts tell how far along
+/// the rest are.
+#[derive(serde::Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexPhase {
+    GitSync,
+    Walking,
+    Hashing,
+    Chunking,
+    Embedding,
+    Committing,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct PhaseProgress {
+    pub phase: IndexPhase,
+    pub files_done: u64,
+    pub files_total: u64,
+    pub chunks_done: u64,
+    pub chunks_total: u64,
+
+    /// Chunks embedded per second, since the first batch of this sync
+    pub embeddings_per_sec: Option<f32>,
+    pub eta_secs: Option<u64>,
+}
+
+struct PhaseTracker {
+    phase: IndexPhase,
+    started: Instant,
+    files_done: u64,
+    files_total: u64,
+    chunks_done: u64,
+    chunks_total: u64,
+    first_embedding: Option<Instant>,
+    last_sent: Option<Instant>,
+}
+
+impl Default for PhaseTracker {
+    fn default() -> Self {
+        Self {
+            phase: IndexPhase::GitSync,
+            started: Instant::now(),
+            files_done: 0,
+            files_total: 0,
+            chunks_done: 0,
+            chunks_total: 0,
+            first_embedding: None,
+            last_sent: None,
+        }
+    }
+}
+
+impl PhaseTracker {
+    fn embeddings_per_sec(&self) -> Option<f32> {
+        let elapsed = self.first_embedding?.elapsed().as_secs_f32();
+        (elapsed > 0.0).then(|| self.chunks_done as f32 / elapsed)
+    }
+
+    /// Whichever of the files and the chunks will take longer, at the rate
+    /// they're going now
+    fn eta(&self) -> Option<Duration> {
+        let files = {
+            let elapsed = self.started.elapsed().as_secs_f32();
+            let rate = self.files_done as f32 / elapsed;
+            let remaining = self.files_total.saturating_sub(self.files_done) as f32;
+            (rate > 0.0).then(|| remaining / rate)
+        };
+
+        let chunks = self.embeddings_per_sec().and_then(|rate| {
+            let remaining = self.chunks_total.saturating_sub(self.chunks_done) as f32;
+            (rate > 0.0).then(|| remaining / rate)
+        });
+
+        let secs = match (files, chunks) {
+            (Some(a), Some(b)) => a.max(b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => return None,
+        };
+
+        Some(Duration::from_secs_f32(secs))
+    }
+
+    fn snapshot(&self) -> PhaseProgress {
+        PhaseProgress {
+            phase: self.phase,
+            files_done: self.files_done,
+            files_total: self.files_total,
+            chunks_done: self.chunks_done,
+            chunks_total: self.chunks_total,
+            embeddings_per_sec: self.embeddings_per_sec(),
+            eta_secs: match self.phase {
+                IndexPhase::GitSync | IndexPhase::Walking | IndexPhase::Committing => None,
+                _ => self.eta().map(|eta| eta.as_secs()),
+            },
+        }
+    }
+
+    /// A snapshot to broadcast, if it's time for one
+    fn due(&mut self, force: bool) -> Option<PhaseProgress> {
+        let now = Instant::now();
+        let due = force
+            || self
+                .last_sent
+                .map_or(true, |sent| now.duration_since(sent) >= PROGRESS_INTERVAL);
+
+        if !due {
+            return None;
+        }
+
+        self.last_sent = Some(now);
+        Some(self.snapshot())
+    }
 }
 
 // This is just a tokio sender which sends progress events for a given repo and
@@ -50,6 +259,9 @@ pub struct ProgressStreamWithContext {
 pub struct SyncQueue {
     runner: BackgroundExecutor,
     active: Arc<scc::HashMap<RepoRef, Arc<SyncHandle>>>,
+    // paused syncs hold neither a slot nor the index writers while they
+    // wait to be resumed
+    paused: Arc<scc::HashMap<RepoRef, QueuedSync>>,
     tickets: Arc<Semaphore>,
     pub(crate) queue: Arc<NotifyQueue>,
 
@@ -72,6 +284,7 @@ impl SyncQueue {
             tickets: Arc::new(Semaphore::new(config.max_threads)),
             runner: BackgroundExecutor::start(config.clone()),
             active: Default::default(),
+            paused: Default::default(),
             queue: Default::default(),
             progress: progress_stream_with_context,
         };
@@ -83,26 +296,55 @@ impl SyncQueue {
             instance.runner.clone().spawn(async move {
                 while let (Ok(permit), next) = tokio::join!(
                     instance.tickets.clone().acquire_owned(),
-                    instance
-                        .queue
-                        .pop_if(|h| !instance.active.contains(&h.reporef))
+                    instance.queue.pop_if(|h| {
+                        !instance.active.contains(&h.reporef)
+                            && !instance.paused.contains(&h.reporef)
+                    })
                 ) {
                     let active = Arc::clone(&instance.active);
+                    let handle = next.handle.clone();
                     match active
-                        .insert_async(next.reporef.clone(), next.clone())
+                        .insert_async(handle.reporef.clone(), handle.clone())
                         .await
                     {
                         Ok(_) => {
+                            METRICS.sync_queue_active.set(active.len() as i64);
+                            let instance = instance.clone();
                             tokio::task::spawn(async move {
-                                info!(?next.reporef, "starting indexing of repository");
-
-                                let result = next.run(permit).await;
-                                _ = active.remove(&next.reporef);
-
+                                info!(
+                                    ?handle.reporef,
+                                    priority = ?next.priority,
+                                    attempt = next.attempt,
+                                    "starting indexing of repository"
+                                );
+
+                                let result = handle.run(permit).await;
                                 debug!(?result, "indexing finished for repository");
+
+                                match result {
+                                    Err(SyncError::Paused) => {
+                                        // parked before it stops being active, so
+                                        // a resume in between finds it in one
+                                        // place or the other
+                                        _ = instance
+                                            .paused
+                                            .insert_async(handle.reporef.clone(), next)
+                                            .await;
+                                        instance.deactivate(&handle.reporef);
+
+                                        if !handle.pipes.is_paused() {
+                                            instance.unpark(&handle.reporef).await;
+                                        }
+                                    }
+                                    Err(err) => {
+                                        instance.deactivate(&handle.reporef);
+                                        instance.schedule_retry(next, &err).await;
+                                    }
+                                    Ok(_) => instance.deactivate(&handle.reporef),
+                                }
                             });
                         }
-                        Err((_, next)) => {
+                        Err(_) => {
                             // this shouldn't happen, but we can handle it gracefully
                             instance.queue.push(next).await
                         }
@@ -114,6 +356,34 @@ impl SyncQueue {
         instance
     }
 
+    fn deactivate(&self, reporef: &RepoRef) {
+        _ = self.active.remove(reporef);
+        METRICS.sync_queue_active.set(self.active.len() as i64);
+    }
+
+    /// Queue a paused sync again, to carry on from its last checkpoint.
+    ///
+    /// Returns `false` if `reporef` is not paused.
+    async fn unpark(&self, reporef: &RepoRef) -> bool {
+        let Some((_, parked)) = self.paused.remove_async(reporef).await else {
+            return false;
+        };
+
+        info!(?reporef, "resuming sync");
+        parked.handle.pipes.resume();
+        parked.handle.pipes.restart();
+        parked.handle.set_status(|_| SyncStatus::Queued);
+        self.queue.push(parked).await;
+        true
+    }
+
+    /// Drop a paused sync for good, leaving `status` behind.
+    async fn discard_paused(&self, reporef: &RepoRef, status: SyncStatus) {
+        if let Some((_, parked)) = self.paused.remove_async(reporef).await {
+            parked.handle.set_status(|_| status);
+        }
+    }
+
     pub fn bind(&self, app: Application) -> BoundSyncQueue {
         BoundSyncQueue(app, self.clone())
     }
@@ -126,6 +396,19 @@ impl SyncQueue {
         &self.progress.context
     }
 
+    /// The indexer's metrics in the Prometheus text format, to serve on a
+    /// `/metrics` endpoint.
+    pub fn metrics(&self) -> String {
+        METRICS.render()
+    }
+
+    /// Write the indexer's metrics to `path` in the Prometheus text format.
+    pub async fn dump_metrics(&self, path: &std::path::Path) -> std::io::Result<()> {
+        crate::metrics::dump(path).await
+    }
+
+    /// Everything being synced, followed by the queue in the order it
+    /// would be picked up right now.
     pub async fn read_queue(&self) -> Vec<QueuedRepoStatus> {
         let mut output = vec![];
         self.active
@@ -133,26 +416,186 @@ impl SyncQueue {
                 output.push(QueuedRepoStatus {
                     reporef: handle.reporef.clone(),
                     state: QueueState::Active,
+                    priority: None,
+                    retry: None,
+                });
+            })
+            .await;
+
+        self.paused
+            .scan_async(|_, parked| {
+                output.push(QueuedRepoStatus {
+                    reporef: parked.handle.reporef.clone(),
+                    state: QueueState::Paused,
+                    priority: Some(parked.priority),
+                    retry: None,
                 });
             })
             .await;
 
-        for handle in self.queue.get_list().await {
+        let now = Instant::now();
+        for queued in self.queue.get_list().await {
+            let state = if queued.is_ready(now) {
+                QueueState::Queued
+            } else {
+                QueueState::Retrying
+            };
+
             output.push(QueuedRepoStatus {
-                reporef: handle.reporef.clone(),
-                state: QueueState::Queued,
+                reporef: queued.handle.reporef.clone(),
+                state,
+                priority: Some(queued.priority),
+                retry: queued.retry_schedule(now),
             });
         }
 
         output
     }
+
+    /// Put a failed sync back into the queue with an exponential backoff,
+    /// if the error is worth retrying and there are attempts left.
+    ///
+    /// The retry runs on the same handle, so whoever waits for the sync to
+    /// finish learns how the retry went, not just about the first failure.
+    async fn schedule_retry(&self, failed: QueuedSync, err: &SyncError) {
+        let reporef = failed.handle.reporef.clone();
+        let Some(delay) = failed.backoff(err) else {
+            if err.is_transient() {
+                error!(?reporef, attempts = failed.attempt, "giving up on repository");
+            }
+            return;
+        };
+
+        // the repository was removed
+        if !failed.handle.app.repo_pool.contains_async(&reporef).await {
+            return;
+        }
+
+        // someone queued it again already, so that sync is our retry
+        let progress = self.subscribe();
+        if self.queue.contains(&reporef).await {
+            tokio::spawn(forward_completion(failed.handle, progress));
+            return;
+        }
+
+        info!(?reporef, ?delay, attempt = failed.attempt + 1, "retrying sync");
+        failed.handle.pipes.restart();
+        self.queue
+            .push(QueuedSync {
+                handle: failed.handle,
+                priority: failed.priority,
+                attempt: failed.attempt + 1,
+                ready_at: Instant::now() + delay,
+                last_error: Some(err.to_string()),
+            })
+            .await;
+    }
+}
+
+/// Hold on to `handle` until another sync of the same repository is over.
+///
+/// Dropping the handle sends the status of the repository to whoever waits
+/// on it, which by then is the outcome of the other sync.
+async fn forward_completion(
+    handle: Arc<SyncHandle>,
+    mut progress: tokio::sync::broadcast::Receiver<Progress>,
+) {
+    use tokio::sync::broadcast::error::RecvError;
+
+    loop {
+        match progress.recv().await {
+            Ok(Progress {
+                reporef,
+                event: ProgressEvent::StatusChange(status),
+            }) if reporef == handle.reporef => {
+                use SyncStatus::*;
+                if matches!(status, Done | Error { .. } | Cancelled | Removed) {
+                    break;
+                }
+            }
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        }
+    }
+
+    drop(handle);
+}
+
+/// A repository waiting in the [`NotifyQueue`], with what we need to know
+/// to schedule it.
+#[derive(Clone)]
+pub(crate) struct QueuedSync {
+    handle: Arc<SyncHandle>,
+    priority: SyncPriority,
+
+    /// Starts from 1, and goes up with every retry
+    attempt: u32,
+
+    /// When this can be picked up. For a retry this is in the future, and
+    /// otherwise the time it was queued, which aging is measured from.
+    ready_at: Instant,
+    last_error: Option<String>,
+}
+
+impl QueuedSync {
+    pub(crate) fn new(handle: Arc<SyncHandle>, priority: SyncPriority) -> Self {
+        Self {
+            handle,
+            priority,
+            attempt: 1,
+            ready_at: Instant::now(),
+            last_error: None,
+        }
+    }
+
+    fn is_ready(&self, now: Instant) -> bool {
+        self.ready_at <= now
+    }
+
+    fn effective_priority(&self, now: Instant) -> u64 {
+        let waited = now.saturating_duration_since(self.ready_at);
+        self.priority.level() + waited.as_secs() / AGING_INTERVAL.as_secs()
+    }
+
+    fn backoff(&self, err: &SyncError) -> Option<Duration> {
+        if !err.is_transient() || self.attempt >= MAX_SYNC_ATTEMPTS {
+            return None;
+        }
+
+        let delay = RETRY_BASE_DELAY
+            .saturating_mul(1 << (self.attempt - 1).min(16))
+            .min(RETRY_MAX_DELAY);
+
+        // spread out retries of repositories which failed together
+        let jitter = rand::random::<f64>() * 0.1;
+        Some(delay.mul_f64(1.0 + jitter))
+    }
+
+    fn retry_schedule(&self, now: Instant) -> Option<RetrySchedule> {
+        let last_error = self.last_error.clone()?;
+        let retry_at = SystemTime::now() + self.ready_at.saturating_duration_since(now);
+
+        Some(RetrySchedule {
+            attempt: self.attempt,
+            max_attempts: MAX_SYNC_ATTEMPTS,
+            retry_at_unix_secs: retry_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            last_error,
+        })
+    }
 }
 
-/// Asynchronous queue with await semantics for popping the front
+/// Asynchronous priority queue with await semantics for popping the next
 /// element.
+///
+/// The next element is the ready one with the highest priority after
+/// aging, and the one which has been waiting longest among equals.
 pub(crate) struct NotifyQueue {
-    queue: tokio::sync::RwLock<VecDeque<Arc<SyncHandle>>>,
+    queue: tokio::sync::RwLock<Vec<QueuedSync>>,
     available: Semaphore,
+    changed: Notify,
 }
 
 impl Default for NotifyQueue {
@@ -160,36 +603,78 @@ impl Default for NotifyQueue {
         Self {
             queue: Default::default(),
             available: Semaphore::new(0),
+            changed: Notify::new(),
         }
     }
 }
 
 impl NotifyQueue {
-    pub(crate) async fn push(&self, item: Arc<SyncHandle>) {
+    pub(crate) async fn push(&self, item: QueuedSync) {
         let mut q = self.queue.write().await;
 
         self.available.add_permits(1);
 
-        q.push_back(item);
+        q.push(item);
+        METRICS.sync_queue_queued.set(q.len() as i64);
+        self.changed.notify_waiters();
     }
 
-    pub(super) async fn pop_if(&self, pred: impl Fn(&SyncHandle) -> bool) -> Arc<SyncHandle> {
+    pub(super) async fn pop_if(&self, pred: impl Fn(&SyncHandle) -> bool) -> QueuedSync {
         loop {
             let permit = self.available.acquire().await.expect("fatal");
             let mut q = self.queue.write().await;
 
-            let first = q.iter().position(|h| (pred)(h));
-
-            if let Some(pos) = first {
+            let now = Instant::now();
+            if let Some(pos) = Self::next(&q, now, &pred) {
                 permit.forget();
-                return q.remove(pos).expect("locked");
+                let next = q.remove(pos);
+                METRICS.sync_queue_queued.set(q.len() as i64);
+                return next;
+            }
+
+            // Nothing we can start right now, wait for the next retry to
+            // come due, or for the queue to change.
+            let next_retry = q
+                .iter()
+                .filter(|item| !item.is_ready(now))
+                .map(|item| item.ready_at - now)
+                .min();
+
+            let changed = self.changed.notified();
+            drop(q);
+            drop(permit);
+
+            let wait = next_retry
+                .unwrap_or(QUEUE_POLL_INTERVAL)
+                .min(QUEUE_POLL_INTERVAL);
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {},
+                _ = changed => {},
             }
         }
     }
 
-    #[allow(unused)]
-    pub(super) async fn get_list(&self) -> Vec<Arc<SyncHandle>> {
-        self.queue.read().await.iter().cloned().collect()
+    fn next(q: &[QueuedSync], now: Instant, pred: impl Fn(&SyncHandle) -> bool) -> Option<usize> {
+        q.iter()
+            .enumerate()
+            .filter(|(_, item)| item.is_ready(now) && (pred)(&item.handle))
+            .max_by_key(|(_, item)| (item.effective_priority(now), Reverse(item.ready_at)))
+            .map(|(pos, _)| pos)
+    }
+
+    /// The queue in the order it would be popped right now.
+    pub(super) async fn get_list(&self) -> Vec<QueuedSync> {
+        let now = Instant::now();
+        let mut list = self.queue.read().await.clone();
+        list.sort_by_key(|item| {
+            (
+                !item.is_ready(now),
+                Reverse(item.effective_priority(now)),
+                item.ready_at,
+            )
+        });
+        list
     }
 
     pub(super) async fn contains(&self, reporef: &RepoRef) -> bool {
@@ -197,14 +682,33 @@ impl NotifyQueue {
             .read()
             .await
             .iter()
-            .any(|h| &h.reporef == reporef)
+            .any(|item| &item.handle.reporef == reporef)
     }
 
-    #[allow(unused)]
-    pub(super) async fn remove(&self, reporef: RepoRef) {
+    /// Raise the priority of `reporef` if it's already queued.
+    ///
+    /// An interactive request skips any pending retry delay, and starts
+    /// counting attempts from scratch.
+    ///
+    /// Returns `false` if the repository is not in the queue.
+    pub(super) async fn promote(&self, reporef: &RepoRef, priority: SyncPriority) -> bool {
         let mut q = self.queue.write().await;
-        self.available.acquire().await.expect("fatal").forget();
-        q.retain(|item| item.reporef != reporef);
+        let Some(item) = q.iter_mut().find(|item| &item.handle.reporef == reporef) else {
+            return false;
+        };
+
+        if priority > item.priority {
+            item.priority = priority;
+        }
+
+        if priority == SyncPriority::Interactive && item.last_error.is_some() {
+            item.attempt = 1;
+            item.ready_at = Instant::now();
+            item.last_error = None;
+        }
+
+        self.changed.notify_waiters();
+        true
     }
 }
 
@@ -224,19 +728,23 @@ pub struct BoundSyncQueue(pub(crate) Application, pub(crate) SyncQueue);
 impl BoundSyncQueue {
     /// Enqueue repos for syncing with the current configuration.
     ///
-    /// Skips any repositories in the list which are already queued or being synced.
+    /// Skips any repositories in the list which are being synced. Ones which
+    /// are already queued are bumped to `priority` if that's higher.
     /// Returns the number of new repositories queued for syncing.
-    pub async fn enqueue_sync(self, repositories: Vec<RepoRef>) -> usize {
+    pub async fn enqueue_sync(self, repositories: Vec<RepoRef>, priority: SyncPriority) -> usize {
         let mut num_queued = 0;
 
         for reporef in repositories {
-            if self.1.queue.contains(&reporef).await || self.1.active.contains(&reporef) {
+            if self.1.active.contains(&reporef)
+                || self.1.paused.contains(&reporef)
+                || self.1.queue.promote(&reporef, priority).await
+            {
                 continue;
             }
 
-            info!(?reporef, "queueing for sync");
+            info!(?reporef, ?priority, "queueing for sync");
             let handle = SyncHandle::new(self.0.clone(), reporef, self.1.progress.clone()).await;
-            self.1.queue.push(handle).await;
+            self.1.queue.push(QueuedSync::new(handle, priority)).await;
             num_queued += 1;
         }
 
@@ -245,15 +753,20 @@ impl BoundSyncQueue {
 
     /// Block until the repository sync & index process is complete.
     ///
-    /// Returns the new status.
+    /// Returns the new status, after any retries of a failed sync.
     pub(crate) async fn block_until_synced(self, reporef: RepoRef) -> anyhow::Result<SyncStatus> {
         let handle = SyncHandle::new(self.0.clone(), reporef, self.1.progress.clone()).await;
         let finished = handle.notify_done();
-        self.1.queue.push(handle).await;
+        self.1
+            .queue
+            .push(QueuedSync::new(handle, SyncPriority::Interactive))
+            .await;
         Ok(finished.recv_async().await?)
     }
 
     pub(crate) async fn remove(self, reporef: RepoRef) -> Option<()> {
+        self.1.discard_paused(&reporef, SyncStatus::Removed).await;
+
         let active = self
             .1
             .active
@@ -269,13 +782,15 @@ impl BoundSyncQueue {
                 .update_async(&reporef, |_k, v| v.mark_removed())
                 .await?;
 
-            self.enqueue_sync(vec![reporef]).await;
+            self.enqueue_sync(vec![reporef], SyncPriority::Interactive).await;
         }
 
         Some(())
     }
 
     pub(crate) async fn cancel(&self, reporef: RepoRef) {
+        self.1.discard_paused(&reporef, SyncStatus::Cancelled).await;
+
         self.1
             .active
             .update_async(&reporef, |_, v| {
@@ -285,13 +800,357 @@ impl BoundSyncQueue {
             .await;
     }
 
+    /// Stop an active sync after the files it's working on, without losing
+    /// the embeddings done so far.
+    ///
+    /// The sync gives up its slot and the index writers, so other syncs
+    /// carry on meanwhile. [`Self::resume`] queues it again, and it picks up
+    /// from its last checkpoint. Returns `false` if the repository is not
+    /// being synced.
+    pub(crate) async fn pause(&self, reporef: RepoRef) -> bool {
+        self.1
+            .active
+            .update_async(&reporef, |_, v| {
+                v.pipes.pause();
+                v.set_status(|_| SyncStatus::Paused);
+            })
+            .await
+            .is_some()
+    }
+
+    pub(crate) async fn resume(&self, reporef: RepoRef) -> bool {
+        // a sync which hasn't stopped yet simply carries on, and one which
+        // stopped in the meantime is queued again, see `SyncQueue::start`
+        let active = self
+            .1
+            .active
+            .update_async(&reporef, |_, v| {
+                if v.pipes.is_paused() {
+                    v.pipes.resume();
+                    v.set_status(|_| SyncStatus::Indexing);
+                }
+            })
+            .await
+            .is_some();
+
+        self.1.unpark(&reporef).await || active
+    }
+
+    /// Cross-check the caches, the snippet index and the vectors.
+    ///
+    /// With `repair` set, fixes what can be fixed in place and queues every
+    /// affected repository for another sync.
+    pub async fn verify(self, repair: bool) -> anyhow::Result<IntegrityReport> {
+        let report =
+            integrity::verify(&self.0.sql, &self.0.indexes.snippets, &self.0.repo_pool).await?;
+
+        if repair && !report.is_healthy() {
+            let resync = integrity::repair(&self.0.sql, &self.0.indexes, &report).await?;
+            let queued = self.enqueue_sync(resync, SyncPriority::Background).await;
+            info!(queued, "queued repositories after repair");
+        }
+
+        Ok(report)
+    }
+
+    /// Export the index of `reporef` into a snapshot file at `archive`.
+    pub async fn export_snapshot(
+        &self,
+        reporef: RepoRef,
+        archive: PathBuf,
+    ) -> anyhow::Result<SnapshotSummary> {
+        let Some(ref semantic) = self.0.semantic else {
+            anyhow::bail!("no semantic search client configured");
+        };
+
+        let repo = self
+            .0
+            .repo_pool
+            .read_async(&reporef, |_k, v| v.clone())
+            .await
+            .ok_or_else(|| anyhow::anyhow!("unknown repository {reporef}"))?;
+
+        snapshot::export(&self.0.sql, semantic, &reporef, &repo, &archive).await
+    }
+
+    /// Import a snapshot as the index of the repository at `disk_path`, then
+    /// queue it for a sync which picks up whatever differs locally.
+    pub async fn import_snapshot(
+        self,
+        disk_path: PathBuf,
+        archive: PathBuf,
+    ) -> anyhow::Result<SnapshotSummary> {
+        let Some(ref semantic) = self.0.semantic else {
+            anyhow::bail!("no semantic search client configured");
+        };
+
+        let reporef = RepoRef::new(Backend::Local, &disk_path.to_string_lossy())?;
+        if self.1.active.contains(&reporef) {
+            anyhow::bail!("repository {reporef} is being indexed");
+        }
+
+        let storage = self
+            .0
+            .repo_pool
+            .entry_async(reporef.clone())
+            .await
+            .or_insert_with(|| Repository::local_from(&reporef))
+            .get()
+            .vector_storage;
+
+        let summary = snapshot::import(
+            &self.0.sql,
+            semantic,
+            &reporef,
+            &disk_path,
+            storage,
+            &archive,
+        )
+        .await?;
+
+        self.enqueue_sync(vec![reporef], SyncPriority::Interactive).await;
+        Ok(summary)
+    }
+
+    /// Measure how well quantised storage would do on `reporef`, against
+    /// exact search over its full precision vectors.
+    pub async fn recall_report(
+        &self,
+        reporef: RepoRef,
+        samples: usize,
+        k: usize,
+    ) -> anyhow::Result<RecallReport> {
+        recall::report(&self.0.sql, &reporef, samples, k).await
+    }
+
+    /// Find clusters of duplicated chunks in `reporef`, and write the report
+    /// to `out_dir` as JSON and Markdown if given.
+    pub async fn duplicate_report(
+        &self,
+        reporef: RepoRef,
+        options: DuplicateOptions,
+        out_dir: Option<PathBuf>,
+    ) -> anyhow::Result<DuplicateReport> {
+        let report = duplicates::report(&self.0.sql, &reporef, options).await?;
+        if let Some(dir) = out_dir {
+            report.write(&dir).await?;
+        }
+
+        Ok(report)
+    }
+
+    /// Chunks similar to lines `lines` of `relative_path` in `reporef`, in
+    /// `targets` or every indexed repository if there are none.
+    pub async fn find_similar(
+        &self,
+        reporef: RepoRef,
+        relative_path: &str,
+        lines: RangeInclusive<u64>,
+        targets: Vec<RepoRef>,
+        limit: u64,
+        threshold: f32,
+    ) -> anyhow::Result<Vec<Payload>> {
+        let Some(ref semantic) = self.0.semantic else {
+            anyhow::bail!("no semantic search client configured");
+        };
+
+        let Some(source) = self
+            .0
+            .repo_pool
+            .read_async(&reporef, |_, repo| repo.clone())
+            .await
+        else {
+            anyhow::bail!("repository {reporef} is not indexed");
+        };
+
+        let targets = if targets.is_empty() {
+            let mut all = vec![];
+            self.0
+                .repo_pool
+                .scan_async(|k, _| all.push(k.clone()))
+                .await;
+            all
+        } else {
+            targets
+        };
+
+        semantic
+            .find_similar(
+                &source,
+                &reporef,
+                relative_path,
+                lines,
+                &targets,
+                limit,
+                threshold,
+            )
+            .await
+    }
+
+    /// Commits of `reporef` whose message, or changes, best match `query`.
+    ///
+    /// Only finds anything for repositories with commit history indexing
+    /// turned on, or after an explicit [`Self::index_history`].
+    pub async fn search_history(
+        &self,
+        reporef: RepoRef,
+        query: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<CommitMatch>> {
+        let Some(ref semantic) = self.0.semantic else {
+            anyhow::bail!("no semantic search client configured");
+        };
+
+        history::search(&self.0.sql, semantic, &reporef, query, limit).await
+    }
+
+    /// Index the commits of `reporef` we haven't seen yet, returning how
+    /// many were added
+    pub async fn index_history(
+        &self,
+        reporef: RepoRef,
+        options: HistoryOptions,
+    ) -> anyhow::Result<usize> {
+        let Some(ref semantic) = self.0.semantic else {
+            anyhow::bail!("no semantic search client configured");
+        };
+
+        let Some(repo) = self
+            .0
+            .repo_pool
+            .read_async(&reporef, |_, repo| repo.clone())
+            .await
+        else {
+            anyhow::bail!("repository {reporef} is not indexed");
+        };
+
+        history::index(&self.0.sql, semantic, &reporef, &repo.disk_path, &options).await
+    }
+
+    /// Lines of `reporef` matching the regex `pattern`, in the files
+    /// `query`'s `path:` filters allow
+    pub async fn regex_search(
+        &self,
+        reporef: RepoRef,
+        pattern: &str,
+        query: Option<&ParsedQuery>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SnippetDocument>> {
+        trigram::search(&self.0.sql, &reporef, pattern, query, limit).await
+    }
+
+    /// Snippets of the lexical index containing the words of `query`,
+    /// across every repository its `repo:` filters allow
+    pub fn lexical_search(
+        &self,
+        query: &ParsedQuery,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SnippetDocument>> {
+        self.0.indexes.snippets.search_parsed(query, limit)
+    }
+
+    /// Where symbols called `name` are defined in `reporef`
+    pub async fn find_symbol(
+        &self,
+        reporef: RepoRef,
+        name: &str,
+        mode: SymbolMatch,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Symbol>> {
+        symbols::lookup(&self.0.sql, &reporef, name, mode, limit).await
+    }
+
+    /// Remove the repositories whose directory is gone, delete data left
+    /// behind by repositories which are no longer in the pool, then vacuum
+    /// the database.
+    ///
+    /// Repositories being synced right now are left alone until the next
+    /// run, and no sync starts writing until the vacuum is done.
+    pub async fn gc(&self) -> anyhow::Result<GcReport> {
+        let Application {
+            ref sql,
+            ref semantic,
+            ref indexes,
+            ref repo_pool,
+            ..
+        } = self.0;
+
+        let mut report = GcReport {
+            bytes_before: gc::database_size(sql).await?,
+            ..Default::default()
+        };
+
+        let mut vanished = vec![];
+        repo_pool
+            .scan_async(|reporef, repo| {
+                if !repo.disk_path.exists() && !self.1.active.contains(reporef) {
+                    vanished.push(reporef.clone());
+                }
+            })
+            .await;
+
+        let writers = indexes.writers().await?;
+        for reporef in vanished {
+            let Some((_, repo)) = repo_pool.remove_async(&reporef).await else {
+                continue;
+            };
+
+            info!(?reporef, "repository directory is gone, removing it");
+            gc::purge(sql, semantic.as_ref(), &reporef).await?;
+            for handle in &*writers {
+                handle.delete(&repo);
+            }
+            report.vanished.push(reporef);
+        }
+
+        if !report.vanished.is_empty() {
+            self.0.config.state_source.save_pool(repo_pool.clone())?;
+        }
+
+        let mut known = HashSet::new();
+        repo_pool
+            .scan_async(|reporef, _| {
+                known.insert(reporef.to_string());
+            })
+            .await;
+        report.orphaned = gc::orphaned(sql, &known).await?;
+        gc::purge_orphaned(sql, semantic.as_ref(), &writers, &report.orphaned).await?;
+
+        // a sync writing to the database would hold the vacuum up, or
+        // fail on the lock it takes
+        let _write_lock = writers.commit_and_hold().await?;
+        gc::vacuum(sql).await?;
+        report.bytes_after = gc::database_size(sql).await?;
+
+        info!(
+            vanished = report.vanished.len(),
+            orphaned = report.orphaned.len(),
+            reclaimed_bytes = report.reclaimed_bytes(),
+            "garbage collection finished"
+        );
+        Ok(report)
+    }
+
+    /// Run [`Self::gc`] every `interval`, for as long as the application
+    /// is running
+    pub fn start_periodic_gc(self, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                if let Err(err) = self.gc().await {
+                    error!(?err, "garbage collection failed");
+                }
+            }
+        });
+    }
+
     pub async fn startup_scan(self) -> anyhow::Result<()> {
         let Self(Application { repo_pool, .. }, _) = &self;
 
         let mut repos = vec![];
         repo_pool.scan_async(|k, _| repos.push(k.clone())).await;
 
-        self.enqueue_sync(repos).await;
+        self.enqueue_sync(repos, SyncPriority::Background).await;
 
         Ok(())
     }
@@ -369,6 +1228,12 @@ pub struct SyncPipes {
     reporef: RepoRef,
     progress: ProgressStreamWithContext,
     event: RwLock<Option<ControlEvent>>,
+    // pausing is separate from the control events, since a paused sync can
+    // still be cancelled or removed
+    paused: AtomicBool,
+    // the walk noticed the pause and stopped taking files
+    stopped: AtomicBool,
+    tracker: Mutex<PhaseTracker>,
 }
 
 impl SyncPipes {
@@ -377,6 +1242,9 @@ impl SyncPipes {
             reporef,
             progress,
             event: Default::default(),
+            paused: Default::default(),
+            stopped: Default::default(),
+            tracker: Default::default(),
         }
     }
 
@@ -389,6 +1257,61 @@ impl SyncPipes {
         });
     }
 
+    /// Move on to `phase`, unless the sync is already past it.
+    pub(crate) fn advance(&self, phase: IndexPhase) {
+        let mut tracker = self.tracker.lock().unwrap();
+        if phase < tracker.phase || (phase == tracker.phase && tracker.last_sent.is_some()) {
+            return;
+        }
+
+        tracker.phase = phase;
+        tracker.started = Instant::now();
+        let progress = tracker.due(true);
+        drop(tracker);
+
+        self.send_phase(progress);
+    }
+
+    pub(crate) fn files_total(&self, total: usize) {
+        self.update_phase(|tracker| tracker.files_total = total as u64);
+    }
+
+    pub(crate) fn file_done(&self) {
+        self.update_phase(|tracker| tracker.files_done += 1);
+    }
+
+    pub(crate) fn chunks_queued(&self, count: usize) {
+        self.update_phase(|tracker| tracker.chunks_total += count as u64);
+    }
+
+    pub(crate) fn chunks_embedded(&self, count: usize) {
+        self.update_phase(|tracker| {
+            tracker.first_embedding.get_or_insert_with(Instant::now);
+            tracker.chunks_done += count as u64;
+        });
+    }
+
+    fn update_phase(&self, update: impl FnOnce(&mut PhaseTracker)) {
+        let mut tracker = self.tracker.lock().unwrap();
+        (update)(&mut tracker);
+        let progress = tracker.due(false);
+        drop(tracker);
+
+        self.send_phase(progress);
+    }
+
+    fn send_phase(&self, progress: Option<PhaseProgress>) {
+        let Some(progress) = progress else {
+            return;
+        };
+
+        trace!(?progress, ?self.reporef, "phase progress");
+        _ = self.progress.sender.send(Progress {
+            reporef: self.reporef.clone(),
+            event: ProgressEvent::Phase(progress),
+        });
+    }
+
     pub(crate) fn status(&self, new: SyncStatus) {
         _ = self.progress.sender.send(Progress {
             reporef: self.reporef.clone(),
@@ -410,6 +1333,46 @@ impl SyncPipes {
         )
     }
 
+    /// Start over with the progress of a sync that runs again.
+    pub(crate) fn restart(&self) {
+        self.stopped.store(false, Ordering::SeqCst);
+        *self.tracker.lock().unwrap() = Default::default();
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Whether the walk should take another file.
+    ///
+    /// A paused sync stops between files, like a cancelled one, and
+    /// [`Self::was_paused`] tells the two apart afterwards.
+    pub(crate) fn should_continue(&self) -> bool {
+        if self.is_cancelled() {
+            return false;
+        }
+
+        if self.is_paused() {
+            self.stopped.store(true, Ordering::SeqCst);
+            return false;
+        }
+
+        true
+    }
+
+    /// The walk stopped early because the sync was paused
+    pub(crate) fn was_paused(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
     pub(crate) fn cancel(&self) {
         *self.event.write().unwrap() = Some(ControlEvent::Cancel);
     }
@@ -442,8 +1405,31 @@ pub(super) enum SyncError {
     #[error("cancelled by user")]
     Cancelled,
 
+    #[error("paused by user")]
+    Paused,
+
     #[error("indexing failed: {0:?}")]
     Indexing(RepoError),
+
+    #[error("tantivy: {0:?}")]
+    Tantivy(anyhow::Error),
+
+    #[error("sql: {0:?}")]
+    Sql(anyhow::Error),
+}
+
+impl SyncError {
+    /// Whether trying again later has a chance of succeeding
+    pub(super) fn is_transient(&self) -> bool {
+        match self {
+            Self::SyncInProgress | Self::Tantivy(_) | Self::Sql(_) => true,
+            Self::Indexing(RepoError::IO { .. } | RepoError::Anyhow { .. }) => true,
+            Self::Indexing(_) => false,
+            Self::PathNotAllowed(_) | Self::RemoveLocal(..) | Self::Cancelled | Self::Paused => {
+                false
+            }
+        }
+    }
 }
 
 impl PartialEq for SyncHandle {
@@ -457,7 +1443,7 @@ impl Drop for SyncHandle {
         let status = self.set_status(|v| {
             use SyncStatus::*;
             match &v.sync_status {
-                Indexing | Syncing => Error {
+                Indexing | Syncing | Paused => Error {
                     message: "unknown".into(),
                 },
                 Cancelling => Cancelled,
@@ -527,6 +1513,7 @@ impl SyncHandle {
             .unwrap_or(false);
 
         if !removed {
+            self.pipes.advance(IndexPhase::GitSync);
             match self.git_sync().await {
                 Ok(status) => {
                     if let SyncStatus::Done = self.set_status(|_| status).unwrap() {
@@ -581,21 +1568,59 @@ impl SyncHandle {
                     }
                 }
 
+                self.enqueue_nested(&repository).await;
+                self.index_history(&repository).await;
+
                 // technically `sync_done_with` does this, but we want to send notifications
                 self.set_status(|_| SyncStatus::Done)
             }
             Err(SyncError::Cancelled) => self.set_status(|_| SyncStatus::Cancelled),
+            Err(SyncError::Paused) => {
+                // the queue holds on to the sync until it's resumed
+                self.set_status(|_| SyncStatus::Paused);
+                return Err(SyncError::Paused);
+            }
             Err(err) => {
                 error!(?err, ?self.reporef, "failed to index repository");
                 self.set_status(|_| SyncStatus::Error {
                     message: err.to_string(),
-                })
+                });
+
+                // the queue decides whether this is worth retrying
+                return Err(err);
             }
         };
 
         Ok(status.expect("failed to update repo status"))
     }
 
+    /// Pick up new commits for history search, if it's turned on. The
+    /// sync itself succeeded already, so failing here is only logged.
+    async fn index_history(&self, repo: &Repository) {
+        let Application {
+            ref config,
+            ref semantic,
+            ref sql,
+            ..
+        } = self.app;
+
+        let Some(semantic) = semantic else {
+            return;
+        };
+        if !config.index_commit_history {
+            return;
+        }
+
+        let options = HistoryOptions {
+            diffs: config.commit_history_diffs,
+            ..Default::default()
+        };
+        let indexed = history::index(sql, semantic, &self.reporef, &repo.disk_path, &options).await;
+        if let Err(err) = indexed {
+            error!(?err, ?self.reporef, "failed to index commit history");
+        }
+    }
+
     async fn index(&self) -> Result<Either<SyncStatus, Arc<RepoMetadata>>> {
         use SyncStatus::*;
         let Application {
@@ -617,7 +1642,7 @@ impl SyncHandle {
 
         let indexed = match repo.sync_status {
             current @ (Uninitialized | Syncing | Indexing) => return Ok(Either::Left(current)),
-            Removed => return Ok(either::Left(SyncStatus::Removed)),
+            Removed => return self.delete_repo(&repo, writers).await,
             RemoteRemoved => {
                 // Note we don't clean up here, leave the
                 // bare bones behind.
@@ -636,20 +1661,38 @@ impl SyncHandle {
         let time_taken = instant.elapsed();
 
         debug!(?self.reporef, ?time_taken, "indexing finished");
+        if indexed.is_ok() {
+            METRICS.index_finished(&self.reporef.to_string(), time_taken);
+        }
 
         match indexed {
             Ok(_) => {
                 debug!("committing index");
+                self.pipes.advance(IndexPhase::Committing);
                 writers.commit().await.map_err(SyncError::Tantivy)?;
                 debug!("finished committing index");
                 indexed.map_err(SyncError::Indexing)
             }
-            // Err(_) if self.pipes.is_removed() => self.delete_repo(&repo, writers).await,
+            Err(_) if self.pipes.is_removed() => {
+                writers.rollback().map_err(SyncError::Tantivy)?;
+                let writers = indexes.writers().await.map_err(SyncError::Tantivy)?;
+                self.delete_repo(&repo, writers).await
+            }
             Err(_) if self.pipes.is_cancelled() => {
+                // the semantic index checkpoints survive this, so the next
+                // sync only embeds the files since the last one, while the
+                // lexical index of the changed files is built again
                 writers.rollback().map_err(SyncError::Tantivy)?;
                 debug!(?self.reporef, "index cancelled by user");
                 Err(SyncError::Cancelled)
             }
+            Err(_) if self.pipes.was_paused() => {
+                // same as cancelling, giving up the writers lets other
+                // syncs go ahead while this one waits
+                writers.rollback().map_err(SyncError::Tantivy)?;
+                debug!(?self.reporef, "index paused by user");
+                Err(SyncError::Paused)
+            }
             Err(err) => {
                 writers.rollback().map_err(SyncError::Tantivy)?;
                 Err(SyncError::Indexing(err))
@@ -657,25 +1700,92 @@ impl SyncHandle {
         }
     }
 
-    // async fn delete_repo(
-    //     &self,
-    //     repo: &Repository,
-    //     writers: indexes::GlobalWriteHandle<'_>,
-    // ) -> Result<Either<SyncStatus, Arc<RepoMetadata>>> {
-    //     self.app.repo_pool.remove(&self.reporef);
+    async fn delete_repo(
+        &self,
+        repo: &Repository,
+        writers: indexes::GlobalWriteHandle<'_>,
+    ) -> Result<Either<SyncStatus, Arc<RepoMetadata>>> {
+        self.app.repo_pool.remove_async(&self.reporef).await;
+
+        let deleted = self.delete_repo_indexes(repo, &writers).await;
+        match deleted {
+            Ok(()) => {
+                writers.commit().await.map_err(SyncError::Tantivy)?;
+                if let Err(err) = self
+                    .app
+                    .config
+                    .state_source
+                    .save_pool(self.app.repo_pool.clone())
+                {
+                    error!(?err, "failed to save the repository pool");
+                }
 
-    //     let deleted = self.delete_repo_indexes(repo, &writers).await;
-    //     if deleted.is_ok() {
-    //         writers.commit().await.map_err(SyncError::Tantivy)?;
-    //         self.app
-    //             .config
-    //             .source
-    //             .save_pool(self.app.repo_pool.clone())
-    //             .map_err(SyncError::State)?;
-    //     }
+                info!(?self.reporef, "deleted repository");
+                self.remove_nested().await;
+                Ok(Either::Left(SyncStatus::Removed))
+            }
+            Err(err) => {
+                writers.rollback().map_err(SyncError::Tantivy)?;
+                Err(err)
+            }
+        }
+    }
 
-    //     deleted.map(|_| Either::Left(SyncStatus::Removed))
-    // }
+    /// Submodules and nested repositories are left out of the parent's
+    /// index, and depending on the policy get indexed as their own
+    /// repository with their own commit hash.
+    async fn enqueue_nested(&self, repo: &Repository) {
+        if repo.walk_policy.submodules != SubmodulePolicy::IndexSeparately {
+            return;
+        }
+
+        let nested = nested_repositories(&repo.disk_path, &repo.walk_policy)
+            .into_iter()
+            .map(|nested| RepoRef::from(&nested.disk_path))
+            .collect::<Vec<_>>();
+
+        if nested.is_empty() {
+            return;
+        }
+
+        // repositories the user added themselves stay when the parent goes
+        for reporef in &nested {
+            self.app
+                .repo_pool
+                .entry_async(reporef.clone())
+                .await
+                .or_insert_with(|| Repository {
+                    parent: Some(self.reporef.clone()),
+                    ..Repository::local_from(reporef)
+                });
+        }
+
+        let queued = self
+            .app
+            .write_index()
+            .enqueue_sync(nested, SyncPriority::Background)
+            .await;
+        debug!(?self.reporef, queued, "queued nested repositories");
+    }
+
+    /// Remove the nested repositories [`Self::enqueue_nested`] queued for
+    /// this one, whether they're still queued, syncing or already indexed.
+    async fn remove_nested(&self) {
+        let mut nested = vec![];
+        self.app
+            .repo_pool
+            .scan_async(|reporef, repo| {
+                if repo.parent.as_ref() == Some(&self.reporef) {
+                    nested.push(reporef.clone());
+                }
+            })
+            .await;
+
+        for reporef in nested {
+            debug!(?self.reporef, ?reporef, "removing nested repository");
+            self.app.write_index().remove(reporef).await;
+        }
+    }
 
     async fn git_sync(&self) -> Result<SyncStatus> {
         // Since we always assume local is correct, we don't have to sync
@@ -774,40 +1884,33 @@ impl SyncHandle {
     //     Err(SyncError::Sync(git_err))
     // }
 
-    // async fn delete_repo_indexes(
-    //     &self,
-    //     repo: &Repository,
-    //     writers: &indexes::GlobalWriteHandleRef<'_>,
-    // ) -> Result<()> {
-    //     let Application {
-    //         ref semantic,
-    //         ref sql,
-    //         ..
-    //     } = self.app;
-
-    //     if let Some(semantic) = semantic {
-    //         semantic
-    //             .delete_points_for_hash(&self.reporef.to_string(), std::iter::empty())
-    //             .await;
-    //     }
+    async fn delete_repo_indexes(
+        &self,
+        repo: &Repository,
+        writers: &indexes::GlobalWriteHandleRef<'_>,
+    ) -> Result<()> {
+        let Application {
+            ref semantic,
+            ref sql,
+            ..
+        } = self.app;
 
-    //     FileCache::for_repo(sql, semantic.as_ref(), &self.reporef)
-    //         .delete()
-    //         .await
-    //         .map_err(SyncError::Sql)?;
+        gc::purge(sql, semantic.as_ref(), &self.reporef)
+            .await
+            .map_err(SyncError::Sql)?;
 
-    //     if !self.reporef.is_local() {
-    //         tokio::fs::remove_dir_all(&repo.disk_path)
-    //             .await
-    //             .map_err(|e| SyncError::RemoveLocal(repo.disk_path.clone(), e))?;
-    //     }
+        if !self.reporef.is_local() {
+            tokio::fs::remove_dir_all(&repo.disk_path)
+                .await
+                .map_err(|e| SyncError::RemoveLocal(repo.disk_path.clone(), e))?;
+        }
 
-    //     for handle in writers {
-    //         handle.delete(repo);
-    //     }
+        for handle in writers {
+            handle.delete(repo);
+        }
 
-    //     Ok(())
-    // }
+        Ok(())
+    }
 
     pub fn pipes(&self) -> &SyncPipes {
         &self.pipes
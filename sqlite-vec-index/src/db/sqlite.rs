@@ -0,0 +1,252 @@
+//! The SQLite database every index lives in.
+//!
+//! One process writes, any number of others read. The database runs in WAL
+//! mode, so readers see the last committed state and never wait on an
+//! indexing transaction, nor does the writer wait on them. Who the writer is
+//! gets settled by an OS lock on a file next to the database: SQLite would
+//! happily let two writers interleave their syncs, which leaves caches
+//! pointing at vectors the other process has since deleted.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
+    SqlitePool,
+};
+use tracing::{debug, info};
+
+use crate::semantic_search::vectors;
+
+pub type SqlDb = Arc<SqlitePool>;
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// How long a connection waits for a lock before giving up. Readers only
+/// ever wait while the WAL index is being rebuilt after a crash.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const LOCK_ATTEMPTS: usize = 3;
+const LOCK_RETRY: Duration = Duration::from_millis(20);
+
+const WRITER_CONNECTIONS: u32 = 8;
+const READER_CONNECTIONS: u32 = 4;
+
+#[derive(thiserror::Error, Debug)]
+pub enum DbError {
+    #[error("{path:?} is already being written by {}", describe_pid(*pid))]
+    WriterActive { path: PathBuf, pid: Option<u32> },
+
+    #[error("no index at {0:?}")]
+    Missing(PathBuf),
+
+    #[error("index at {path:?} is at schema version {found}, expected {expected}")]
+    SchemaMismatch {
+        path: PathBuf,
+        found: i64,
+        expected: i64,
+    },
+
+    #[error("lock file error: {0}")]
+    Lock(#[from] std::io::Error),
+
+    #[error("sql: {0}")]
+    Sql(#[from] sqlx::Error),
+
+    #[error("migration failed: {0}")]
+    Migrate(#[from] sqlx::migrate::MigrateError),
+}
+
+fn describe_pid(pid: Option<u32>) -> String {
+    match pid {
+        Some(pid) => format!("process {pid}"),
+        None => "another process".to_owned(),
+    }
+}
+
+/// Proof that this process is the only one writing to a database.
+///
+/// The lock is the OS's, so it goes away with the process, and a crashed
+/// writer never leaves a stale lock behind. The file itself stays, holding
+/// the pid of the last writer, so other processes can say who they're
+/// waiting on.
+#[derive(Debug)]
+pub struct WriterLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl WriterLock {
+    pub fn path_for(db_path: &Path) -> PathBuf {
+        let mut path = db_path.as_os_str().to_owned();
+        path.push(".lock");
+        path.into()
+    }
+
+    /// Take the writer lock of `db_path`, failing if another process holds it
+    pub fn acquire(db_path: &Path) -> Result<Self, DbError> {
+        let path = Self::path_for(db_path);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        // readers asking who the writer is hold a shared lock for a moment,
+        // which mustn't pass for a writer
+        let mut attempts = 0;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) if attempts < LOCK_ATTEMPTS => {
+                    attempts += 1;
+                    std::thread::sleep(LOCK_RETRY);
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(DbError::WriterActive {
+                        path: db_path.to_owned(),
+                        pid: read_pid(&mut file),
+                    })
+                }
+                Err(TryLockError::Error(err)) => return Err(err.into()),
+            }
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+
+        debug!(?path, "acquired writer lock");
+        Ok(Self { path, _file: file })
+    }
+
+    /// The process writing to `db_path`, if there is one
+    pub fn holder(db_path: &Path) -> Result<Option<ActiveWriter>, DbError> {
+        let path = Self::path_for(db_path);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        match file.try_lock_shared() {
+            // dropping the file releases the lock again
+            Ok(()) => Ok(None),
+            Err(TryLockError::WouldBlock) => Ok(Some(ActiveWriter {
+                pid: read_pid(&mut file),
+            })),
+            Err(TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActiveWriter {
+    /// Missing if the writer didn't get around to leaving its pid yet
+    pub pid: Option<u32>,
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut pid = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut pid).ok()?;
+    pid.trim().parse().ok()
+}
+
+/// The writing side of a database. The lock is held for as long as this is
+/// alive, so it has to outlive every use of `sql`, which
+/// [`SearchWriter`](crate::semantic_search::writer::SearchWriter) takes care
+/// of.
+pub struct WriterDb {
+    pub sql: SqlDb,
+    pub lock: WriterLock,
+}
+
+/// Open `path` for writing, creating it if needed and bringing its schema up
+/// to date.
+///
+/// Fails with [`DbError::WriterActive`] if another process is writing to
+/// it already.
+pub async fn open_writer(path: &Path) -> Result<WriterDb, DbError> {
+    let lock = WriterLock::acquire(path)?;
+    vectors::register_sqlite_vec();
+
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        // in WAL mode this only risks the last transactions on power loss,
+        // never the integrity of the database
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(BUSY_TIMEOUT);
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(WRITER_CONNECTIONS)
+        .connect_with(options)
+        .await?;
+
+    MIGRATOR.run(&pool).await?;
+
+    info!(?path, "opened index for writing");
+    Ok(WriterDb {
+        sql: Arc::new(pool),
+        lock,
+    })
+}
+
+/// Open `path` for reading alongside whichever process is writing to it.
+///
+/// Every connection is read-only, so nothing done through the pool can get
+/// in the writer's way. The schema has to match the one this build expects,
+/// it's the writer's job to migrate it.
+pub async fn open_reader(path: &Path) -> Result<SqlDb, DbError> {
+    if !path.exists() {
+        return Err(DbError::Missing(path.to_owned()));
+    }
+    vectors::register_sqlite_vec();
+
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true)
+        .busy_timeout(BUSY_TIMEOUT);
+
+    let pool = SqlitePoolOptions::new()
+        .max_connections(READER_CONNECTIONS)
+        .connect_with(options)
+        .await?;
+
+    // a database without the migrations table was never migrated at all
+    let found: Option<i64> =
+        match sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(&pool)
+            .await
+        {
+            Ok(found) => found,
+            Err(sqlx::Error::Database(err)) if err.message().contains("no such table") => None,
+            Err(err) => return Err(err.into()),
+        };
+    let expected = MIGRATOR.iter().map(|m| m.version).max().unwrap_or_default();
+
+    if found != Some(expected) {
+        return Err(DbError::SchemaMismatch {
+            path: path.to_owned(),
+            found: found.unwrap_or_default(),
+            expected,
+        });
+    }
+
+    debug!(?path, "opened index for reading");
+    Ok(Arc::new(pool))
+}
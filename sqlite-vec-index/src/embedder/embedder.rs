@@ -3,11 +3,10 @@
 
 use std::{
 //    collections::HashMap,
+    collections::{BTreeSet, VecDeque},
     path::Path,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
-    },
+    sync::{Arc, Condvar, Mutex},
+    time::Instant,
 };
 
 use async_trait::async_trait;
@@ -17,35 +16,236 @@ use ort::{
     Environment, ExecutionProvider, GraphOptimizationLevel, LoggingLevel, SessionBuilder,
 };
 
+use crate::semantic_search::schema::Payload;
+
 pub type Embedding = Vec<f32>;
 
+/// How much the [`EmbedQueue`] holds before producers have to wait.
+///
+/// Whichever limit is hit first applies. A single chunk larger than
+/// `max_bytes` is still let through when the queue is empty.
+#[derive(Debug, Clone, Copy)]
+pub struct EmbedQueueLimits {
+    pub max_chunks: usize,
+    pub max_bytes: usize,
+}
+
+impl Default for EmbedQueueLimits {
+    fn default() -> Self {
+        Self {
+            max_chunks: 4096,
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct EmbedQueueMetrics {
+    pub depth: usize,
+    pub bytes: usize,
+    pub max_depth: usize,
+    pub max_bytes: usize,
+    pub in_flight: usize,
+    pub workers: usize,
+    pub pushed: u64,
+
+    /// Pushes which had to wait for the queue to drain
+    pub blocked_pushes: u64,
+    pub blocked_secs: f64,
+}
+
+#[derive(Default)]
+struct QueueState {
+    chunks: VecDeque<(u64, EmbedChunk)>,
+    bytes: usize,
+    next_seq: u64,
+    // sequence numbers which are queued or being embedded
+    outstanding: BTreeSet<u64>,
+    // chunks up to here are taken in partial batches, someone is waiting
+    flush_until: u64,
+    closed: bool,
+    workers: usize,
+    metrics: EmbedQueueMetrics,
+}
+
+/// Chunks waiting to be embedded.
+///
+/// With workers attached the queue is bounded, and [`EmbedQueue::push`]
+/// blocks while it's full, so the walk can't get arbitrarily far ahead of
+/// the embedder. Without workers it behaves like an unbounded queue, which
+/// is drained by whoever is producing.
+///
+/// Every chunk gets a sequence number, so a caller can wait for everything
+/// pushed up to a point to be done, see [`EmbedQueue::wait_for`].
 #[derive(Default)]
 pub struct EmbedQueue {
-    log: scc::Queue<Mutex<Option<EmbedChunk>>>,
-    len: AtomicUsize,
+    limits: EmbedQueueLimits,
+    state: Mutex<QueueState>,
+    changed: Condvar,
 }
 
 impl EmbedQueue {
-    pub fn pop(&self) -> Option<EmbedChunk> {
-        let Some(val) = self.log.pop() else {
-            return None;
-        };
+    pub fn bounded(limits: EmbedQueueLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
 
-        // wrapping shouldn't happen, because only decrements when
-        // `log` is non-empty.
-        self.len.fetch_sub(1, Ordering::SeqCst);
+    fn is_full(&self, state: &QueueState, size: usize) -> bool {
+        !state.chunks.is_empty()
+            && (state.chunks.len() >= self.limits.max_chunks
+                || state.bytes + size > self.limits.max_bytes)
+    }
 
-        let val = val.lock().unwrap().take().unwrap();
-        Some(val)
+    pub fn pop(&self) -> Option<EmbedChunk> {
+        let mut state = self.state.lock().unwrap();
+        let (seq, chunk) = state.chunks.pop_front()?;
+        state.bytes -= chunk.size();
+        state.metrics.in_flight += 1;
+        drop(state);
+
+        // callers of `pop` are done with the chunk as far as the queue is
+        // concerned
+        self.complete([seq]);
+        Some(chunk)
     }
 
     pub fn push(&self, chunk: EmbedChunk) {
-        self.log.push(Mutex::new(Some(chunk)));
-        self.len.fetch_add(1, Ordering::SeqCst);
+        let size = chunk.size();
+        let mut state = self.state.lock().unwrap();
+
+        if state.workers > 0 && self.is_full(&state, size) {
+            let started = Instant::now();
+            state.metrics.blocked_pushes += 1;
+            while state.workers > 0 && !state.closed && self.is_full(&state, size) {
+                state = self.changed.wait(state).unwrap();
+            }
+            state.metrics.blocked_secs += started.elapsed().as_secs_f64();
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.outstanding.insert(seq);
+        state.chunks.push_back((seq, chunk));
+        state.bytes += size;
+
+        let metrics = &mut state.metrics;
+        metrics.pushed += 1;
+        metrics.max_depth = metrics.max_depth.max(state.chunks.len());
+        metrics.max_bytes = metrics.max_bytes.max(state.bytes);
+        drop(state);
+
+        self.changed.notify_all();
+    }
+
+    /// Take up to `size` chunks for embedding, waiting for a full batch
+    /// unless someone is flushing or the queue is closed.
+    ///
+    /// Returns `None` once the queue is closed and empty. The sequence
+    /// numbers have to be handed back through [`EmbedQueue::complete`].
+    pub fn pop_batch(&self, size: usize) -> Option<Vec<(u64, EmbedChunk)>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let flushing = state
+                .chunks
+                .front()
+                .is_some_and(|(seq, _)| *seq < state.flush_until);
+
+            let partial = !state.chunks.is_empty() && (flushing || state.closed);
+            if state.chunks.len() >= size || partial {
+                break;
+            }
+
+            if state.closed {
+                return None;
+            }
+
+            state = self.changed.wait(state).unwrap();
+        }
+
+        let take = size.min(state.chunks.len());
+        let batch = state.chunks.drain(..take).collect::<Vec<_>>();
+        state.bytes -= batch.iter().map(|(_, chunk)| chunk.size()).sum::<usize>();
+        state.metrics.in_flight += batch.len();
+        drop(state);
+
+        // there's room for the producers again
+        self.changed.notify_all();
+        Some(batch)
+    }
+
+    /// Mark chunks as embedded, or given up on.
+    pub fn complete(&self, seqs: impl IntoIterator<Item = u64>) {
+        let mut state = self.state.lock().unwrap();
+        for seq in seqs {
+            if state.outstanding.remove(&seq) {
+                state.metrics.in_flight = state.metrics.in_flight.saturating_sub(1);
+            }
+        }
+        drop(state);
+
+        self.changed.notify_all();
+    }
+
+    /// The sequence number the next push will get.
+    ///
+    /// Everything pushed before this call has a lower one.
+    pub fn barrier(&self) -> u64 {
+        self.state.lock().unwrap().next_seq
+    }
+
+    /// Block until every chunk pushed before `barrier` has been completed.
+    ///
+    /// Only makes sense with workers attached, otherwise nobody is going to
+    /// complete anything.
+    pub fn wait_for(&self, barrier: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.flush_until = state.flush_until.max(barrier);
+        self.changed.notify_all();
+
+        while state.workers > 0 && state.outstanding.first().is_some_and(|seq| *seq < barrier) {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Register a worker, which makes the queue bounded.
+    pub fn attach_worker(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.workers += 1;
+        state.closed = false;
+    }
+
+    pub fn detach_worker(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.workers -= 1;
+        drop(state);
+
+        self.changed.notify_all();
+    }
+
+    /// No more chunks are coming, workers drain what's left and exit.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+
+    pub fn has_workers(&self) -> bool {
+        self.state.lock().unwrap().workers > 0
+    }
+
+    pub fn metrics(&self) -> EmbedQueueMetrics {
+        let state = self.state.lock().unwrap();
+        EmbedQueueMetrics {
+            depth: state.chunks.len(),
+            bytes: state.bytes,
+            workers: state.workers,
+            ..state.metrics.clone()
+        }
     }
 
     pub fn len(&self) -> usize {
-        self.len.load(Ordering::SeqCst)
+        self.state.lock().unwrap().chunks.len()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -57,7 +257,14 @@ impl EmbedQueue {
 pub struct EmbedChunk {
     pub id: String,
     pub data: String,
-    //pub payload: HashMap<String, qdrant_client::qdrant::Value>,
+    pub payload: Payload,
+}
+
+impl EmbedChunk {
+    /// Roughly what the chunk costs in memory, the text is in there twice
+    fn size(&self) -> usize {
+        self.data.len() + self.payload.text.len()
+    }
 }
 
 #[async_trait]
@@ -0,0 +1,103 @@
+// A cross-encoder which scores (query, text) pairs, for re-ranking what the
+// bi-encoder retrieved. It runs on the same ort runtime as `LocalEmbedder`,
+// so `init_ort_dylib` has to be called before creating one.
+
+use std::{path::Path, sync::Arc};
+
+use ndarray::Axis;
+use ort::{
+    tensor::{FromArray, InputTensor, OrtOwnedTensor},
+    Environment, ExecutionProvider, GraphOptimizationLevel, LoggingLevel, SessionBuilder,
+};
+
+pub trait Reranker: Send + Sync {
+    /// Relevance of every text to `query`, higher is better.
+    ///
+    /// Scores are only comparable with other scores from the same model.
+    fn score(&self, query: &str, texts: &[&str]) -> anyhow::Result<Vec<f32>>;
+}
+
+pub struct CrossEncoder {
+    session: ort::Session,
+    tokenizer: tokenizers::Tokenizer,
+}
+
+impl CrossEncoder {
+    pub fn new(model_dir: &Path) -> anyhow::Result<Self> {
+        let environment = Arc::new(
+            Environment::builder()
+                .with_name("reranking")
+                .with_log_level(LoggingLevel::Warning)
+                .with_execution_providers([ExecutionProvider::cpu()])
+                .build()?,
+        );
+
+        let threads = if let Ok(v) = std::env::var("NUM_OMP_THREADS") {
+            str::parse(&v).unwrap_or(1)
+        } else {
+            1
+        };
+
+        let session = SessionBuilder::new(&environment)?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_intra_threads(threads)?
+            .with_model_from_file(model_dir.join("onnx").join("model.onnx"))?;
+
+        let tokenizer = tokenizers::Tokenizer::from_file(model_dir.join("tokenizer.json"))
+            .map_err(|err| anyhow::anyhow!("failed to load tokenizer: {err}"))?;
+
+        Ok(Self { session, tokenizer })
+    }
+
+    fn score_pair(&self, query: &str, text: &str) -> anyhow::Result<f32> {
+        let tokenizer_output = self
+            .tokenizer
+            .encode((query, text), true)
+            .map_err(|err| anyhow::anyhow!("failed to tokenize: {err}"))?;
+
+        let input_ids = tokenizer_output.get_ids();
+        let attention_mask = tokenizer_output.get_attention_mask();
+        let token_type_ids = tokenizer_output.get_type_ids();
+        let length = input_ids.len();
+
+        let inputs_ids_array = ndarray::Array::from_shape_vec(
+            (1, length),
+            input_ids.iter().map(|&x| x as i64).collect(),
+        )?;
+
+        let attention_mask_array = ndarray::Array::from_shape_vec(
+            (1, length),
+            attention_mask.iter().map(|&x| x as i64).collect(),
+        )?;
+
+        let token_type_ids_array = ndarray::Array::from_shape_vec(
+            (1, length),
+            token_type_ids.iter().map(|&x| x as i64).collect(),
+        )?;
+
+        let outputs = self.session.run([
+            InputTensor::from_array(inputs_ids_array.into_dyn()),
+            InputTensor::from_array(attention_mask_array.into_dyn()),
+            InputTensor::from_array(token_type_ids_array.into_dyn()),
+        ])?;
+
+        // a single relevance logit per pair
+        let output_tensor: OrtOwnedTensor<f32, _> = outputs[0].try_extract()?;
+        let logits = &*output_tensor.view();
+        logits
+            .index_axis(Axis(0), 0)
+            .iter()
+            .next()
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("cross-encoder returned no score"))
+    }
+}
+
+impl Reranker for CrossEncoder {
+    fn score(&self, query: &str, texts: &[&str]) -> anyhow::Result<Vec<f32>> {
+        texts
+            .iter()
+            .map(|text| self.score_pair(query, text))
+            .collect()
+    }
+}
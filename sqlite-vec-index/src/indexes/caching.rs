@@ -4,15 +4,15 @@
 // Now the way we want to go about doing this:
 // we use a fs based system and wrap it in a lock so we are okay with things
 
-//use qdrant_client::qdrant::{PointId, PointStruct};
 use rayon::iter::ParallelIterator;
 use scc::hash_map::Entry;
 use sqlx::Sqlite;
 use std::collections::HashSet;
 use std::ops::Deref;
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info, trace, warn};
 
 use anyhow::Result;
@@ -22,11 +22,32 @@ use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
 use uuid::Uuid;
 
+use crate::application::background::{IndexPhase, SyncPipes};
 use crate::db::sqlite::SqlDb;
-use crate::embedder::embedder::{EmbedChunk, EmbedQueue};
+use crate::embedder::embedder::{
+    EmbedChunk, EmbedQueue, EmbedQueueLimits, EmbedQueueMetrics, Embedding,
+};
+use crate::metrics::METRICS;
+use crate::repo::encoding::{self, OffsetMap};
+use crate::repo::ownership::Ownership;
 use crate::repo::types::RepoRef;
 use crate::semantic_search::client::SemanticClient;
+use crate::semantic_search::documents;
+use crate::semantic_search::history;
 use crate::semantic_search::schema::Payload;
+use crate::semantic_search::vectors::{self, VectorStorage};
+use crate::state::schema_version::get_schema_version;
+
+use super::{symbols, trigram};
+
+/// Checkpoint once this many files are done since the last checkpoint
+const CHECKPOINT_FILES: usize = 256;
+
+/// ... or once this much time has passed, whichever comes first
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Embedding workers per repository sync, see [`FileCache::embed_while`]
+const DEFAULT_EMBEDDING_WORKERS: usize = 2;
 
 /// The extra information here which we need for the code snippet is the line_start
 /// and the line_end
@@ -49,6 +70,20 @@ impl SnippetCacheKeys {
             content_hash,
         }
     }
+
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+
+    /// The `unique_hash` of the snippets in the tantivy index built from
+    /// this version of the file
+    pub fn unique_hash(&self) -> String {
+        let mut hash = blake3::Hasher::new();
+        hash.update(self.commit_hash.as_bytes());
+        hash.update(self.file_path.as_bytes());
+        hash.update(self.content_hash.as_bytes());
+        hash.finalize().to_hex().to_string()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Hash, Eq)]
@@ -202,6 +237,20 @@ pub struct FileCache<'a> {
     reporef: &'a RepoRef,
     semantic: Option<&'a SemanticClient>,
     embed_queue: EmbedQueue,
+    // file cache keys of files where at least one chunk failed to embed,
+    // these must not be marked as indexed in `file_cache`
+    failed: scc::HashSet<String>,
+    vector_storage: VectorStorage,
+    // files whose chunks have all been queued since the last checkpoint
+    completed: std::sync::Mutex<Vec<CacheKeys>>,
+    last_checkpoint: std::sync::Mutex<Instant>,
+    // held from popping a batch off the queue until it's committed, so a
+    // flush knows nothing is still in flight when it returns
+    embedding: Mutex<()>,
+    embedding_workers: usize,
+    progress: Option<&'a SyncPipes>,
+    // blame and owners recorded on every chunk, if enabled
+    ownership: Option<Ownership>,
 }
 
 impl<'a> FileCache<'a> {
@@ -215,12 +264,69 @@ impl<'a> FileCache<'a> {
             reporef,
             semantic,
             embed_queue: Default::default(),
+            failed: Default::default(),
+            vector_storage: Default::default(),
+            completed: Default::default(),
+            last_checkpoint: Instant::now().into(),
+            embedding: Default::default(),
+            embedding_workers: DEFAULT_EMBEDDING_WORKERS,
+            progress: None,
+            ownership: None,
         }
     }
 
+    /// Bound the embedding queue, producers wait for the workers to catch
+    /// up when it's full.
+    pub fn with_embed_queue_limits(mut self, limits: EmbedQueueLimits) -> Self {
+        self.embed_queue = EmbedQueue::bounded(limits);
+        self
+    }
+
+    pub fn with_embedding_workers(mut self, workers: usize) -> Self {
+        self.embedding_workers = workers.max(1);
+        self
+    }
+
+    pub fn embed_queue_metrics(&self) -> EmbedQueueMetrics {
+        self.embed_queue.metrics()
+    }
+
+    /// Record who last changed each chunk, and who owns its file, from the
+    /// repository at `disk_path`. This runs `git blame` on every file we
+    /// chunk, so it's off by default.
+    pub fn with_ownership(mut self, disk_path: &Path) -> Self {
+        self.ownership = Some(Ownership::load(disk_path));
+        self
+    }
+
+    /// Report chunking and embedding progress of the sync through `pipes`.
+    pub fn with_progress(mut self, pipes: &'a SyncPipes) -> Self {
+        self.progress = Some(pipes);
+        self
+    }
+
+    fn report(&self, update: impl FnOnce(&SyncPipes)) {
+        if let Some(pipes) = self.progress {
+            (update)(pipes);
+        }
+    }
+
+    /// Store new vectors, and move existing ones, into `storage`.
+    pub fn with_vector_storage(mut self, storage: VectorStorage) -> Self {
+        self.vector_storage = storage;
+        self
+    }
+
     // Retrieve a file-level snapshot of the cache for the repository in scope.
     pub(crate) async fn retrieve(&'a self) -> FileCacheSnapshot<'a> {
         let repo_str = self.reporef.to_string();
+
+        // Changing the storage doesn't invalidate anything in the cache, we
+        // keep full precision vectors around to convert from.
+        if let Err(err) = self.convert_vectors(&repo_str).await {
+            warn!(?err, ?self.reporef, "failed to convert vector storage");
+        }
+
         let rows = sqlx::query! {
             "SELECT file_content_hash, file_path, commit_hash, semantic_search_hash FROM file_cache \
              WHERE repo_ref = ?",
@@ -242,12 +348,52 @@ impl<'a> FileCache<'a> {
             );
         }
 
+        // Files an interrupted sync already finished are as good as cached,
+        // so we resume right after them.
+        let checkpointed = sqlx::query! {
+            "SELECT file_content_hash, file_path, commit_hash, semantic_search_hash \
+             FROM index_checkpoints WHERE repo_ref = ?",
+            repo_str,
+        }
+        .fetch_all(self.sqlite.as_ref())
+        .await;
+
+        let mut resumed = 0;
+        for row in checkpointed.into_iter().flatten() {
+            resumed += 1;
+            _ = output.insert(
+                CacheKeys {
+                    semantic: row.semantic_search_hash,
+                    commit_hash: row.commit_hash,
+                    file_path: row.file_path,
+                    file_content_hash: row.file_content_hash,
+                },
+                FreshValue::stale(()),
+            );
+        }
+
+        if resumed > 0 {
+            info!(?self.reporef, resumed, "resuming from checkpoint");
+        }
+
         FileCacheSnapshot {
             snapshot: output.into(),
             parent: self,
         }
     }
 
+    async fn convert_vectors(&self, repo_str: &str) -> anyhow::Result<()> {
+        let mut tx = self.sqlite.begin().await?;
+        let converted = vectors::convert(&mut tx, repo_str, self.vector_storage).await?;
+        tx.commit().await?;
+
+        if converted > 0 {
+            info!(?self.reporef, converted, storage = ?self.vector_storage, "converted vectors");
+        }
+
+        Ok(())
+    }
+
     async fn delete_files(&self, tx: &mut sqlx::Transaction<'_, Sqlite>) -> anyhow::Result<()> {
         let repo_str = self.reporef.to_string();
         sqlx::query! {
@@ -261,6 +407,21 @@ impl<'a> FileCache<'a> {
         Ok(())
     }
 
+    async fn delete_checkpoint(
+        &self,
+        tx: &mut sqlx::Transaction<'_, Sqlite>,
+    ) -> anyhow::Result<()> {
+        let repo_str = self.reporef.to_string();
+        sqlx::query! {
+            "DELETE FROM index_checkpoints WHERE repo_ref = ?",
+            repo_str
+        }
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
     async fn delete_chunks(&self, tx: &mut sqlx::Transaction<'_, Sqlite>) -> anyhow::Result<()> {
         let repo_str = self.reporef.to_string();
         sqlx::query! {
@@ -273,17 +434,25 @@ impl<'a> FileCache<'a> {
         Ok(())
     }
 
+    /// Write the state of the file cache back to SQLite.
+    ///
+    /// The embedding queue is flushed first, so by the time a file shows up
+    /// in `file_cache` every one of its chunks has a vector. Stale vectors,
+    /// their `chunk_cache` rows and the new `file_cache` rows are then
+    /// committed in a single transaction.
     pub(crate) async fn synchronize(
         &'a self,
         cache: FileCacheSnapshot<'a>,
     ) -> anyhow::Result<()> {
         debug!(?self.reporef, "synchronizing file cache");
-        let mut tx = self.sqlite.begin().await?;
-        // First we clean-up our cache here by calling delete files
-        self.delete_files(&mut tx).await?;
+
+        // make sure we generate & commit all remaining embeddings
+        self.report(|pipes| pipes.advance(IndexPhase::Embedding));
+        self.batched_embed_or_flush_queue(true).await?;
+        self.report(|pipes| pipes.advance(IndexPhase::Committing));
 
         // files that are no longer tracked are to be removed
-        let qdrant_stale = {
+        let stale = {
             let mut semantic_fresh = HashSet::new();
             let mut semantic_all = HashSet::new();
 
@@ -306,21 +475,38 @@ impl<'a> FileCache<'a> {
                 .collect::<Vec<_>>()
         };
 
+        let repo_str = self.reporef.to_string();
+        let mut tx = self.sqlite.begin().await?;
+        // First we clean-up our cache here by calling delete files
+        self.delete_files(&mut tx).await?;
+
+        // the checkpointed files are in the snapshot, and the sync is done
+        self.delete_checkpoint(&mut tx).await?;
+        self.completed.lock().unwrap().clear();
+
         // generate a transaction to push the remaining entries
         // into the sql cache
         {
             let mut next = cache.first_occupied_entry_async().await;
             while let Some(entry) = next {
                 let key = entry.key();
-                let semantic_key = key.semantic().to_owned();
-                let commit_hash = key.commit_hash.to_owned();
+                next = entry.next();
+
+                // leaving the file out means it's picked up again next
+                // time, and only the missing chunks get embedded
+                if self.failed.contains(key.semantic()) {
+                    debug!(?key, "not caching file with failed embeddings");
+                    continue;
+                }
+
+                let semantic_key = key.semantic();
+                let commit_hash = key.commit_hash();
                 let file_path = key.file_path();
                 let file_content_hash = key.file_content_hash();
-                let repo_str = self.reporef.to_string();
                 sqlx::query!(
                     "INSERT INTO file_cache \
                  (repo_ref, semantic_search_hash, commit_hash, file_path, file_content_hash) \
-                         VALUES (?, ?, ?, ?, ?, ?)",
+                         VALUES (?, ?, ?, ?, ?)",
                     repo_str,
                     semantic_key,
                     commit_hash,
@@ -329,28 +515,26 @@ impl<'a> FileCache<'a> {
                 )
                 .execute(&mut *tx)
                 .await?;
-
-                next = entry.next();
             }
-
-            tx.commit().await?;
         }
 
-        // batch-delete points from qdrant index
-        if !qdrant_stale.is_empty() {
-            if let Some(semantic) = self.semantic {
-                let semantic = semantic.clone();
-                let reporef = self.reporef.to_string();
-                tokio::spawn(async move {
-                    semantic
-                        .delete_points_for_hash(reporef.as_str(), qdrant_stale.into_iter())
-                        .await;
-                });
+        // chunks of files which are gone, or changed, go together with
+        // their vectors
+        if !stale.is_empty() {
+            for file_cache_key in stale.iter() {
+                sqlx::query! {
+                    "DELETE FROM chunk_cache WHERE repo_ref = ? AND file_cache_key = ?",
+                    repo_str,
+                    file_cache_key,
+                }
+                .execute(&mut *tx)
+                .await?;
             }
+
+            vectors::delete_for_content_hashes(&mut tx, &repo_str, &stale).await?;
         }
 
-        // make sure we generate & commit all remaining embeddings
-        self.batched_embed_or_flush_queue(true).await?;
+        tx.commit().await?;
 
         Ok(())
     }
@@ -361,17 +545,48 @@ impl<'a> FileCache<'a> {
             return Ok(());
         };
 
+        // the workers do the embedding, all we can do is wait for them
+        if self.embed_queue.has_workers() {
+            if flush {
+                let barrier = self.embed_queue.barrier();
+                tokio::task::block_in_place(|| self.embed_queue.wait_for(barrier));
+            }
+
+            return Ok(());
+        }
+
+        let _embedding = self.embedding.lock().await;
         let new_points = self.embed_queued_points(semantic, flush).await?;
+        self.store_embeddings(&new_points).await
+    }
 
-        if !new_points.is_empty() {
-            if let Err(err) = semantic
-                .qdrant_client()
-                .upsert_points(&semantic.collection_name(), new_points, None)
-                .await
-            {
-                error!(?err, "failed to write new points into qdrant");
+    /// The vector and the `chunk_cache` row for a chunk are only ever
+    /// written together, so the cache never claims a chunk is embedded when
+    /// it is not, and there are no vectors the cache doesn't know about.
+    async fn store_embeddings(&self, points: &[(EmbedChunk, Embedding)]) -> anyhow::Result<()> {
+        if points.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.sqlite.begin().await?;
+        for (chunk, embedding) in points.iter() {
+            let EmbedChunk { id, payload, .. } = chunk;
+            vectors::insert(&mut tx, self.vector_storage, id, embedding, payload).await?;
+
+            sqlx::query! {
+                "insert into chunk_cache (chunk_hash, commit_hash, file_cache_key, repo_ref, file_path) \
+                VALUES (?, ?, ?, ?, ?)",
+                id, payload.commit_hash, payload.content_hash, payload.repo_ref, payload.relative_path,
             }
+            .execute(&mut *tx)
+            .await?;
         }
+        tx.commit().await?;
+
+        self.report(|pipes| {
+            pipes.advance(IndexPhase::Embedding);
+            pipes.chunks_embedded(points.len());
+        });
         Ok(())
     }
 
@@ -381,7 +596,7 @@ impl<'a> FileCache<'a> {
         &self,
         semantic: &SemanticClient,
         flush: bool,
-    ) -> Result<Vec<PointStruct>, anyhow::Error> {
+    ) -> Result<Vec<(EmbedChunk, Embedding)>, anyhow::Error> {
         let batch_size = semantic.get_embedding_queue_size();
         let log = &self.embed_queue;
         debug!(?batch_size, ?self.reporef, "embedding queue");
@@ -404,49 +619,179 @@ impl<'a> FileCache<'a> {
                 }
             }
 
-            let (elapsed, res) = {
-                let time = Instant::now();
-                let res = semantic
-                    .get_embedder()
-                    .batch_embed(batch.iter().map(|c| c.data.as_ref()).collect::<Vec<_>>())
-                    .await;
-
-                (time.elapsed(), res)
-            };
-
-            match res {
-                Ok(res) => {
-                    trace!(?elapsed, size = batch.len(), "batch embedding successful");
-                    output.extend(
-                        res.into_iter()
-                            .zip(batch)
-                            .map(|(embedding, src)| PointStruct {
-                                id: Some(PointId::from(src.id)),
-                                vectors: Some(embedding.into()),
-                                payload: src.payload,
-                            }),
-                    )
+            output.extend(self.embed_batch(semantic, batch).await);
+        }
+    }
+
+    async fn embed_batch(
+        &self,
+        semantic: &SemanticClient,
+        batch: Vec<EmbedChunk>,
+    ) -> Vec<(EmbedChunk, Embedding)> {
+        let (elapsed, res) = {
+            let time = Instant::now();
+            let res = semantic
+                .get_embedder()
+                .batch_embed(batch.iter().map(|c| c.data.as_ref()).collect::<Vec<_>>())
+                .await;
+
+            (time.elapsed(), res)
+        };
+        METRICS.embedding_batch.observe(elapsed);
+
+        match res {
+            Ok(res) => {
+                trace!(?elapsed, size = batch.len(), "batch embedding successful");
+                batch.into_iter().zip(res).collect()
+            }
+            Err(err) => {
+                error!(
+                    ?err,
+                    ?elapsed,
+                    size = batch.len(),
+                    "remote batch embeddings failed"
+                );
+
+                for chunk in batch {
+                    _ = self.failed.insert(chunk.payload.content_hash);
                 }
-                Err(err) => {
-                    error!(
-                        ?err,
-                        ?elapsed,
-                        size = batch.len(),
-                        "remote batch embeddings failed"
-                    )
+                vec![]
+            }
+        }
+    }
+
+    /// Run `produce` with a pool of embedding workers draining the queue.
+    ///
+    /// While the workers are running the queue is bounded, so whatever
+    /// `produce` pushes waits for room instead of piling up in memory. The
+    /// queue is drained by the time this returns.
+    ///
+    /// This blocks, so call it from a blocking context.
+    pub fn embed_while<T: Send>(&self, produce: impl FnOnce() -> T + Send) -> T {
+        let Some(semantic) = self.semantic else {
+            return produce();
+        };
+
+        let handle = tokio::runtime::Handle::current();
+        std::thread::scope(|scope| {
+            // the scope waits for the workers, which only stop once the
+            // queue is closed, even if `produce` panics
+            let _closing = CloseGuard(&self.embed_queue);
+            for n in 0..self.embedding_workers {
+                // attach before spawning, so the queue is bounded from the
+                // first push
+                self.embed_queue.attach_worker();
+                let handle = handle.clone();
+
+                std::thread::Builder::new()
+                    .name(format!("embed-worker-{n}"))
+                    .spawn_scoped(scope, move || {
+                        let _worker = WorkerGuard(&self.embed_queue);
+                        self.embedding_worker(semantic, &handle);
+                    })
+                    .expect("failed to spawn embedding worker");
+            }
+
+            produce()
+        })
+    }
+
+    fn embedding_worker(&self, semantic: &SemanticClient, handle: &tokio::runtime::Handle) {
+        let batch_size = semantic.get_embedding_queue_size();
+
+        while let Some(batch) = self.embed_queue.pop_batch(batch_size) {
+            let (seqs, chunks): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+            let files = chunks
+                .iter()
+                .map(|chunk| chunk.payload.content_hash.clone())
+                .collect::<Vec<_>>();
+
+            let stored = handle.block_on(async {
+                let points = self.embed_batch(semantic, chunks).await;
+                self.store_embeddings(&points).await
+            });
+
+            if let Err(err) = stored {
+                error!(?err, ?self.reporef, "failed to store embeddings");
+                for file in files {
+                    _ = self.failed.insert(file);
                 }
             }
+
+            self.embed_queue.complete(seqs);
+
+            let metrics = self.embed_queue.metrics();
+            METRICS.embed_queue_depth.set(metrics.depth as i64);
+            trace!(?metrics, "embedding queue");
         }
     }
 
-    /// Process the next chunk from the embedding queue if the batch size is met.
+    /// Process the next chunk from the embedding queue if the batch size is met,
+    /// and checkpoint the finished files if one is due.
     pub fn process_embedding_queue(&self) -> anyhow::Result<()> {
         tokio::task::block_in_place(|| {
-            tokio::runtime::Handle::current()
-                .block_on(async { self.batched_embed_or_flush_queue(false).await })
+            tokio::runtime::Handle::current().block_on(async {
+                self.batched_embed_or_flush_queue(false).await?;
+
+                if self.checkpoint_due() {
+                    self.checkpoint().await?;
+                }
+
+                Ok(())
+            })
         })
     }
 
+    fn checkpoint_due(&self) -> bool {
+        let completed = self.completed.lock().unwrap().len();
+        let elapsed = self.last_checkpoint.lock().unwrap().elapsed();
+        completed >= CHECKPOINT_FILES || (completed > 0 && elapsed >= CHECKPOINT_INTERVAL)
+    }
+
+    /// Record the files finished since the last checkpoint, so a sync which
+    /// is cancelled or dies can pick up after them.
+    ///
+    /// The queue is flushed first, so a checkpointed file has a vector for
+    /// every one of its chunks.
+    pub(crate) async fn checkpoint(&self) -> anyhow::Result<()> {
+        let completed = std::mem::take(&mut *self.completed.lock().unwrap());
+        *self.last_checkpoint.lock().unwrap() = Instant::now();
+        if completed.is_empty() {
+            return Ok(());
+        }
+
+        self.batched_embed_or_flush_queue(true).await?;
+
+        let repo_str = self.reporef.to_string();
+        let mut tx = self.sqlite.begin().await?;
+        for key in completed.iter() {
+            if self.failed.contains(key.semantic()) {
+                continue;
+            }
+
+            let semantic_key = key.semantic();
+            let commit_hash = key.commit_hash();
+            let file_path = key.file_path();
+            let file_content_hash = key.file_content_hash();
+            sqlx::query! {
+                "INSERT OR REPLACE INTO index_checkpoints \
+                 (repo_ref, semantic_search_hash, commit_hash, file_path, file_content_hash) \
+                 VALUES (?, ?, ?, ?, ?)",
+                repo_str,
+                semantic_key,
+                commit_hash,
+                file_path,
+                file_content_hash,
+            }
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        debug!(?self.reporef, files = completed.len(), "checkpointed index");
+        Ok(())
+    }
+
     pub async fn process_chunks(
         &self,
         cache_keys: &CacheKeys,
@@ -457,9 +802,19 @@ impl<'a> FileCache<'a> {
         lang_str: &str,
         branches: &[String],
         file_extension: Option<&str>,
+        offsets: &OffsetMap,
     ) -> anyhow::Result<()> {
         let chunk_cache = self.chunks_for_file(cache_keys, relative_path).await;
         let semantic = self.semantic.expect("uninitialized semantic db");
+        self.report(|pipes| pipes.advance(IndexPhase::Chunking));
+
+        let (blame, owners) = match self.ownership {
+            Some(ref ownership) => (
+                ownership.blame(relative_path).await,
+                ownership.owners(relative_path),
+            ),
+            None => (None, vec![]),
+        };
 
         semantic
             .chunks_for_buffer(
@@ -471,8 +826,14 @@ impl<'a> FileCache<'a> {
                 lang_str,
                 branches,
                 file_extension,
+                offsets,
             )
-            .for_each(|(data, payload)| {
+            .for_each(|(data, mut payload)| {
+                if let Some(ref blame) = blame {
+                    payload.blame = blame.range(payload.start_line, payload.end_line);
+                }
+                payload.owners = owners.clone();
+
                 let cached = chunk_cache.update_or_embed(&data, payload);
                 if let Err(err) = cached {
                     warn!(?err, %repo_name, %relative_path, "embedding failed");
@@ -481,10 +842,14 @@ impl<'a> FileCache<'a> {
 
         match chunk_cache.commit().await {
             Ok((new, updated, deleted)) => {
+                METRICS.chunks_committed((new, updated, deleted));
+                METRICS.embed_queue_depth.set(self.embed_queue.len() as i64);
                 info!(
                     repo_name,
                     relative_path, new, updated, deleted, "Successful commit"
-                )
+                );
+                self.report(|pipes| pipes.chunks_queued(new));
+                self.completed.lock().unwrap().push(cache_keys.clone());
             }
             Err(err) => {
                 warn!(repo_name, relative_path, ?err, "Failed to upsert vectors")
@@ -500,8 +865,6 @@ impl<'a> FileCache<'a> {
     ) -> ChunkCache<'a> {
         ChunkCache::for_file(
             self.sqlite,
-            self.semantic
-                .expect("we shouldn't get here without semantic db configured"),
             self.reporef,
             &self.embed_queue,
             key.semantic(),
@@ -513,17 +876,42 @@ impl<'a> FileCache<'a> {
     pub async fn delete(&self) -> Result<()> {
         // For deleting, we have to do the following:
         // - 1. clenup the current file cache and the chunk cache
-        // - 2. cleanup the adrant data which we have as its not required
+        // - 2. cleanup the vectors, which are useless without the cache
+        // - 3. cleanup what else we keep per repository
+        let repo_str = self.reporef.to_string();
         let mut tx = self.sqlite.begin().await?;
         // First we clean-up our cache here by calling delete files
         self.delete_files(&mut tx).await?;
         // Next delete the chunk cache
         self.delete_chunks(&mut tx).await?;
+        self.delete_checkpoint(&mut tx).await?;
+        vectors::delete_for_repo(&mut tx, &repo_str).await?;
+        history::delete_for_repo(&mut tx, &repo_str).await?;
+        tx.commit().await?;
         Ok(())
     }
 }
 
-/// Manage both the SQL cache and the underlying qdrant database to
+/// Detaches an embedding worker from the queue even if it panics, so
+/// nobody is left waiting on it.
+struct WorkerGuard<'a>(&'a EmbedQueue);
+
+impl Drop for WorkerGuard<'_> {
+    fn drop(&mut self) {
+        self.0.detach_worker();
+    }
+}
+
+/// Closes the embedding queue when dropped, see [`FileCache::embed_while`]
+struct CloseGuard<'a>(&'a EmbedQueue);
+
+impl Drop for CloseGuard<'_> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/// Manage both the SQL cache and the underlying vector table to
 /// ensure consistency.
 ///
 /// Operates on a single file's level.
@@ -533,14 +921,13 @@ impl<'a> FileCache<'a> {
 /// and using that as part of the caching algorithm and keeping the state using that
 /// we try to commit them in that order and use that as our unique key.
 ///
-/// What we want to maintain over here is the cache consistency with the qdrant
-/// layer where we store the vectors.
+/// What we want to maintain over here is the cache consistency with the
+/// `chunk_vectors` table where we store the vectors.
 ///
 /// For that, we want to map this to the semantic_key of the file and use that to
 /// populate the entries and check what's going to happen
 pub struct ChunkCache<'a> {
     sql: &'a SqlDb,
-    semantic: &'a SemanticClient,
     reporef: &'a RepoRef,
     // this is the unique cache key for the file which can be used to identify
     // the file and it only belongs to a single file
@@ -556,16 +943,15 @@ pub struct ChunkCache<'a> {
     // as these need to be updated, and not inserted a new (these chunks were already present in
     // the repo but they were part of a different commit)
     update_to_commit_hash: scc::HashMap<String, Vec<String>>,
-    // for the new sql, here we are keeping track of the rows we have to add
-    // so here we are going for a mapping from
-    new_sql: RwLock<Vec<(String, String)>>,
+    // number of chunks we queued for embedding, their rows are written
+    // together with the vectors once the queue is processed
+    queued: AtomicUsize,
     embed_queue: &'a EmbedQueue,
 }
 
 impl<'a> ChunkCache<'a> {
     async fn for_file(
         sql: &'a SqlDb,
-        semantic: &'a SemanticClient,
         reporef: &'a RepoRef,
         embed_log: &'a EmbedQueue,
         file_cache_key: &'a str,
@@ -586,21 +972,21 @@ impl<'a> ChunkCache<'a> {
 
         Self {
             sql,
-            semantic,
             reporef,
             file_cache_key,
             file_path,
             cache_to_commit_hash: cache,
             embed_queue: embed_log,
             update_to_commit_hash: Default::default(),
-            new_sql: Default::default(),
+            queued: Default::default(),
         }
     }
 
     /// Update a cache entry with the details from `payload`, or create a new embedding.
     ///
     /// New insertions are queued, and stored on the repository-level
-    /// `FileCache` instance that created this.
+    /// `FileCache` instance that created this. Nothing is written to
+    /// `chunk_cache` for them until they have been embedded.
     fn update_or_embed(&self, data: &'a str, payload: Payload) -> anyhow::Result<()> {
         let id = self.derive_chunk_uuid(data, &payload);
         let commit_hash = payload.commit_hash.to_owned();
@@ -625,15 +1011,12 @@ impl<'a> ChunkCache<'a> {
                 *existing.get_mut() = commit_hash.into();
             }
             scc::hash_map::Entry::Vacant(vacant) => {
-                self.new_sql
-                    .write()
-                    .unwrap()
-                    .push((vacant.key().to_owned(), commit_hash.clone()));
+                self.queued.fetch_add(1, Ordering::Relaxed);
 
                 self.embed_queue.push(EmbedChunk {
                     id: vacant.key().clone(),
                     data: data.into(),
-                    payload: payload.into_qdrant(),
+                    payload,
                 });
 
                 vacant.insert_entry(commit_hash.into());
@@ -643,58 +1026,30 @@ impl<'a> ChunkCache<'a> {
         Ok(())
     }
 
-    /// Commit both qdrant and cache changes to the respective databases.
+    /// Commit updates and deletions to both the cache and the vector table.
     ///
-    /// The SQLite operations mirror qdrant changes 1:1, so any
-    /// discrepancy between the 2 should be minimized.
+    /// Both are written in the same SQLite transaction, so they either
+    /// land together or not at all.
     ///
-    /// In addition, the SQLite cache is committed only AFTER all
-    /// qdrant writes have successfully completed, meaning they're in
-    /// qdrant's pipelines.
-    ///
-    /// Since qdrant changes are pipelined on their end, data written
-    /// here is not necessarily available for querying when the
-    /// commit's completed.
+    /// New chunks are not written here, they are committed together with
+    /// their vectors once the embedding queue is processed, see
+    /// [`FileCache::batched_embed_or_flush_queue`].
     pub async fn commit(self) -> anyhow::Result<(usize, usize, usize)> {
         let mut tx = self.sql.begin().await?;
 
         let update_size = self.commit_commit_hash_updates(&mut tx).await?;
         let delete_size = self.commit_deletes(&mut tx).await?;
-        let new_size = self.commit_inserts(&mut tx).await?;
 
         tx.commit().await?;
 
+        let new_size = self.queued.load(Ordering::Relaxed);
+        debug!(
+            ?self.reporef,
+            self.file_path, new_size, update_size, delete_size, "committed chunk cache"
+        );
         Ok((new_size, update_size, delete_size))
     }
 
-    /// Insert new additions to sqlite
-    ///
-    /// Note this step will update the cache before changes are
-    /// actually written to qdrant in batches.
-    ///
-    /// All qdrant operations are executed in batches through a call
-    /// to [`FileCache::commit_embed_log`].
-    async fn commit_inserts(
-        &self,
-        tx: &mut sqlx::Transaction<'_, Sqlite>,
-    ) -> Result<usize, anyhow::Error> {
-        let new_sql = std::mem::take(&mut *self.new_sql.write().unwrap());
-        let new_size = new_sql.len();
-        let _file_path = self.file_path;
-
-        let repo_str = self.reporef.to_string();
-        for (chunk_hash, commit_hash) in new_sql {
-            sqlx::query! {
-                "insert into chunk_cache (chunk_hash, commit_hash, file_cache_key, repo_ref, file_path) \
-                VALUES (?, ?, ?, ?, ?)",
-                chunk_hash, commit_hash, self.file_cache_key, repo_str, self.file_path,
-            }.execute(&mut **tx)
-            .await?;
-        }
-
-        Ok(new_size)
-    }
-
     /// Delete points that have expired in the latest index.
     async fn commit_deletes(
         &self,
@@ -721,20 +1076,7 @@ impl<'a> ChunkCache<'a> {
             .await?;
         }
 
-        if !to_delete.is_empty() {
-            self.semantic
-                .qdrant_client()
-                .delete_points(
-                    self.semantic.collection_name(),
-                    &to_delete
-                        .into_iter()
-                        .map(PointId::from)
-                        .collect::<Vec<_>>()
-                        .into(),
-                    None,
-                )
-                .await?;
-        }
+        vectors::delete(tx, &to_delete).await?;
         Ok(delete_size)
     }
 
@@ -745,7 +1087,6 @@ impl<'a> ChunkCache<'a> {
         tx: &mut sqlx::Transaction<'_, Sqlite>,
     ) -> Result<usize, anyhow::Error> {
         let mut update_size = 0;
-        let mut qdrant_updates = vec![];
 
         let mut next = self.update_to_commit_hash.first_occupied_entry();
         while let Some(entry) = next {
@@ -753,7 +1094,7 @@ impl<'a> ChunkCache<'a> {
             let points = entry.get();
             update_size += points.len();
 
-            for chunk_hash_id in entry.get() {
+            for chunk_hash_id in points {
                 sqlx::query! {
                     "UPDATE chunk_cache SET commit_hash = ? \
                      WHERE chunk_hash = ?",
@@ -764,56 +1105,60 @@ impl<'a> ChunkCache<'a> {
                 .await?;
             }
 
-            let id = points
-                .iter()
-                .cloned()
-                .map(PointId::from)
-                .collect::<Vec<_>>()
-                .into();
-
-            let payload = qdrant_client::client::Payload::new_from_hashmap(
-                [("commit_hash".to_string(), commit_hash.to_owned().into())].into(),
-            );
-
-            qdrant_updates.push(async move {
-                self.semantic
-                    .qdrant_client()
-                    .set_payload(self.semantic.collection_name(), &id, payload, None)
-                    .await
-            });
+            vectors::set_commit_hash(tx, points, commit_hash).await?;
             next = entry.next();
         }
 
-        // Note these actions aren't actually parallel, merely
-        // concurrent.
-        //
-        // This should be fine since the number of updates would be
-        // reasonably small.
-        futures::future::join_all(qdrant_updates.into_iter())
-            .await
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()?;
-
         Ok(update_size)
     }
 
-    /// Generate a content hash from the embedding data, and pin it to
-    /// the containing file's content id.
     fn derive_chunk_uuid(&self, data: &str, payload: &Payload) -> String {
-        let id = {
-            let mut bytes = [0; 16];
-            let mut hasher = blake3::Hasher::new();
-            hasher.update(&payload.start_line.to_le_bytes());
-            hasher.update(&payload.end_line.to_le_bytes());
-            hasher.update(self.file_cache_key.as_bytes());
-            hasher.update(data.as_ref());
-            bytes.copy_from_slice(&hasher.finalize().as_bytes()[16..32]);
-            Uuid::from_bytes(bytes).to_string()
-        };
-        id
+        chunk_id(self.file_cache_key, data, payload.start_line, payload.end_line)
     }
 }
 
+/// The file-level cache key of the semantic index, which identifies the
+/// embeddings of a version of a file.
+///
+/// This pins the file's content to its repository and path, so the same
+/// file in two places has two keys. Embedding the same file with another
+/// model, or with another document prefix, gives different vectors, so
+/// those are part of the key as well.
+pub fn semantic_cache_key(
+    semantic: &SemanticClient,
+    repo_ref: &str,
+    relative_path: &str,
+    buffer: &str,
+) -> String {
+    let model = semantic.model_descriptor();
+    let mut hash = blake3::Hasher::new();
+    hash.update(get_schema_version().as_bytes());
+    hash.update(model.name.as_bytes());
+    hash.update(&(model.dimensions as u64).to_le_bytes());
+    hash.update(semantic.prefixes().document.as_bytes());
+    hash.update(relative_path.as_bytes());
+    hash.update(repo_ref.as_bytes());
+    hash.update(buffer.as_bytes());
+    hash.finalize().to_hex().to_string()
+}
+
+pub fn file_content_hash(buffer: &str) -> String {
+    blake3::hash(buffer.as_bytes()).to_hex().to_string()
+}
+
+/// Generate a content hash from the embedding data, and pin it to
+/// the containing file's content id.
+pub fn chunk_id(file_cache_key: &str, data: &str, start_line: u64, end_line: u64) -> String {
+    let mut bytes = [0; 16];
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&start_line.to_le_bytes());
+    hasher.update(&end_line.to_le_bytes());
+    hasher.update(file_cache_key.as_bytes());
+    hasher.update(data.as_ref());
+    bytes.copy_from_slice(&hasher.finalize().as_bytes()[16..32]);
+    Uuid::from_bytes(bytes).to_string()
+}
+
 /// Here we are going to create snapshots and cache for the code snippets which
 /// we will also use as a lexical search input
 
@@ -856,11 +1201,72 @@ impl<'a> SnippetCacheSnapshot<'a> {
 pub struct SnippetCache<'a> {
     sqlite: &'a SqlDb,
     reporef: &'a RepoRef,
+    // files which were (re-)indexed during this sync and need their
+    // trigrams and symbols updated, absolute path to relative path
+    pending: scc::HashMap<String, String>,
 }
 
 impl<'a> SnippetCache<'a> {
     pub fn for_repo(sqlite: &'a SqlDb, reporef: &'a RepoRef) -> Self {
-        Self { sqlite, reporef }
+        Self {
+            sqlite,
+            reporef,
+            pending: Default::default(),
+        }
+    }
+
+    /// Update the trigrams and symbols of the file at `file_path` when the
+    /// cache is synchronized.
+    ///
+    /// The file is read again at that point rather than kept in memory
+    /// until then.
+    pub fn queue_update(&self, file_path: &str, relative_path: &str) {
+        _ = self
+            .pending
+            .upsert(file_path.to_owned(), relative_path.to_owned());
+    }
+
+    async fn update_files(
+        &self,
+        tx: &mut sqlx::Transaction<'_, Sqlite>,
+        stale: &[String],
+    ) -> anyhow::Result<()> {
+        let repo_str = self.reporef.to_string();
+        for file_path in stale {
+            trigram::delete_for_file(tx, &repo_str, file_path).await?;
+            symbols::delete_for_file(tx, &repo_str, file_path).await?;
+        }
+
+        let mut pending = vec![];
+        self.pending
+            .scan_async(|file_path, relative_path| {
+                pending.push((file_path.clone(), relative_path.clone()))
+            })
+            .await;
+        self.pending.clear_async().await;
+
+        for (file_path, relative_path) in pending {
+            let text = tokio::fs::read(&file_path)
+                .await
+                .ok()
+                .and_then(encoding::decode);
+            match text {
+                Some(buffer) => {
+                    let text = documents::searchable_text(&relative_path, &buffer.text);
+                    trigram::replace(tx, &repo_str, &file_path, &relative_path, &text).await?;
+
+                    let found = symbols::extract(&relative_path, &text);
+                    symbols::replace(tx, &repo_str, &file_path, &found).await?;
+                    trace!(relative_path, symbols = found.len(), "updated symbols");
+                }
+                None => {
+                    trigram::delete_for_file(tx, &repo_str, &file_path).await?;
+                    symbols::delete_for_file(tx, &repo_str, &file_path).await?;
+                }
+            }
+        }
+
+        Ok(())
     }
 
     pub(crate) async fn retrieve(&'a self) -> SnippetCacheSnapshot<'a> {
@@ -904,13 +1310,29 @@ impl<'a> SnippetCache<'a> {
         Ok(())
     }
 
+    /// Write the fresh entries of `cache` back, calling `delete` with the
+    /// unique hash of every stale one, so its snippets can be dropped from
+    /// the index.
     pub(crate) async fn synchronize(
         &'a self,
         cache: SnippetCacheSnapshot<'a>,
+        delete: impl Fn(&str),
     ) -> anyhow::Result<()> {
         debug!(?self.reporef, "synchronizing code snippet cache");
         let mut tx = self.sqlite.begin().await?;
 
+        // files which are gone, or changed, take their trigrams and symbols
+        // with them, the changed ones get them back from the pending updates
+        let mut stale = vec![];
+        cache.retain(|k, v| {
+            if !v.fresh {
+                delete(&k.unique_hash());
+                stale.push(k.file_path.clone());
+            }
+            v.fresh
+        });
+        self.update_files(&mut tx, &stale).await?;
+
         self.delete_files(&mut tx).await?;
 
         // generate a transaction to push the remaining entries
@@ -925,7 +1347,7 @@ impl<'a> SnippetCache<'a> {
                 sqlx::query!(
                     "INSERT INTO code_snippet_cache \
                     (repo_ref, commit_hash, file_path, content_hash) \
-                         VALUES (?, ?, ?, ?, ?)",
+                         VALUES (?, ?, ?, ?)",
                     repo_str,
                     commit_hash,
                     file_path,
@@ -942,11 +1364,29 @@ impl<'a> SnippetCache<'a> {
         Ok(())
     }
 
+    /// Forget what's in the snippet index for every repository, for when
+    /// the index itself is gone and every file has to be indexed again
+    pub(crate) async fn delete_all(sqlite: &SqlDb) -> anyhow::Result<()> {
+        let mut tx = sqlite.begin().await?;
+        sqlx::query!("DELETE FROM code_snippet_cache")
+            .execute(&mut *tx)
+            .await?;
+        trigram::delete_all(&mut tx).await?;
+        symbols::delete_all(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
     pub async fn delete(&self) -> Result<()> {
         // for deleting we have to do the following:
         // - 1. cleanup the code snippet cache
+        // - 2. cleanup the trigrams and symbols of its files
+        let repo_str = self.reporef.to_string();
         let mut tx = self.sqlite.begin().await?;
         self.delete_files(&mut tx).await?;
+        trigram::delete_for_repo(&mut tx, &repo_str).await?;
+        symbols::delete_for_repo(&mut tx, &repo_str).await?;
+        tx.commit().await?;
         Ok(())
     }
 }
@@ -0,0 +1,146 @@
+//! Dropping the data of repositories we no longer index.
+//!
+//! Removing a repository deletes its data as part of its last sync, see
+//! `SyncHandle::delete_repo`. What's left over after a crash, or for a
+//! repository whose directory went away, is picked up here, from the
+//! database and the tantivy indexes both, after which the database is
+//! vacuumed to give the space back.
+
+use std::collections::HashSet;
+
+use sqlx::Row;
+use tracing::{info, warn};
+
+use crate::{db::sqlite::SqlDb, repo::types::RepoRef, semantic_search::client::SemanticClient};
+
+use super::{
+    caching::{FileCache, SnippetCache},
+    GlobalWriteHandleRef,
+};
+
+/// Tables with a `repo_ref` column which we know how to clean up
+const REPO_TABLES: [&str; 13] = [
+    "file_cache",
+    "chunk_cache",
+    "index_checkpoints",
+    "code_snippet_cache",
+    "symbols",
+    "trigram_files",
+    "trigram_postings",
+    "commits",
+    "commit_vectors",
+    "chunk_vectors",
+    "chunk_vectors_int8",
+    "chunk_vectors_bit",
+    "chunk_vectors_full",
+];
+
+#[derive(serde::Serialize, Debug, Clone, Default)]
+pub struct GcReport {
+    /// Repositories whose directory no longer exists, which were removed
+    pub vanished: Vec<RepoRef>,
+
+    /// Repositories which weren't in the pool, but still had data
+    pub orphaned: Vec<String>,
+
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl GcReport {
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+}
+
+/// Delete everything the SQL database has on `reporef`
+pub async fn purge(
+    sql: &SqlDb,
+    semantic: Option<&SemanticClient>,
+    reporef: &RepoRef,
+) -> anyhow::Result<()> {
+    FileCache::for_repo(sql, reporef, semantic).delete().await?;
+    SnippetCache::for_repo(sql, reporef).delete().await?;
+    Ok(())
+}
+
+/// Delete the rows of `repo_ref` from every table we know about, for
+/// references which no longer parse into a [`RepoRef`]
+async fn purge_raw(sql: &SqlDb, repo_ref: &str) -> anyhow::Result<()> {
+    let mut tx = sql.begin().await?;
+    for table in REPO_TABLES {
+        sqlx::query(&format!("DELETE FROM {table} WHERE repo_ref = ?"))
+            .bind(repo_ref)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// The repositories which have data, but aren't in `known`
+pub async fn orphaned(sql: &SqlDb, known: &HashSet<String>) -> anyhow::Result<Vec<String>> {
+    let query = REPO_TABLES
+        .iter()
+        .map(|table| format!("SELECT DISTINCT repo_ref FROM {table}"))
+        .collect::<Vec<_>>()
+        .join(" UNION ");
+
+    let mut orphaned = sqlx::query(&query)
+        .fetch_all(sql.as_ref())
+        .await?
+        .into_iter()
+        .map(|row| row.try_get::<String, _>("repo_ref"))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    orphaned.retain(|repo_ref| !known.contains(repo_ref));
+    orphaned.sort();
+    Ok(orphaned)
+}
+
+/// Delete the data of `orphaned` repositories through `writers` and from
+/// the database
+pub async fn purge_orphaned(
+    sql: &SqlDb,
+    semantic: Option<&SemanticClient>,
+    writers: &GlobalWriteHandleRef<'_>,
+    orphaned: &[String],
+) -> anyhow::Result<()> {
+    for repo_ref in orphaned {
+        for handle in writers {
+            handle.delete_repo_ref(repo_ref);
+        }
+
+        match repo_ref.parse::<RepoRef>() {
+            Ok(reporef) => purge(sql, semantic, &reporef).await?,
+            Err(err) => {
+                warn!(?err, repo_ref, "deleting rows of unparsable repository");
+                purge_raw(sql, repo_ref).await?;
+            }
+        }
+
+        info!(repo_ref, "deleted data of orphaned repository");
+    }
+
+    Ok(())
+}
+
+/// Size of the database file, free pages included
+pub async fn database_size(sql: &SqlDb) -> anyhow::Result<u64> {
+    let pages: i64 = sqlx::query_scalar("PRAGMA page_count")
+        .fetch_one(sql.as_ref())
+        .await?;
+    let page_size: i64 = sqlx::query_scalar("PRAGMA page_size")
+        .fetch_one(sql.as_ref())
+        .await?;
+
+    Ok((pages * page_size) as u64)
+}
+
+/// Rebuild the database without its free pages, and refresh the query
+/// planner's statistics
+pub async fn vacuum(sql: &SqlDb) -> anyhow::Result<()> {
+    sqlx::query("VACUUM").execute(sql.as_ref()).await?;
+    sqlx::query("PRAGMA optimize").execute(sql.as_ref()).await?;
+    Ok(())
+}
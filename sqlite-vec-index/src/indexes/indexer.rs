@@ -0,0 +1,263 @@
+use std::{ops::Deref, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use tantivy::{
+    collector::TopDocs,
+    directory::MmapDirectory,
+    query::Query,
+    schema::{Field, Schema, Value},
+    DocAddress, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument,
+};
+use tokio::sync::MutexGuard;
+use tracing::{debug, warn};
+
+use crate::{
+    application::background::{SyncHandle, SyncPipes},
+    repo::{
+        state::RepoError,
+        types::{RepoMetadata, RepoRef, Repository},
+    },
+};
+
+/// A source of documents for one of the tantivy indexes
+#[async_trait]
+pub trait Indexable: Send + Sync {
+    /// Index the repository, adding and deleting documents through `writer`.
+    ///
+    /// Nothing is visible to readers until the writer is committed.
+    async fn index_repository(
+        &self,
+        reporef: &RepoRef,
+        repo: &Repository,
+        repo_metadata: &RepoMetadata,
+        writer: &IndexWriter,
+        pipes: &SyncPipes,
+    ) -> Result<()>;
+
+    /// Delete every document of `repo`
+    fn delete_by_repo(&self, writer: &IndexWriter, repo: &Repository);
+
+    /// Delete every document of the repository `repo_ref`, for when that's
+    /// all we have left of it
+    fn delete_by_repo_ref(&self, writer: &IndexWriter, repo_ref: &str);
+
+    /// Delete the documents of the file version with `unique_hash`
+    fn delete_by_unique_hash(&self, writer: &IndexWriter, unique_hash: &str);
+
+    /// Return the tantivy `Schema` of the current index
+    fn schema(&self) -> Schema;
+}
+
+pub struct Indexer<T> {
+    pub source: T,
+    pub index: Index,
+    pub reader: IndexReader,
+    pub reindex_buffer_size: usize,
+    pub reindex_threads: usize,
+    /// Whether the index was created empty, rather than opened, so any
+    /// cache of what's in it is out of date
+    pub created: bool,
+}
+
+impl<T: Indexable> Indexer<T> {
+    /// Open the index at `path`, or create it.
+    ///
+    /// An index created with a different schema can't be read anymore, so
+    /// it's thrown away and built again on the next sync.
+    pub fn create(source: T, path: &Path, buffer_size: usize, threads: usize) -> Result<Self> {
+        let (index, created) = match Self::init_index(source.schema(), path, threads) {
+            Ok(opened) => opened,
+            Err(err) => {
+                warn!(?err, ?path, "failed to open index, recreating it");
+                std::fs::remove_dir_all(path)
+                    .with_context(|| format!("failed to remove index at {path:?}"))?;
+                Self::init_index(source.schema(), path, threads)?
+            }
+        };
+
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+
+        Ok(Self {
+            source,
+            index,
+            reader,
+            reindex_buffer_size: buffer_size,
+            reindex_threads: threads,
+            created,
+        })
+    }
+
+    /// The index at `path`, and whether it had to be created
+    fn init_index(schema: Schema, path: &Path, threads: usize) -> Result<(Index, bool)> {
+        std::fs::create_dir_all(path)?;
+
+        let directory = MmapDirectory::open(path)?;
+        let created = !Index::exists(&directory)?;
+        let mut index = Index::open_or_create(directory, schema)?;
+        index.set_multithread_executor(threads)?;
+
+        Ok((index, created))
+    }
+
+    pub(super) fn write_handle(&self) -> Result<IndexWriteHandle<'_>> {
+        let writer = self
+            .index
+            .writer_with_num_threads(
+                self.reindex_threads,
+                self.reindex_buffer_size * self.reindex_threads,
+            )
+            .context("failed to create index writer")?;
+
+        Ok(IndexWriteHandle {
+            source: &self.source,
+            reader: &self.reader,
+            writer,
+        })
+    }
+
+    /// The top `limit` documents for `query`, best first
+    pub fn search(&self, query: &dyn Query, limit: usize) -> Result<Vec<(f32, TantivyDocument)>> {
+        let searcher = self.reader.searcher();
+        searcher
+            .search(query, &TopDocs::with_limit(limit))?
+            .into_iter()
+            .map(|(score, address): (f32, DocAddress)| Ok((score, searcher.doc(address)?)))
+            .collect()
+    }
+}
+
+/// A writer for a single index, alive for the duration of a sync
+pub struct IndexWriteHandle<'a> {
+    source: &'a dyn Indexable,
+    reader: &'a IndexReader,
+    writer: IndexWriter,
+}
+
+impl<'a> IndexWriteHandle<'a> {
+    pub fn delete(&self, repo: &Repository) {
+        self.source.delete_by_repo(&self.writer, repo)
+    }
+
+    pub fn delete_repo_ref(&self, repo_ref: &str) {
+        self.source.delete_by_repo_ref(&self.writer, repo_ref)
+    }
+
+    pub fn delete_unique_hash(&self, unique_hash: &str) {
+        self.source.delete_by_unique_hash(&self.writer, unique_hash)
+    }
+
+    pub async fn index(
+        &self,
+        reporef: &RepoRef,
+        repo: &Repository,
+        metadata: &RepoMetadata,
+        pipes: &SyncPipes,
+    ) -> Result<()> {
+        self.source
+            .index_repository(reporef, repo, metadata, &self.writer, pipes)
+            .await
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        self.writer.commit()?;
+        self.reader.reload()?;
+
+        Ok(())
+    }
+
+    pub fn rollback(&mut self) -> Result<()> {
+        self.writer.rollback()?;
+        Ok(())
+    }
+}
+
+/// The writers of every index, see [`GlobalWriteHandle`]
+pub struct GlobalWriteHandleRef<'a> {
+    pub(super) handles: Vec<IndexWriteHandle<'a>>,
+}
+
+impl<'a> IntoIterator for &'a GlobalWriteHandleRef<'a> {
+    type Item = &'a IndexWriteHandle<'a>;
+    type IntoIter = std::slice::Iter<'a, IndexWriteHandle<'a>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.handles.iter()
+    }
+}
+
+/// Writers for all the indexes at once.
+///
+/// Only one of these exists at any time, as tantivy allows a single writer
+/// per index, so syncs take turns.
+pub struct GlobalWriteHandle<'a> {
+    pub(super) handles: GlobalWriteHandleRef<'a>,
+    pub(super) _write_lock: MutexGuard<'a, ()>,
+}
+
+impl<'a> Deref for GlobalWriteHandle<'a> {
+    type Target = GlobalWriteHandleRef<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handles
+    }
+}
+
+impl<'a> GlobalWriteHandle<'a> {
+    /// Index the repository of `sync_handle` into every index.
+    pub async fn index(
+        &self,
+        sync_handle: &SyncHandle,
+        repo: &Repository,
+    ) -> std::result::Result<Arc<RepoMetadata>, RepoError> {
+        let metadata = repo.get_repo_metadata().await;
+
+        for handle in self.handles.handles.iter() {
+            handle
+                .index(&sync_handle.reporef, repo, &metadata, &sync_handle.pipes)
+                .await
+                .map_err(|error| RepoError::Anyhow { error })?;
+        }
+
+        Ok(metadata)
+    }
+
+    pub async fn commit(self) -> Result<()> {
+        self.commit_and_hold().await.map(drop)
+    }
+
+    /// Like [`Self::commit`], but other syncs keep waiting for the writers
+    /// until the returned guard is dropped
+    pub async fn commit_and_hold(self) -> Result<MutexGuard<'a, ()>> {
+        for mut handle in self.handles.handles {
+            handle.commit()?;
+        }
+
+        debug!("committed indexes");
+        Ok(self._write_lock)
+    }
+
+    pub fn rollback(self) -> Result<()> {
+        for mut handle in self.handles.handles {
+            handle.rollback()?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn get_text_field(doc: &TantivyDocument, field: Field) -> String {
+    doc.get_first(field)
+        .and_then(|value| value.as_str())
+        .unwrap_or_default()
+        .to_owned()
+}
+
+pub fn get_u64_field(doc: &TantivyDocument, field: Field) -> u64 {
+    doc.get_first(field)
+        .and_then(|value| value.as_u64())
+        .unwrap_or_default()
+}
@@ -0,0 +1,475 @@
+// Cross-checks between the caches, the snippet index and the vector table.
+//
+// `file_cache`, `chunk_cache`, `code_snippet_cache`, the snippets in the
+// tantivy index and the vector tables are all supposed to agree with each
+// other and with what's on disk, this is where we find out whether they do,
+// and fix up what we can.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use sqlx::{Row, Sqlite};
+use tantivy::{
+    collector::{Count, TopDocs},
+    query::TermQuery,
+    schema::IndexRecordOption,
+    Searcher, TantivyDocument, Term,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    db::sqlite::SqlDb,
+    repo::{encoding::decode, state::RepositoryPool, types::RepoRef},
+    semantic_search::{schema::EMBEDDING_DIM, vectors},
+};
+
+use super::{
+    caching::{FileCache, SnippetCache, SnippetCacheKeys},
+    indexer::{get_text_field, Indexer},
+    schema::Snippet,
+    Indexes,
+};
+
+#[derive(serde::Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepoIntegrity {
+    #[serde(rename = "ref")]
+    pub reporef: String,
+
+    /// The repository is not in the repo pool anymore, all of its rows are
+    /// left over
+    pub untracked: bool,
+
+    /// Vectors without a `chunk_cache` row
+    pub orphan_vectors: usize,
+
+    /// `chunk_cache` rows which claim a chunk is embedded without a vector
+    pub chunks_without_vectors: usize,
+
+    /// Files no longer on disk which `file_cache` or `code_snippet_cache`
+    /// still have rows for
+    pub missing_files: usize,
+
+    /// Snippets in the lexical index without a `code_snippet_cache` row
+    pub orphan_snippets: usize,
+
+    /// `code_snippet_cache` rows for files which have no snippets in the
+    /// lexical index
+    pub files_without_snippets: usize,
+
+    /// Vectors whose length doesn't match the embedding dimension, or
+    /// quantised vectors which lost their full precision version
+    pub dimension_mismatches: usize,
+
+    // what we need to know to repair, but not to report
+    #[serde(skip)]
+    orphan_vector_ids: Vec<String>,
+    #[serde(skip)]
+    unembedded_files: HashSet<String>,
+    #[serde(skip)]
+    mismatched_files: HashSet<String>,
+    #[serde(skip)]
+    orphan_snippet_hashes: Vec<String>,
+    #[serde(skip)]
+    unindexed_snippet_files: Vec<String>,
+}
+
+impl RepoIntegrity {
+    pub fn is_healthy(&self) -> bool {
+        !self.untracked
+            && self.orphan_vectors == 0
+            && self.chunks_without_vectors == 0
+            && self.missing_files == 0
+            && self.dimension_mismatches == 0
+            && self.orphan_snippets == 0
+            && self.files_without_snippets == 0
+    }
+}
+
+#[derive(serde::Serialize, Debug, Default, Clone)]
+pub struct IntegrityReport {
+    pub repos: Vec<RepoIntegrity>,
+}
+
+impl IntegrityReport {
+    pub fn is_healthy(&self) -> bool {
+        self.repos.iter().all(RepoIntegrity::is_healthy)
+    }
+
+    /// Repositories which need a fresh sync after a repair
+    pub fn needs_sync(&self) -> impl Iterator<Item = &RepoIntegrity> {
+        self.repos
+            .iter()
+            .filter(|repo| !repo.untracked && !repo.is_healthy())
+    }
+
+    /// Repositories with data left over after they were removed
+    pub fn untracked(&self) -> impl Iterator<Item = &RepoIntegrity> {
+        self.repos.iter().filter(|repo| repo.untracked)
+    }
+}
+
+/// Check every repository with data in the database, the snippet index, or
+/// in the repo pool.
+pub async fn verify(
+    sql: &SqlDb,
+    snippets: &Indexer<Snippet>,
+    repo_pool: &RepositoryPool,
+) -> anyhow::Result<IntegrityReport> {
+    let mut orphan_snippets = orphan_snippets(sql, snippets).await?;
+
+    let mut repos = orphan_snippets.keys().cloned().collect::<HashSet<_>>();
+    for table in [
+        "file_cache",
+        "chunk_cache",
+        "code_snippet_cache",
+        "chunk_vectors_all",
+    ] {
+        let rows = sqlx::query(&format!("SELECT DISTINCT repo_ref FROM {table}"))
+            .fetch_all(sql.as_ref())
+            .await?;
+
+        for row in rows {
+            repos.insert(row.try_get::<String, _>("repo_ref")?);
+        }
+    }
+
+    repo_pool
+        .scan_async(|reporef, _| {
+            repos.insert(reporef.to_string());
+        })
+        .await;
+
+    let searcher = snippets.reader.searcher();
+    let mut report = IntegrityReport::default();
+    for repo_ref in repos {
+        let mut integrity =
+            verify_repo(sql, &searcher, &snippets.source, repo_pool, repo_ref).await?;
+        integrity.orphan_snippet_hashes = orphan_snippets
+            .remove(&integrity.reporef)
+            .unwrap_or_default();
+        integrity.orphan_snippets = integrity.orphan_snippet_hashes.len();
+        debug!(?integrity, "verified repository");
+        report.repos.push(integrity);
+    }
+
+    report.repos.sort_by(|a, b| a.reporef.cmp(&b.reporef));
+    Ok(report)
+}
+
+/// Snippets whose file version no `code_snippet_cache` row knows about, by
+/// repository.
+///
+/// This goes through the terms of the index rather than the documents, the
+/// documents are only looked at for the few hashes we don't expect.
+async fn orphan_snippets(
+    sql: &SqlDb,
+    snippets: &Indexer<Snippet>,
+) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    let expected =
+        sqlx::query("SELECT commit_hash, file_path, content_hash FROM code_snippet_cache")
+            .fetch_all(sql.as_ref())
+            .await?
+            .into_iter()
+            .map(|row| {
+                Ok(SnippetCacheKeys::new(
+                    row.try_get("commit_hash")?,
+                    row.try_get("file_path")?,
+                    row.try_get("content_hash")?,
+                )
+                .unique_hash())
+            })
+            .collect::<Result<HashSet<_>, sqlx::Error>>()?;
+
+    let schema = &snippets.source;
+    let searcher = snippets.reader.searcher();
+
+    let mut indexed = HashSet::new();
+    for segment in searcher.segment_readers() {
+        let inverted = segment.inverted_index(schema.unique_hash)?;
+        let mut terms = inverted.terms().stream()?;
+        while terms.advance() {
+            let hash = String::from_utf8_lossy(terms.key());
+            if !expected.contains(hash.as_ref()) {
+                indexed.insert(hash.into_owned());
+            }
+        }
+    }
+
+    let mut orphans: HashMap<String, Vec<String>> = HashMap::new();
+    for hash in indexed {
+        // the terms of deleted documents stick around until segments merge
+        let query = unique_hash_query(schema, &hash);
+        let Some((_, address)) = searcher.search(&query, &TopDocs::with_limit(1))?.pop() else {
+            continue;
+        };
+
+        let doc: TantivyDocument = searcher.doc(address)?;
+        orphans
+            .entry(get_text_field(&doc, schema.repo_ref))
+            .or_default()
+            .push(hash);
+    }
+
+    Ok(orphans)
+}
+
+fn unique_hash_query(schema: &Snippet, unique_hash: &str) -> TermQuery {
+    TermQuery::new(
+        Term::from_field_text(schema.unique_hash, unique_hash),
+        IndexRecordOption::Basic,
+    )
+}
+
+async fn verify_repo(
+    sql: &SqlDb,
+    searcher: &Searcher,
+    schema: &Snippet,
+    repo_pool: &RepositoryPool,
+    repo_ref: String,
+) -> anyhow::Result<RepoIntegrity> {
+    let reporef = repo_ref.parse::<RepoRef>().ok();
+    let disk_path = match reporef {
+        Some(ref reporef) => {
+            repo_pool
+                .read_async(reporef, |_, repo| repo.disk_path.clone())
+                .await
+        }
+        None => None,
+    };
+
+    let mut integrity = RepoIntegrity {
+        untracked: disk_path.is_none(),
+        ..Default::default()
+    };
+
+    integrity.orphan_vector_ids = sqlx::query(
+        "SELECT chunk_id FROM chunk_vectors_all WHERE repo_ref = ? \
+         AND chunk_id NOT IN (SELECT chunk_hash FROM chunk_cache WHERE repo_ref = ?)",
+    )
+    .bind(&repo_ref)
+    .bind(&repo_ref)
+    .fetch_all(sql.as_ref())
+    .await?
+    .into_iter()
+    .map(|row| row.try_get("chunk_id"))
+    .collect::<Result<_, _>>()?;
+    integrity.orphan_vectors = integrity.orphan_vector_ids.len();
+
+    let unembedded = sqlx::query(
+        "SELECT file_cache_key FROM chunk_cache WHERE repo_ref = ? \
+         AND chunk_hash NOT IN (SELECT chunk_id FROM chunk_vectors_all WHERE repo_ref = ?)",
+    )
+    .bind(&repo_ref)
+    .bind(&repo_ref)
+    .fetch_all(sql.as_ref())
+    .await?;
+    integrity.chunks_without_vectors = unembedded.len();
+    for row in unembedded {
+        integrity
+            .unembedded_files
+            .insert(row.try_get("file_cache_key")?);
+    }
+
+    let mismatched = sqlx::query(
+        "SELECT content_hash FROM chunk_vectors_all WHERE repo_ref = ?1 \
+         AND (dimensions != ?2 OR full_embedding IS NULL OR length(full_embedding) != ?2 * 4)",
+    )
+    .bind(&repo_ref)
+    .bind(EMBEDDING_DIM as i64)
+    .fetch_all(sql.as_ref())
+    .await?;
+    integrity.dimension_mismatches = mismatched.len();
+    for row in mismatched {
+        integrity
+            .mismatched_files
+            .insert(row.try_get("content_hash")?);
+    }
+
+    let snippet_files = sqlx::query(
+        "SELECT commit_hash, file_path, content_hash FROM code_snippet_cache WHERE repo_ref = ?",
+    )
+    .bind(&repo_ref)
+    .fetch_all(sql.as_ref())
+    .await?;
+    for row in snippet_files {
+        let keys = SnippetCacheKeys::new(
+            row.try_get("commit_hash")?,
+            row.try_get("file_path")?,
+            row.try_get("content_hash")?,
+        );
+
+        if searcher.search(&unique_hash_query(schema, &keys.unique_hash()), &Count)? > 0 {
+            continue;
+        }
+
+        // a file with nothing but whitespace in it has no snippets, and
+        // files which are gone are counted as missing below
+        let has_text = tokio::fs::read(keys.file_path())
+            .await
+            .ok()
+            .and_then(decode)
+            .is_some_and(|decoded| !decoded.text.trim().is_empty());
+        if has_text {
+            integrity
+                .unindexed_snippet_files
+                .push(keys.file_path().to_owned());
+        }
+    }
+    integrity.files_without_snippets = integrity.unindexed_snippet_files.len();
+
+    if let Some(disk_path) = disk_path {
+        // both caches usually have a row for the same file
+        let mut paths = HashSet::new();
+        for table in ["file_cache", "code_snippet_cache"] {
+            let rows = sqlx::query(&format!("SELECT file_path FROM {table} WHERE repo_ref = ?"))
+                .bind(&repo_ref)
+                .fetch_all(sql.as_ref())
+                .await?;
+
+            for row in rows {
+                paths.insert(row.try_get::<String, _>("file_path")?);
+            }
+        }
+
+        for path in paths {
+            let path = disk_path.join(Path::new(&path));
+            if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+                integrity.missing_files += 1;
+            }
+        }
+    }
+
+    integrity.reporef = repo_ref;
+    Ok(integrity)
+}
+
+/// Fix up what can be fixed in place.
+///
+/// Orphan vectors and snippets are dropped. Chunks without a vector, or
+/// with a vector of the wrong size, are dropped together with the
+/// `file_cache` row of their file, so the next sync embeds them again.
+/// Files missing from the snippet index lose their `code_snippet_cache`
+/// row the same way. Rows for files that vanished are left to the next
+/// sync, which removes them from every index at once. Whatever is left of
+/// untracked repositories is deleted.
+///
+/// Returns the repositories which need to be synced again.
+pub async fn repair(
+    sql: &SqlDb,
+    indexes: &Indexes,
+    report: &IntegrityReport,
+) -> anyhow::Result<Vec<RepoRef>> {
+    let mut resync = vec![];
+    let writers = indexes.writers().await?;
+
+    for integrity in report.untracked() {
+        for handle in &*writers {
+            handle.delete_repo_ref(&integrity.reporef);
+        }
+
+        let Ok(reporef) = integrity.reporef.parse::<RepoRef>() else {
+            warn!(
+                reporef = integrity.reporef,
+                "can't clean up data of unknown repository"
+            );
+            continue;
+        };
+
+        FileCache::for_repo(sql, &reporef, None).delete().await?;
+        SnippetCache::for_repo(sql, &reporef).delete().await?;
+        info!(reporef = integrity.reporef, "deleted untracked repository");
+    }
+
+    for integrity in report.needs_sync() {
+        for hash in &integrity.orphan_snippet_hashes {
+            for handle in &*writers {
+                handle.delete_unique_hash(hash);
+            }
+        }
+
+        let mut tx = sql.begin().await?;
+
+        vectors::delete(&mut tx, &integrity.orphan_vector_ids).await?;
+
+        let broken_files = integrity
+            .unembedded_files
+            .union(&integrity.mismatched_files)
+            .cloned()
+            .collect::<Vec<_>>();
+        forget_files(&mut tx, &integrity.reporef, &broken_files).await?;
+        forget_snippet_files(
+            &mut tx,
+            &integrity.reporef,
+            &integrity.unindexed_snippet_files,
+        )
+        .await?;
+
+        tx.commit().await?;
+
+        info!(
+            reporef = integrity.reporef,
+            orphan_vectors = integrity.orphan_vectors,
+            orphan_snippets = integrity.orphan_snippets,
+            broken_files = broken_files.len(),
+            unindexed_files = integrity.files_without_snippets,
+            "repaired repository"
+        );
+
+        if let Ok(reporef) = integrity.reporef.parse() {
+            resync.push(reporef);
+        }
+    }
+
+    writers.commit().await?;
+    Ok(resync)
+}
+
+/// Drop the `code_snippet_cache` rows of `file_paths`, so the next sync
+/// indexes their snippets again.
+async fn forget_snippet_files(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+    file_paths: &[String],
+) -> anyhow::Result<()> {
+    for file_path in file_paths {
+        sqlx::query! {
+            "DELETE FROM code_snippet_cache WHERE repo_ref = ? AND file_path = ?",
+            repo_ref,
+            file_path,
+        }
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Drop everything we know about the files with the given cache keys, so
+/// they are indexed from scratch next time.
+async fn forget_files(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+    file_cache_keys: &[String],
+) -> anyhow::Result<()> {
+    for file_cache_key in file_cache_keys {
+        sqlx::query! {
+            "DELETE FROM chunk_cache WHERE repo_ref = ? AND file_cache_key = ?",
+            repo_ref,
+            file_cache_key,
+        }
+        .execute(&mut **tx)
+        .await?;
+
+        sqlx::query! {
+            "DELETE FROM file_cache WHERE repo_ref = ? AND semantic_search_hash = ?",
+            repo_ref,
+            file_cache_key,
+        }
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    vectors::delete_for_content_hashes(tx, repo_ref, file_cache_keys).await
+}
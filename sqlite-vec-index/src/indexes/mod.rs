@@ -1,2 +1,67 @@
 pub mod caching;
+pub mod gc;
+pub mod indexer;
+pub mod integrity;
+pub mod schema;
+pub mod snapshot;
 pub mod snippet;
+pub mod symbols;
+pub mod trigram;
+
+use anyhow::Result;
+use tracing::info;
+
+use crate::{
+    application::config::configuration::Configuration, db::sqlite::SqlDb,
+    semantic_search::client::SemanticClient,
+};
+
+pub use self::indexer::{GlobalWriteHandle, GlobalWriteHandleRef};
+use self::{caching::SnippetCache, indexer::Indexer, schema::Snippet};
+
+/// The tantivy indexes, which are written next to the vector index on every
+/// sync
+pub struct Indexes {
+    pub snippets: Indexer<Snippet>,
+    write_mutex: tokio::sync::Mutex<()>,
+}
+
+impl Indexes {
+    pub async fn new(
+        config: &Configuration,
+        sql: SqlDb,
+        semantic: Option<SemanticClient>,
+    ) -> Result<Self> {
+        let snippets = Indexer::create(
+            Snippet::new(sql.clone(), semantic).with_ownership(config.index_ownership),
+            config.index_path("snippets").as_ref(),
+            config.buffer_size,
+            config.max_threads,
+        )?;
+
+        // the cache would keep the files it knows about from being indexed
+        // into the new index
+        if snippets.created {
+            info!("snippet index created, indexing every file again");
+            SnippetCache::delete_all(&sql).await?;
+        }
+
+        Ok(Self {
+            snippets,
+            write_mutex: Default::default(),
+        })
+    }
+
+    /// Writers for every index, waiting for any other sync to finish with
+    /// theirs first
+    pub async fn writers(&self) -> Result<GlobalWriteHandle<'_>> {
+        let _write_lock = self.write_mutex.lock().await;
+
+        Ok(GlobalWriteHandle {
+            handles: GlobalWriteHandleRef {
+                handles: vec![self.snippets.write_handle()?],
+            },
+            _write_lock,
+        })
+    }
+}
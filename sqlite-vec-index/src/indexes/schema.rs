@@ -0,0 +1,89 @@
+//! Every change in this file will trigger a reset of the snippet index.
+//! Use with care.
+
+use tantivy::schema::{
+    IndexRecordOption, Schema, SchemaBuilder, TextFieldIndexing, TextOptions, FAST, STORED,
+    STRING,
+};
+
+use crate::{db::sqlite::SqlDb, semantic_search::client::SemanticClient};
+
+/// The lexical index over snippets of code, a fixed number of lines each.
+#[derive(Clone)]
+pub struct Snippet {
+    pub(super) schema: Schema,
+    pub(super) sql: SqlDb,
+
+    /// The files are chunked and embedded while the snippets are built, if
+    /// semantic search is set up
+    pub(super) semantic: Option<SemanticClient>,
+
+    /// Record blame and CODEOWNERS owners on the chunks, see
+    /// [`FileCache::with_ownership`](super::caching::FileCache::with_ownership)
+    pub(super) ownership: bool,
+
+    /// Identifies the version of the file a snippet came from, so the
+    /// snippets of a stale file can be deleted in one go
+    pub unique_hash: tantivy::schema::Field,
+
+    pub repo_disk_path: tantivy::schema::Field,
+    pub repo_ref: tantivy::schema::Field,
+    pub repo_name: tantivy::schema::Field,
+    pub relative_path: tantivy::schema::Field,
+    pub commit_hash: tantivy::schema::Field,
+    pub last_commit_unix_seconds: tantivy::schema::Field,
+
+    pub content: tantivy::schema::Field,
+    pub start_line: tantivy::schema::Field,
+    pub end_line: tantivy::schema::Field,
+}
+
+impl Snippet {
+    pub fn new(sql: SqlDb, semantic: Option<SemanticClient>) -> Self {
+        let mut builder = SchemaBuilder::new();
+
+        let code = TextOptions::default()
+            .set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer("default")
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            )
+            .set_stored();
+
+        let unique_hash = builder.add_text_field("unique_hash", STRING | STORED);
+        let repo_disk_path = builder.add_text_field("repo_disk_path", STRING);
+        let repo_ref = builder.add_text_field("repo_ref", STRING | STORED);
+        let repo_name = builder.add_text_field("repo_name", STRING | STORED);
+        let relative_path = builder.add_text_field("relative_path", code.clone());
+        let commit_hash = builder.add_text_field("commit_hash", STRING | STORED);
+        let last_commit_unix_seconds =
+            builder.add_u64_field("last_commit_unix_seconds", FAST | STORED);
+
+        let content = builder.add_text_field("content", code);
+        let start_line = builder.add_u64_field("start_line", STORED);
+        let end_line = builder.add_u64_field("end_line", STORED);
+
+        Self {
+            schema: builder.build(),
+            sql,
+            semantic,
+            ownership: false,
+            unique_hash,
+            repo_disk_path,
+            repo_ref,
+            repo_name,
+            relative_path,
+            commit_hash,
+            last_commit_unix_seconds,
+            content,
+            start_line,
+            end_line,
+        }
+    }
+
+    /// Look up who changed and who owns each chunk while embedding it
+    pub fn with_ownership(mut self, ownership: bool) -> Self {
+        self.ownership = ownership;
+        self
+    }
+}
@@ -0,0 +1,395 @@
+// Portable snapshots of a single repository's semantic index.
+//
+// Indexing a big repository on CPU takes hours, so we want to be able to
+// do it once and hand the result around. A snapshot is a standalone SQLite
+// file holding the chunks, payloads and vectors of one `RepoRef`, together
+// with the model that produced them and the commit they were indexed at.
+//
+// Cache keys and chunk ids pin the repository they were created for, so on
+// import we re-derive them from the files on the importing machine. Files
+// which differ locally are left out, and the sync that follows the import
+// takes care of them.
+//
+// The lexical snippet index, and the symbols that go with it, are cheap to
+// rebuild, so they are not part of the snapshot. They have a cache of their
+// own, which the import leaves alone.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+use futures::TryStreamExt;
+use sqlx::{
+    sqlite::SqliteConnectOptions, Connection, Row, Sqlite, SqliteConnection, Transaction,
+};
+use tracing::{debug, info};
+
+use crate::{
+    db::sqlite::SqlDb,
+    repo::{
+        encoding::decode,
+        types::{RepoRef, Repository},
+    },
+    semantic_search::{
+        client::{chunk_data, SemanticClient},
+        schema::{ModelDescriptor, Payload},
+        vectors::{self, VectorStorage},
+    },
+};
+
+use super::caching::{chunk_id, file_content_hash, semantic_cache_key};
+
+/// Bump this whenever the layout of the snapshot tables changes
+pub const SNAPSHOT_VERSION: i64 = 1;
+
+#[derive(serde::Serialize, Debug, Default, Clone)]
+pub struct SnapshotSummary {
+    pub commit_hash: String,
+    pub files: usize,
+    pub chunks: usize,
+    /// Files in the snapshot which are different, or missing, locally
+    pub skipped_files: usize,
+}
+
+/// Write the index of `reporef` into a new snapshot file at `archive`.
+///
+/// The snapshot is written next to `archive` first and only moved into
+/// place once complete, so a failed export doesn't leave a truncated
+/// snapshot behind.
+pub async fn export(
+    sql: &SqlDb,
+    semantic: &SemanticClient,
+    reporef: &RepoRef,
+    repo: &Repository,
+    archive: &Path,
+) -> anyhow::Result<SnapshotSummary> {
+    if tokio::fs::try_exists(archive).await? {
+        bail!("snapshot {archive:?} already exists");
+    }
+
+    let mut partial = archive.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    // left over from an export that was interrupted
+    if tokio::fs::try_exists(&partial).await? {
+        tokio::fs::remove_file(&partial).await?;
+    }
+
+    let summary = match write_snapshot(sql, semantic, reporef, repo, &partial).await {
+        Ok(summary) => summary,
+        Err(err) => {
+            _ = tokio::fs::remove_file(&partial).await;
+            return Err(err);
+        }
+    };
+
+    tokio::fs::rename(&partial, archive)
+        .await
+        .with_context(|| format!("failed to move snapshot to {archive:?}"))?;
+
+    info!(?reporef, ?archive, ?summary, "exported snapshot");
+    Ok(summary)
+}
+
+async fn write_snapshot(
+    sql: &SqlDb,
+    semantic: &SemanticClient,
+    reporef: &RepoRef,
+    repo: &Repository,
+    path: &Path,
+) -> anyhow::Result<SnapshotSummary> {
+    let repo_str = reporef.to_string();
+    let metadata = repo.get_repo_metadata().await;
+    let mut out = SqliteConnection::connect_with(
+        &SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true),
+    )
+    .await?;
+
+    let mut tx = out.begin().await?;
+    sqlx::query(&format!("PRAGMA user_version = {SNAPSHOT_VERSION}"))
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL)")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "CREATE TABLE files (relative_path TEXT NOT NULL, file_content_hash TEXT NOT NULL, \
+         semantic_search_hash TEXT NOT NULL, commit_hash TEXT NOT NULL)",
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "CREATE TABLE chunks (chunk_hash TEXT NOT NULL, content_hash TEXT NOT NULL, \
+         payload TEXT NOT NULL, embedding BLOB NOT NULL)",
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let model = serde_json::to_string(&semantic.model_descriptor())?;
+    let repo_name = reporef.indexed_name();
+    for (key, value) in [
+        ("model", model.as_str()),
+        ("commit_hash", metadata.commit_hash.as_str()),
+        ("repo_name", repo_name.as_str()),
+    ] {
+        sqlx::query("INSERT INTO meta (key, value) VALUES (?, ?)")
+            .bind(key)
+            .bind(value)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    // rows are streamed straight into the snapshot, a big repository has
+    // far too many vectors to hold them all in memory. Both tables are read
+    // in one transaction, so a sync running alongside can't leave us with
+    // files and chunks from different versions of the index.
+    let mut source = sql.begin().await?;
+    let mut files = sqlx::query! {
+        "SELECT file_path, file_content_hash, semantic_search_hash, commit_hash FROM file_cache \
+         WHERE repo_ref = ?",
+        repo_str,
+    }
+    .fetch(&mut *source);
+
+    let mut file_count = 0;
+    while let Some(file) = files.try_next().await? {
+        // paths are stored relative to the repository, so they can be
+        // rebased onto wherever the snapshot is imported
+        let file_path = PathBuf::from(&file.file_path);
+        let relative_path = file_path
+            .strip_prefix(&repo.disk_path)
+            .unwrap_or(&file_path)
+            .to_string_lossy();
+
+        sqlx::query(
+            "INSERT INTO files (relative_path, file_content_hash, semantic_search_hash, commit_hash) \
+             VALUES (?, ?, ?, ?)",
+        )
+        .bind(relative_path.as_ref())
+        .bind(&file.file_content_hash)
+        .bind(&file.semantic_search_hash)
+        .bind(&file.commit_hash)
+        .execute(&mut *tx)
+        .await?;
+        file_count += 1;
+    }
+    drop(files);
+
+    // always export at full precision, the importing end decides how to
+    // store them
+    let mut chunks = sqlx::query(
+        "SELECT chunk_id, content_hash, payload, full_embedding FROM chunk_vectors_all \
+         WHERE repo_ref = ?",
+    )
+    .bind(&repo_str)
+    .fetch(&mut *source);
+
+    let mut chunk_count = 0;
+    while let Some(chunk) = chunks.try_next().await? {
+        sqlx::query(
+            "INSERT INTO chunks (chunk_hash, content_hash, payload, embedding) VALUES (?, ?, ?, ?)",
+        )
+        .bind(chunk.try_get::<&str, _>("chunk_id")?)
+        .bind(chunk.try_get::<&str, _>("content_hash")?)
+        .bind(chunk.try_get::<&str, _>("payload")?)
+        .bind(chunk.try_get::<&[u8], _>("full_embedding")?)
+        .execute(&mut *tx)
+        .await?;
+        chunk_count += 1;
+    }
+    drop(chunks);
+    source.rollback().await?;
+
+    tx.commit().await?;
+    out.close().await?;
+
+    Ok(SnapshotSummary {
+        commit_hash: metadata.commit_hash.clone(),
+        files: file_count,
+        chunks: chunk_count,
+        skipped_files: 0,
+    })
+}
+
+struct ImportedFile {
+    semantic_key: String,
+    relative_path: String,
+    file_content_hash: String,
+    commit_hash: String,
+}
+
+/// Load a snapshot as the index of `reporef`, whose checkout lives at
+/// `disk_path`.
+///
+/// Anything we had indexed for `reporef` before is replaced.
+pub async fn import(
+    sql: &SqlDb,
+    semantic: &SemanticClient,
+    reporef: &RepoRef,
+    disk_path: &Path,
+    storage: VectorStorage,
+    archive: &Path,
+) -> anyhow::Result<SnapshotSummary> {
+    let mut snapshot = SqliteConnection::connect_with(
+        &SqliteConnectOptions::new()
+            .filename(archive)
+            .read_only(true),
+    )
+    .await
+    .with_context(|| format!("failed to open snapshot {archive:?}"))?;
+
+    let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(&mut snapshot)
+        .await?;
+    if version != SNAPSHOT_VERSION {
+        bail!("unsupported snapshot version {version}, expected {SNAPSHOT_VERSION}");
+    }
+
+    let meta = sqlx::query("SELECT key, value FROM meta")
+        .fetch_all(&mut snapshot)
+        .await?
+        .into_iter()
+        .map(|row| Ok((row.try_get("key")?, row.try_get("value")?)))
+        .collect::<anyhow::Result<HashMap<String, String>>>()?;
+
+    let model: ModelDescriptor = serde_json::from_str(meta.get("model").context("no model")?)?;
+    if model != semantic.model_descriptor() {
+        bail!(
+            "snapshot was created with {model:?}, but we are using {:?}",
+            semantic.model_descriptor()
+        );
+    }
+
+    let repo_str = reporef.to_string();
+    let repo_name = reporef.indexed_name();
+    let mut summary = SnapshotSummary {
+        commit_hash: meta.get("commit_hash").cloned().unwrap_or_default(),
+        ..Default::default()
+    };
+
+    // Map the snapshot's file keys onto the keys the files have here, only
+    // keeping the files which are the same on both ends.
+    let mut files = HashMap::new();
+    let mut rows = sqlx::query("SELECT * FROM files").fetch(&mut snapshot);
+    while let Some(row) = rows.try_next().await? {
+        let relative_path: String = row.try_get("relative_path")?;
+        let expected_hash: String = row.try_get("file_content_hash")?;
+
+        let local = tokio::fs::read(disk_path.join(&relative_path))
+            .await
+            .ok()
+            .and_then(decode);
+        let Some(local) = local else {
+            summary.skipped_files += 1;
+            continue;
+        };
+
+        if file_content_hash(&local.text) != expected_hash {
+            debug!(relative_path, "file differs locally, skipping");
+            summary.skipped_files += 1;
+            continue;
+        }
+
+        files.insert(
+            row.try_get::<String, _>("semantic_search_hash")?,
+            ImportedFile {
+                semantic_key: semantic_cache_key(semantic, &repo_str, &relative_path, &local.text),
+                relative_path,
+                file_content_hash: expected_hash,
+                commit_hash: row.try_get("commit_hash")?,
+            },
+        );
+    }
+    drop(rows);
+
+    let mut tx = sql.begin().await?;
+    forget_repo(&mut tx, &repo_str).await?;
+
+    for file in files.values() {
+        let file_path = disk_path.join(&file.relative_path);
+        let file_path = file_path.to_string_lossy();
+        sqlx::query! {
+            "INSERT INTO file_cache \
+             (repo_ref, semantic_search_hash, commit_hash, file_path, file_content_hash) \
+             VALUES (?, ?, ?, ?, ?)",
+            repo_str,
+            file.semantic_key,
+            file.commit_hash,
+            file_path,
+            file.file_content_hash,
+        }
+        .execute(&mut *tx)
+        .await?;
+    }
+    summary.files = files.len();
+
+    // the vectors are streamed, like on export
+    let mut rows = sqlx::query("SELECT * FROM chunks").fetch(&mut snapshot);
+    while let Some(row) = rows.try_next().await? {
+        let Some(file) = files.get(row.try_get::<&str, _>("content_hash")?) else {
+            continue;
+        };
+
+        let mut payload: Payload = serde_json::from_str(row.try_get("payload")?)?;
+        payload.repo_ref = repo_str.clone();
+        payload.repo_name = repo_name.clone();
+        payload.relative_path = file.relative_path.clone();
+        payload.content_hash = file.semantic_key.clone();
+
+        let data = chunk_data(
+            semantic.prefixes(),
+            &repo_name,
+            &payload.relative_path,
+            &payload.embedding_text(),
+        );
+        let id = chunk_id(&file.semantic_key, &data, payload.start_line, payload.end_line);
+        let embedding = vectors::decode(row.try_get("embedding")?);
+
+        vectors::insert(&mut tx, storage, &id, &embedding, &payload).await?;
+        sqlx::query! {
+            "insert into chunk_cache (chunk_hash, commit_hash, file_cache_key, repo_ref, file_path) \
+            VALUES (?, ?, ?, ?, ?)",
+            id, payload.commit_hash, payload.content_hash, payload.repo_ref, payload.relative_path,
+        }
+        .execute(&mut *tx)
+        .await?;
+
+        summary.chunks += 1;
+    }
+    drop(rows);
+
+    tx.commit().await?;
+
+    info!(?reporef, ?archive, ?summary, "imported snapshot");
+    Ok(summary)
+}
+
+async fn forget_repo(tx: &mut Transaction<'_, Sqlite>, repo_ref: &str) -> anyhow::Result<()> {
+    sqlx::query! {
+        "DELETE FROM file_cache WHERE repo_ref = ?",
+        repo_ref,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query! {
+        "DELETE FROM chunk_cache WHERE repo_ref = ?",
+        repo_ref,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query! {
+        "DELETE FROM index_checkpoints WHERE repo_ref = ?",
+        repo_ref,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    vectors::delete_for_repo(tx, repo_ref).await
+}
@@ -5,24 +5,44 @@ use std::{
 
 use anyhow::{bail, Result};
 use async_trait::async_trait;
+use tantivy::{
+    doc,
+    query::{BooleanQuery, Occur, PhraseQuery, Query, TermQuery},
+    schema::{IndexRecordOption, Schema},
+    IndexWriter, TantivyDocument, Term,
+};
 use tracing::{debug, info, trace, warn};
 
 use crate::{
-    //application::background::SyncPipes,
+    application::background::{IndexPhase, SyncPipes},
+    embedder::embedder::EmbedQueueLimits,
+    metrics::{SkipReason, METRICS},
+    query::parser::{Field, ParsedQuery},
     repo::{
         filesystem::FileWalker,
-        iterator::{FileSource, RepoDirectoryEntry},
+        iterator::{FileSource, RepoDirectoryEntry, RepositoryFile},
         types::{RepoMetadata, RepoRef, Repository},
     },
-    state::schema_version::get_schema_version,
+    semantic_search::{
+        client::SemanticClient,
+        context::{self, ContextOptions, ResultContext},
+        documents,
+        query::split_identifiers,
+    },
 };
 
 use super::{
-    caching::{SnippetCache, SnippetCacheKeys, SnippetCacheSnapshot},
-    indexer::{get_text_field, get_u64_field, Indexable},
+    caching::{
+        file_content_hash, semantic_cache_key, CacheKeys, FileCache, FileCacheSnapshot,
+        SnippetCache, SnippetCacheKeys, SnippetCacheSnapshot,
+    },
+    indexer::{get_text_field, get_u64_field, Indexable, Indexer},
     schema::Snippet,
 };
 
+/// Lines in every snippet of the lexical index
+const SNIPPET_LINES: usize = 20;
+
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SnippetDocument {
     pub relative_path: String,
@@ -32,31 +52,69 @@ pub struct SnippetDocument {
     pub line_start: u64,
     pub line_end: u64,
     pub score: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<ResultContext>,
+}
+
+impl SnippetDocument {
+    /// Whether the snippet passes the filters of `query`.
+    ///
+    /// The extension of the file stands in for its language, the same as it
+    /// does for the chunks of the semantic index.
+    pub fn matches(&self, query: &ParsedQuery) -> bool {
+        let lang = Path::new(&self.relative_path)
+            .extension()
+            .map(|ext| ext.to_string_lossy())
+            .unwrap_or_default();
+
+        query.allows(Field::Lang, &lang)
+            && query.allows(Field::Path, &self.relative_path)
+            && (query.allows(Field::Repo, &self.repo_name)
+                || query.allows(Field::Repo, &self.repo_ref))
+            && query.allows_text(&self.content)
+    }
+
+    /// Read the code around the snippet from the working copy, highlighting
+    /// where `terms` occur.
+    pub fn expand(&mut self, disk_path: &Path, terms: &[String], options: &ContextOptions) {
+        self.context = context::expand(
+            disk_path,
+            &self.relative_path,
+            &self.content,
+            self.line_start,
+            self.line_end,
+            terms,
+            options,
+        );
+    }
 }
 
 pub struct SnippetReader;
 
 impl SnippetReader {
-    pub fn read_document(schema: &Snippet) -> SnippetDocument {
+    pub fn read_document(schema: &Snippet, doc: TantivyDocument) -> SnippetDocument {
         let path = get_text_field(&doc, schema.relative_path);
         let repo_ref = get_text_field(&doc, schema.repo_ref);
+        let repo_name = get_text_field(&doc, schema.repo_name);
         let content = get_text_field(&doc, schema.content);
         let line_start = get_u64_field(&doc, schema.start_line);
         let line_end = get_u64_field(&doc, schema.end_line);
 
         SnippetDocument {
             relative_path: path,
-            repo_name: "".to_owned(),
+            repo_name,
             repo_ref,
             content,
             line_start,
             line_end,
             score: 0.0,
+            context: None,
         }
     }
 
     pub fn read_document_with_score(
         schema: &Snippet,
+        doc: TantivyDocument,
         score: f32,
     ) -> SnippetDocument {
         let mut code_snippet_doc = Self::read_document(schema, doc);
@@ -67,6 +125,7 @@ impl SnippetReader {
 
 pub struct Workload<'a> {
     cache: &'a SnippetCacheSnapshot<'a>,
+    semantic_cache: Option<&'a FileCacheSnapshot<'a>>,
     repo_disk_path: &'a Path,
     repo_name: &'a str,
     repo_metadata: &'a RepoMetadata,
@@ -89,6 +148,7 @@ impl<'a> Workload<'a> {
     ) -> Self {
         Self {
             cache,
+            semantic_cache: None,
             repo_disk_path,
             repo_name,
             repo_metadata,
@@ -101,19 +161,31 @@ impl<'a> Workload<'a> {
 }
 
 impl<'a> Workload<'a> {
+    /// Chunk and embed the file as well, unless `cache` says it's done
+    /// already
+    pub fn with_semantic_cache(mut self, cache: Option<&'a FileCacheSnapshot<'a>>) -> Self {
+        self.semantic_cache = cache;
+        self
+    }
+
+    fn semantic_cache_keys(&self, semantic: &SemanticClient, file: &RepositoryFile) -> CacheKeys {
+        CacheKeys::new(
+            semantic_cache_key(
+                semantic,
+                &self.repo_ref,
+                &self.relative_path.to_string_lossy(),
+                &file.buffer,
+            ),
+            self.commit_hash.to_owned(),
+            self.normalized_path.to_string_lossy().into_owned(),
+            file_content_hash(&file.buffer),
+        )
+    }
+
     // These cache keys are important as they also encode information about the
     // the file path in the cache, which implies that for each file we will have
     // a unique cache key.
     fn cache_keys(&self, dir_entry: &RepoDirectoryEntry) -> SnippetCacheKeys {
-        let semantic_hash = {
-            let mut hash = blake3::Hasher::new();
-            hash.update(get_schema_version().as_bytes());
-            hash.update(self.relative_path.to_string_lossy().as_ref().as_ref());
-            hash.update(self.repo_ref.as_bytes());
-            hash.update(dir_entry.buffer().unwrap_or_default().as_bytes());
-            hash.finalize().to_hex().to_string()
-        };
-
         let file_content_hash = match dir_entry.buffer() {
             Some(content) => {
                 let mut hash = blake3::Hasher::new();
@@ -127,12 +199,7 @@ impl<'a> Workload<'a> {
 
         let file_path = dir_entry.path();
 
-        debug!(
-            ?semantic_hash,
-            ?file_content_hash,
-            ?file_path,
-            "cache keys"
-        );
+        debug!(?file_content_hash, ?file_path, "cache keys");
 
         SnippetCacheKeys::new(
             self.commit_hash.to_owned(),
@@ -156,13 +223,31 @@ impl Indexable for Snippet {
     ) -> Result<()> {
         let code_snippet_cache = Arc::new(SnippetCache::for_repo(&self.sql, reporef));
         let cache = code_snippet_cache.retrieve().await;
+        let file_cache = self.semantic.as_ref().map(|semantic| {
+            let file_cache = FileCache::for_repo(&self.sql, reporef, Some(semantic))
+                .with_vector_storage(repo.vector_storage)
+                .with_progress(pipes)
+                .with_embed_queue_limits(EmbedQueueLimits::default());
+
+            if self.ownership {
+                file_cache.with_ownership(&repo.disk_path)
+            } else {
+                file_cache
+            }
+        });
+        let semantic_cache = match file_cache {
+            Some(ref file_cache) => Some(file_cache.retrieve().await),
+            None => None,
+        };
         let repo_name = reporef.indexed_name();
         let processed = &AtomicU64::new(0);
 
         let file_worker = |count: usize| {
             let cache = &cache;
+            let semantic_cache = semantic_cache.as_ref();
             move |dir_entry: RepoDirectoryEntry| {
                 let completed = processed.fetch_add(1, Ordering::Relaxed);
+                pipes.advance(IndexPhase::Hashing);
 
                 let entry_disk_path = dir_entry.path().unwrap().to_owned();
                 debug!(
@@ -188,29 +273,51 @@ impl Indexable for Snippet {
                     relative_path,
                     normalized_path,
                     repo_metadata.commit_hash.clone(),
-                );
+                )
+                .with_semantic_cache(semantic_cache);
 
                 trace!(entry_disk_path, "queueing entry for code snippet indexing");
                 if let Err(err) = self.worker(dir_entry, workload, writer) {
                     warn!(%err, entry_disk_path, "indexing failed code snippet; finished");
                 }
                 debug!(entry_disk_path, "finished indexing code snippet");
+                pipes.file_done();
                 pipes.index_percent(((completed as f32 / count as f32) * 100f32) as u8);
             }
         };
 
         let start = std::time::Instant::now();
 
-        let walker = FileWalker::index_directory(&repo.disk_path);
+        pipes.advance(IndexPhase::Walking);
+        let walker = FileWalker::index_directory(&repo.disk_path, &repo.walk_policy);
         let count = walker.len();
-        walker.for_each(pipes, file_worker(count));
+        pipes.files_total(count);
+        // the embedding workers drain the queue while we walk, and the walk
+        // waits for them whenever the queue is full
+        tokio::task::block_in_place(|| match file_cache {
+            Some(ref file_cache) => {
+                file_cache.embed_while(|| walker.for_each(pipes, file_worker(count)))
+            }
+            None => walker.for_each(pipes, file_worker(count)),
+        });
+
+        if pipes.is_cancelled() || pipes.was_paused() {
+            // the next sync picks up after the files embedded so far
+            if let Some(ref file_cache) = file_cache {
+                file_cache.checkpoint().await?;
+            }
 
-        if pipes.is_cancelled() {
             bail!("cancelled indexing");
         }
 
         info!(?repo.disk_path, "indexing finished, took {:?}", start.elapsed());
 
+        if let (Some(file_cache), Some(semantic_cache)) = (&file_cache, semantic_cache) {
+            file_cache.synchronize(semantic_cache).await?;
+        }
+
+        pipes.advance(IndexPhase::Committing);
+
         code_snippet_cache
             .synchronize(cache, |key| {
                 writer.delete_term(Term::from_field_text(self.unique_hash, key));
@@ -228,6 +335,14 @@ impl Indexable for Snippet {
         ));
     }
 
+    fn delete_by_repo_ref(&self, writer: &IndexWriter, repo_ref: &str) {
+        writer.delete_term(Term::from_field_text(self.repo_ref, repo_ref));
+    }
+
+    fn delete_by_unique_hash(&self, writer: &IndexWriter, unique_hash: &str) {
+        writer.delete_term(Term::from_field_text(self.unique_hash, unique_hash));
+    }
+
     /// Return the tantivy `Schema` of the current index
     fn schema(&self) -> Schema {
         self.schema.clone()
@@ -248,23 +363,30 @@ impl Snippet {
             .unwrap_or_default();
         trace!("processing file for code snippets");
         match dir_entry {
-            _ if workload.cache.is_fresh(&cache_keys) => {
-                info!(?cache_keys, "code snippet cache is fresh");
-                return Ok(());
-            }
             RepoDirectoryEntry::Dir(dir) => {
                 debug!("not indexing snippets from the directory {:?}", dir);
             }
             RepoDirectoryEntry::File(file) => {
+                // the semantic index has a cache of its own, so this happens
+                // whether the snippets are fresh or not
+                if let Err(err) = self.embed_file(&file, &workload) {
+                    warn!(?err, path = file.path, "failed to embed file");
+                }
+
+                if workload.cache.is_fresh(&cache_keys) {
+                    info!(?cache_keys, "code snippet cache is fresh");
+                    METRICS.file_skipped(SkipReason::Unchanged);
+                    return Ok(());
+                }
+
+                workload.cache.parent().queue_update(
+                    cache_keys.file_path(),
+                    &workload.relative_path.to_string_lossy(),
+                );
+
                 // Here we get back a list of documents all of which we have to write
                 // to the index
-                let documents = file.build_documents(
-                    self,
-                    &workload,
-                    &cache_keys,
-                    last_commit,
-                    workload.cache.parent(),
-                );
+                let documents = file.build_documents(self, &workload, &cache_keys, last_commit);
                 // add all the generated code snippets to the index
                 documents.into_iter().for_each(|document| {
                     // TODO(codestory): This kind of expect is bad, but we need
@@ -281,3 +403,176 @@ impl Snippet {
         Ok(())
     }
 }
+
+impl Snippet {
+    /// Chunk `file` and queue its chunks for embedding, if semantic search
+    /// is set up and the file changed since it was last embedded.
+    fn embed_file(&self, file: &RepositoryFile, workload: &Workload<'_>) -> Result<()> {
+        let (Some(cache), Some(semantic)) = (workload.semantic_cache, &self.semantic) else {
+            return Ok(());
+        };
+
+        let cache_keys = workload.semantic_cache_keys(semantic, file);
+        if cache.is_fresh(&cache_keys) {
+            trace!(?cache_keys, "semantic cache is fresh");
+            return Ok(());
+        }
+
+        // we don't detect languages, the extension stands in for one
+        let relative_path = workload.relative_path.to_string_lossy();
+        let extension = workload
+            .relative_path
+            .extension()
+            .map(|ext| ext.to_string_lossy());
+
+        let file_cache = cache.parent();
+        tokio::runtime::Handle::current().block_on(file_cache.process_chunks(
+            &cache_keys,
+            workload.repo_name,
+            &workload.repo_ref,
+            &relative_path,
+            &file.buffer,
+            extension.as_deref().unwrap_or_default(),
+            &[],
+            extension.as_deref(),
+            &file.offsets,
+        ))?;
+
+        file_cache.process_embedding_queue()
+    }
+}
+
+impl RepositoryFile {
+    /// Split the file into snippets of [`SNIPPET_LINES`] lines, all tagged
+    /// with the unique hash of this version of the file.
+    fn build_documents(
+        &self,
+        schema: &Snippet,
+        workload: &Workload<'_>,
+        cache_keys: &SnippetCacheKeys,
+        last_commit: i64,
+    ) -> Vec<TantivyDocument> {
+        let unique_hash = cache_keys.unique_hash();
+        let repo_disk_path = workload.repo_disk_path.to_string_lossy();
+        let relative_path = workload.relative_path.to_string_lossy();
+
+        let buffer = documents::searchable_text(&relative_path, &self.buffer);
+        let lines = buffer.lines().collect::<Vec<_>>();
+        lines
+            .chunks(SNIPPET_LINES)
+            .enumerate()
+            .filter(|(_, lines)| lines.iter().any(|line| !line.trim().is_empty()))
+            .map(|(index, lines)| {
+                let start_line = (index * SNIPPET_LINES) as u64;
+                let end_line = start_line + lines.len() as u64 - 1;
+
+                doc!(
+                    schema.unique_hash => unique_hash.as_str(),
+                    schema.repo_disk_path => repo_disk_path.as_ref(),
+                    schema.repo_ref => workload.repo_ref.as_str(),
+                    schema.repo_name => workload.repo_name,
+                    schema.relative_path => relative_path.as_ref(),
+                    schema.commit_hash => workload.commit_hash.as_str(),
+                    schema.last_commit_unix_seconds => last_commit.max(0) as u64,
+                    schema.content => lines.join("\n"),
+                    schema.start_line => start_line,
+                    schema.end_line => end_line,
+                )
+            })
+            .collect()
+    }
+}
+
+impl Indexer<Snippet> {
+    /// Snippets containing every term and phrase of `query`, best first.
+    ///
+    /// Terms may match the path as well as the content. An identifier also
+    /// matches its words, split the way the lexical terms of a
+    /// [`QueryPlan`](crate::semantic_search::query::QueryPlan) are, so
+    /// `getUserName` finds `get_user_name`. The filters of `query` are
+    /// applied to the results, so fewer than `limit` may come back.
+    pub fn search_parsed(&self, query: &ParsedQuery, limit: usize) -> Result<Vec<SnippetDocument>> {
+        let schema = &self.source;
+        let mut tokenizer = self.index.tokenizer_for_field(schema.content)?;
+        let mut tokens = |text: &str| {
+            let mut stream = tokenizer.token_stream(text);
+            let mut tokens = vec![];
+            while let Some(token) = stream.next() {
+                tokens.push(token.text.clone());
+            }
+            tokens
+        };
+
+        let term_query = |field, token: &str| -> Box<dyn Query> {
+            Box::new(TermQuery::new(
+                Term::from_field_text(field, token),
+                IndexRecordOption::WithFreqs,
+            ))
+        };
+
+        let all_of = |tokens: Vec<String>| -> Box<dyn Query> {
+            let clauses = tokens
+                .iter()
+                .map(|token| -> (Occur, Box<dyn Query>) {
+                    let either = BooleanQuery::new(vec![
+                        (Occur::Should, term_query(schema.content, token)),
+                        (Occur::Should, term_query(schema.relative_path, token)),
+                    ]);
+                    (Occur::Must, Box::new(either))
+                })
+                .collect();
+            Box::new(BooleanQuery::new(clauses))
+        };
+
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = vec![];
+        for term in &query.terms {
+            let whole = tokens(term);
+            let words = tokens(&split_identifiers(term).join(" "));
+            if whole.is_empty() {
+                continue;
+            }
+
+            if words == whole || words.is_empty() {
+                clauses.push((Occur::Must, all_of(whole)));
+            } else {
+                let either = BooleanQuery::new(vec![
+                    (Occur::Should, all_of(whole)),
+                    (Occur::Should, all_of(words)),
+                ]);
+                clauses.push((Occur::Must, Box::new(either)));
+            }
+        }
+        for phrase in &query.phrases {
+            let terms = tokens(phrase)
+                .into_iter()
+                .map(|token| Term::from_field_text(schema.content, &token))
+                .collect::<Vec<_>>();
+            match terms.len() {
+                0 => {}
+                1 => clauses.push((
+                    Occur::Must,
+                    Box::new(TermQuery::new(
+                        terms[0].clone(),
+                        IndexRecordOption::WithFreqs,
+                    )),
+                )),
+                _ => clauses.push((Occur::Must, Box::new(PhraseQuery::new(terms)))),
+            }
+        }
+
+        if clauses.is_empty() {
+            return Ok(vec![]);
+        }
+
+        // oversample, as the filters only run after tantivy
+        let documents = self
+            .search(&BooleanQuery::new(clauses), limit * 4)?
+            .into_iter()
+            .map(|(score, doc)| SnippetReader::read_document_with_score(schema, doc, score))
+            .filter(|document| document.matches(query))
+            .take(limit)
+            .collect();
+
+        Ok(documents)
+    }
+}
@@ -0,0 +1,487 @@
+//! Symbol definitions, for answering "where is X defined".
+//!
+//! Definitions are picked out of a file while it's being indexed, using the
+//! shape of the line they start on and the indentation or braces of the
+//! block after it. That's a lot less precise than a real parser, but it
+//! works the same for every language we chunk, and finds the definitions
+//! people search for.
+//!
+//! Rows are keyed by the file's absolute path, the same key
+//! `code_snippet_cache` and the trigrams use, so a file that's fresh keeps
+//! its symbols and a file that changed gets them replaced.
+
+use std::fmt;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use sqlx::{Row, Sqlite};
+
+use crate::{db::sqlite::SqlDb, repo::types::RepoRef};
+
+/// How many names a fuzzy lookup scores before fetching their definitions
+const FUZZY_CANDIDATES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymbolKind {
+    Function,
+    Class,
+    Struct,
+    Enum,
+    Trait,
+    Interface,
+    Impl,
+    Module,
+    Type,
+    Constant,
+}
+
+impl SymbolKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Function => "function",
+            Self::Class => "class",
+            Self::Struct => "struct",
+            Self::Enum => "enum",
+            Self::Trait => "trait",
+            Self::Interface => "interface",
+            Self::Impl => "impl",
+            Self::Module => "module",
+            Self::Type => "type",
+            Self::Constant => "constant",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        Some(match kind {
+            "function" => Self::Function,
+            "class" => Self::Class,
+            "struct" => Self::Struct,
+            "enum" => Self::Enum,
+            "trait" => Self::Trait,
+            "interface" => Self::Interface,
+            "impl" => Self::Impl,
+            "module" => Self::Module,
+            "type" => Self::Type,
+            "constant" => Self::Constant,
+            _ => return None,
+        })
+    }
+
+    fn from_keyword(keyword: &str) -> Option<Self> {
+        Some(match keyword {
+            "fn" | "def" | "func" | "function" => Self::Function,
+            "class" | "object" => Self::Class,
+            "struct" => Self::Struct,
+            "enum" => Self::Enum,
+            "trait" => Self::Trait,
+            "interface" | "protocol" => Self::Interface,
+            "impl" => Self::Impl,
+            "mod" | "module" | "namespace" | "package" => Self::Module,
+            "type" | "typedef" => Self::Type,
+            "const" | "static" => Self::Constant,
+            _ => return None,
+        })
+    }
+
+    /// Whether definitions inside this one are members of it
+    fn is_container(&self) -> bool {
+        !matches!(self, Self::Function | Self::Type | Self::Constant)
+    }
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// The class, module or impl block the symbol is defined in
+    pub container: Option<String>,
+    pub relative_path: String,
+    pub start_line: u64,
+    pub end_line: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymbolMatch {
+    Exact,
+    Prefix,
+    /// The query's characters appear in the name in order, so `hndlreq`
+    /// finds `handle_request`
+    Fuzzy,
+}
+
+/// Pick the definitions out of `buffer`, lines are 0-based like the ones of
+/// chunks.
+pub fn extract(relative_path: &str, buffer: &str) -> Vec<Symbol> {
+    static DEFINITION: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(
+            r"^\s*(?:(?:pub(?:\([^)]*\))?|export|default|public|private|protected|internal|static|abstract|final|async|unsafe|extern|override|virtual|open|sealed|data)\s+)*(fn|def|func|function|class|object|struct|enum|trait|interface|protocol|impl|mod|module|namespace|package|type|typedef|const|static)\b(?:\s*<[^>]*>)?\s*(?:\([^)]*\)\s*)?([A-Za-z_$][\w$]*(?:::[A-Za-z_$][\w$]*)*)",
+        )
+        .unwrap()
+    });
+
+    let lines = buffer.lines().collect::<Vec<_>>();
+    let mut symbols = vec![];
+    // (indent, end_line, name, kind) of the definitions we're inside of
+    let mut enclosing: Vec<(usize, usize, String, SymbolKind)> = vec![];
+
+    for (idx, line) in lines.iter().enumerate() {
+        let Some(captures) = DEFINITION.captures(line) else {
+            continue;
+        };
+        let Some(kind) = SymbolKind::from_keyword(&captures[1]) else {
+            continue;
+        };
+
+        let (kind, name) = match (kind, line.split_once(" for ")) {
+            // `impl Display for Foo` is about `Foo`
+            (SymbolKind::Impl, Some((_, target))) => (
+                kind,
+                target
+                    .trim()
+                    .trim_end_matches('{')
+                    .split(|c: char| !c.is_alphanumeric() && c != '_' && c != ':')
+                    .next()
+                    .unwrap_or_default()
+                    .to_owned(),
+            ),
+            // the type comes first in `static int foo = 1;`, and
+            // `static void main(...)` is no constant at all
+            (SymbolKind::Constant, _) => {
+                let rest = &line[captures.get(1).map_or(0, |keyword| keyword.end())..];
+                match declared_name(rest) {
+                    Some((name, true)) => (SymbolKind::Function, name),
+                    Some((name, false)) => (kind, name),
+                    None => continue,
+                }
+            }
+            _ => (kind, captures[2].to_owned()),
+        };
+        if name.is_empty() {
+            continue;
+        }
+
+        let indent = indentation(line);
+        let end = block_end(&lines, idx);
+
+        enclosing.retain(|(outer_indent, outer_end, _, _)| {
+            *outer_end >= idx && *outer_indent < indent
+        });
+        let innermost = enclosing.last().map(|(_, _, _, kind)| *kind);
+        let container = enclosing
+            .iter()
+            .rev()
+            .find(|(_, _, _, kind)| kind.is_container())
+            .map(|(_, _, name, _)| name.clone());
+        enclosing.push((indent, end, name.clone(), kind));
+
+        // locals nobody searches for, and impl blocks which are only there
+        // to give methods a container
+        if kind == SymbolKind::Impl
+            || (kind == SymbolKind::Constant && innermost == Some(SymbolKind::Function))
+        {
+            continue;
+        }
+
+        symbols.push(Symbol {
+            name,
+            kind,
+            container,
+            relative_path: relative_path.to_owned(),
+            start_line: idx as u64,
+            end_line: end as u64,
+        });
+    }
+
+    symbols
+}
+
+/// The identifier right before the `=`, `;`, `:` or `(` of a declaration,
+/// and whether it's followed by `(`, which makes it a function
+fn declared_name(declaration: &str) -> Option<(String, bool)> {
+    static IDENTIFIER: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Za-z_$][\w$]*").unwrap());
+
+    let bytes = declaration.as_bytes();
+    let end = (0..bytes.len()).find(|&idx| match bytes[idx] {
+        b'=' | b';' | b'(' => true,
+        // but not the `::` of a path
+        b':' => bytes.get(idx + 1) != Some(&b':') && (idx == 0 || bytes[idx - 1] != b':'),
+        _ => false,
+    })?;
+
+    let name = IDENTIFIER.find_iter(&declaration[..end]).last()?;
+    Some((name.as_str().to_owned(), bytes[end] == b'('))
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// The last line of the block a definition on `start` opens.
+///
+/// Braces are counted if the definition has them, otherwise the block ends
+/// before the next line that isn't indented deeper than the definition.
+fn block_end(lines: &[&str], start: usize) -> usize {
+    let indent = indentation(lines[start]);
+    let mut depth = 0i64;
+    let mut braced = false;
+
+    for (idx, line) in lines.iter().enumerate().skip(start) {
+        for c in line.chars() {
+            match c {
+                '{' => {
+                    depth += 1;
+                    braced = true;
+                }
+                '}' => depth -= 1,
+                _ => {}
+            }
+        }
+
+        if braced && depth <= 0 {
+            return idx;
+        }
+
+        if braced {
+            continue;
+        }
+
+        // a declaration without a body
+        if idx == start && line.trim_end().ends_with(';') {
+            return idx;
+        }
+
+        match lines.get(idx + 1).map(|next| next.trim_start()) {
+            None => return idx,
+            // the body, or the rest of the signature, is still to come
+            Some(next) if next.is_empty() || next.starts_with(['{', ')', ']']) => {}
+            Some(_) if indentation(lines[idx + 1]) <= indent => return idx,
+            Some(_) => {}
+        }
+    }
+
+    lines.len().saturating_sub(1).max(start)
+}
+
+/// Replace the symbols stored for the file at `file_path`.
+pub(crate) async fn replace(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+    file_path: &str,
+    symbols: &[Symbol],
+) -> anyhow::Result<()> {
+    delete_for_file(tx, repo_ref, file_path).await?;
+
+    for symbol in symbols {
+        let kind = symbol.kind.as_str();
+        let start_line = symbol.start_line as i64;
+        let end_line = symbol.end_line as i64;
+        sqlx::query! {
+            "INSERT INTO symbols \
+             (repo_ref, file_path, relative_path, name, kind, container, start_line, end_line) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            repo_ref,
+            file_path,
+            symbol.relative_path,
+            symbol.name,
+            kind,
+            symbol.container,
+            start_line,
+            end_line,
+        }
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn delete_for_file(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+    file_path: &str,
+) -> anyhow::Result<()> {
+    sqlx::query! {
+        "DELETE FROM symbols WHERE repo_ref = ? AND file_path = ?",
+        repo_ref,
+        file_path,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn delete_all(tx: &mut sqlx::Transaction<'_, Sqlite>) -> anyhow::Result<()> {
+    sqlx::query!("DELETE FROM symbols")
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn delete_for_repo(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+) -> anyhow::Result<()> {
+    sqlx::query! {
+        "DELETE FROM symbols WHERE repo_ref = ?",
+        repo_ref,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Definitions of `name` in `reporef`, best matches first.
+///
+/// Exact and prefix matches ignore case, shorter names rank first among
+/// prefix matches.
+pub async fn lookup(
+    sql: &SqlDb,
+    reporef: &RepoRef,
+    name: &str,
+    mode: SymbolMatch,
+    limit: usize,
+) -> anyhow::Result<Vec<Symbol>> {
+    let repo_ref = reporef.to_string();
+
+    let rows = match mode {
+        SymbolMatch::Exact => {
+            sqlx::query(
+                "SELECT * FROM symbols WHERE repo_ref = ? AND name = ? COLLATE NOCASE \
+                 ORDER BY relative_path, start_line LIMIT ?",
+            )
+            .bind(&repo_ref)
+            .bind(name)
+            .bind(limit as i64)
+            .fetch_all(sql.as_ref())
+            .await?
+        }
+        SymbolMatch::Prefix => {
+            let pattern = format!("{}%", escape_like(name));
+            sqlx::query(
+                "SELECT * FROM symbols WHERE repo_ref = ? AND name LIKE ? ESCAPE '\\' \
+                 ORDER BY length(name), name, relative_path, start_line LIMIT ?",
+            )
+            .bind(&repo_ref)
+            .bind(pattern)
+            .bind(limit as i64)
+            .fetch_all(sql.as_ref())
+            .await?
+        }
+        SymbolMatch::Fuzzy => return fuzzy_lookup(sql, &repo_ref, name, limit).await,
+    };
+
+    rows.iter().map(read_symbol).collect()
+}
+
+async fn fuzzy_lookup(
+    sql: &SqlDb,
+    repo_ref: &str,
+    query: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<Symbol>> {
+    let names: Vec<String> =
+        sqlx::query_scalar("SELECT DISTINCT name FROM symbols WHERE repo_ref = ?")
+            .bind(repo_ref)
+            .fetch_all(sql.as_ref())
+            .await?;
+
+    let mut scored = names
+        .into_iter()
+        .filter_map(|name| Some((fuzzy_score(query, &name)?, name)))
+        .collect::<Vec<_>>();
+    scored.sort_by(|(a, a_name), (b, b_name)| b.cmp(a).then_with(|| a_name.cmp(b_name)));
+    scored.truncate(FUZZY_CANDIDATES);
+
+    let mut symbols = vec![];
+    for (_, name) in scored {
+        if symbols.len() >= limit {
+            break;
+        }
+
+        let rows = sqlx::query(
+            "SELECT * FROM symbols WHERE repo_ref = ? AND name = ? \
+             ORDER BY relative_path, start_line",
+        )
+        .bind(repo_ref)
+        .bind(&name)
+        .fetch_all(sql.as_ref())
+        .await?;
+
+        for row in rows.iter() {
+            symbols.push(read_symbol(row)?);
+        }
+    }
+
+    symbols.truncate(limit);
+    Ok(symbols)
+}
+
+/// Score `name` for `query` if all the characters of the query appear in it
+/// in order, ignoring case. Runs of consecutive characters and matches at
+/// the start of a word score higher, long names score lower.
+fn fuzzy_score(query: &str, name: &str) -> Option<i64> {
+    let query = query.to_lowercase().chars().collect::<Vec<_>>();
+    if query.is_empty() {
+        return None;
+    }
+
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut score = 0i64;
+    let mut next = 0;
+    let mut previous_match = None;
+
+    for (idx, c) in chars.iter().enumerate() {
+        if next == query.len() {
+            break;
+        }
+
+        if c.to_lowercase().eq(query[next].to_lowercase()) {
+            score += 1;
+            if previous_match == Some(idx.wrapping_sub(1)) {
+                score += 4;
+            }
+
+            let word_start = idx == 0
+                || matches!(chars[idx - 1], '_' | '-' | '.' | ':')
+                || (chars[idx - 1].is_lowercase() && c.is_uppercase());
+            if word_start {
+                score += 8;
+            }
+
+            previous_match = Some(idx);
+            next += 1;
+        }
+    }
+
+    (next == query.len()).then(|| score * 16 - chars.len() as i64)
+}
+
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn read_symbol(row: &sqlx::sqlite::SqliteRow) -> anyhow::Result<Symbol> {
+    let kind: String = row.try_get("kind")?;
+    Ok(Symbol {
+        name: row.try_get("name")?,
+        kind: SymbolKind::parse(&kind)
+            .ok_or_else(|| anyhow::anyhow!("unknown symbol kind {kind}"))?,
+        container: row.try_get("container")?,
+        relative_path: row.try_get("relative_path")?,
+        start_line: row.try_get::<i64, _>("start_line")? as u64,
+        end_line: row.try_get::<i64, _>("end_line")? as u64,
+    })
+}
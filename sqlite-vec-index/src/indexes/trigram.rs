@@ -0,0 +1,483 @@
+// Regex search over the files of the snippet index.
+//
+// Tantivy only knows about tokens, so a regex like `fn\s+derive_\w+` can't
+// be answered by the snippet index. Instead we keep the trigrams of every
+// file, work out which literal strings any match of the regex has to
+// contain, and only read the files which have all of their trigrams. The
+// regex then runs over those files to find the actual matches.
+//
+// Trigrams are case folded, which lets a few more files through but never
+// leaves one out. A regex without a literal of three bytes or more has to
+// read every file, and matches are found line by line.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use regex::Regex;
+use regex_syntax::hir::{Hir, HirKind};
+use sqlx::{QueryBuilder, Row, Sqlite};
+use tracing::debug;
+
+use crate::{
+    db::sqlite::SqlDb,
+    query::parser::{Field, ParsedQuery},
+    repo::{encoding, types::RepoRef},
+    semantic_search::documents,
+};
+
+use super::snippet::SnippetDocument;
+
+/// Longer lines are cut when they're returned as a match
+const MAX_LINE_LEN: usize = 512;
+
+/// Postings inserted by a single statement, at three parameters each
+const INSERT_BATCH: usize = 256;
+
+fn trigram(bytes: &[u8]) -> i64 {
+    let fold = |b: u8| b.to_ascii_lowercase() as i64;
+    (fold(bytes[0]) << 16) | (fold(bytes[1]) << 8) | fold(bytes[2])
+}
+
+/// The distinct trigrams of `text`
+pub(crate) fn trigrams(text: &str) -> HashSet<i64> {
+    text.as_bytes().windows(3).map(trigram).collect()
+}
+
+pub(crate) async fn replace(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+    file_path: &str,
+    relative_path: &str,
+    text: &str,
+) -> anyhow::Result<()> {
+    delete_for_file(tx, repo_ref, file_path).await?;
+
+    sqlx::query! {
+        "INSERT INTO trigram_files (repo_ref, file_path, relative_path) VALUES (?, ?, ?)",
+        repo_ref,
+        file_path,
+        relative_path,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    let trigrams = trigrams(text).into_iter().collect::<Vec<_>>();
+    for batch in trigrams.chunks(INSERT_BATCH) {
+        let mut insert = QueryBuilder::<Sqlite>::new(
+            "INSERT INTO trigram_postings (repo_ref, trigram, file_path) ",
+        );
+        insert.push_values(batch, |mut row, trigram| {
+            row.push_bind(repo_ref)
+                .push_bind(*trigram)
+                .push_bind(file_path);
+        });
+        insert.build().execute(&mut **tx).await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn delete_for_file(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+    file_path: &str,
+) -> anyhow::Result<()> {
+    sqlx::query! {
+        "DELETE FROM trigram_postings WHERE repo_ref = ? AND file_path = ?",
+        repo_ref,
+        file_path,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query! {
+        "DELETE FROM trigram_files WHERE repo_ref = ? AND file_path = ?",
+        repo_ref,
+        file_path,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn delete_all(tx: &mut sqlx::Transaction<'_, Sqlite>) -> anyhow::Result<()> {
+    sqlx::query!("DELETE FROM trigram_postings")
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query!("DELETE FROM trigram_files")
+        .execute(&mut **tx)
+        .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn delete_for_repo(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+) -> anyhow::Result<()> {
+    sqlx::query! {
+        "DELETE FROM trigram_postings WHERE repo_ref = ?",
+        repo_ref,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query! {
+        "DELETE FROM trigram_files WHERE repo_ref = ?",
+        repo_ref,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// What a file has to contain for the regex to possibly match in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Prefilter {
+    /// Nothing we can check with trigrams
+    Any,
+    Literal(Vec<u8>),
+    All(Vec<Prefilter>),
+    AnyOf(Vec<Prefilter>),
+}
+
+impl Prefilter {
+    fn from_hir(hir: &Hir) -> Self {
+        match hir.kind() {
+            HirKind::Literal(literal) => Self::Literal(literal.0.to_vec()),
+            HirKind::Capture(capture) => Self::from_hir(&capture.sub),
+            HirKind::Repetition(repetition) if repetition.min > 0 => {
+                Self::from_hir(&repetition.sub)
+            }
+            HirKind::Concat(parts) => {
+                // adjacent literals make up a longer literal, with more
+                // trigrams to check
+                let mut all = vec![];
+                let mut literal = vec![];
+                for part in parts {
+                    match Self::from_hir(part) {
+                        Self::Literal(bytes) if is_plain_literal(part) => {
+                            literal.extend(bytes);
+                        }
+                        other => {
+                            if !literal.is_empty() {
+                                all.push(Self::Literal(std::mem::take(&mut literal)));
+                            }
+                            all.push(other);
+                        }
+                    }
+                }
+                if !literal.is_empty() {
+                    all.push(Self::Literal(literal));
+                }
+
+                Self::All(all).simplify()
+            }
+            HirKind::Alternation(branches) => {
+                Self::AnyOf(branches.iter().map(Self::from_hir).collect()).simplify()
+            }
+            _ => Self::Any,
+        }
+    }
+
+    fn simplify(self) -> Self {
+        match self {
+            Self::Literal(bytes) if bytes.len() < 3 => Self::Any,
+            Self::All(parts) => {
+                let mut parts = parts
+                    .into_iter()
+                    .map(Self::simplify)
+                    .filter(|part| *part != Self::Any)
+                    .collect::<Vec<_>>();
+                match parts.len() {
+                    0 => Self::Any,
+                    1 => parts.remove(0),
+                    _ => Self::All(parts),
+                }
+            }
+            Self::AnyOf(branches) => {
+                let mut branches = branches.into_iter().map(Self::simplify).collect::<Vec<_>>();
+                if branches.is_empty() || branches.contains(&Self::Any) {
+                    Self::Any
+                } else if branches.len() == 1 {
+                    branches.remove(0)
+                } else {
+                    Self::AnyOf(branches)
+                }
+            }
+            other => other,
+        }
+    }
+}
+
+fn is_plain_literal(hir: &Hir) -> bool {
+    matches!(hir.kind(), HirKind::Literal(_))
+}
+
+/// Files which might match, `None` if every file might
+struct Candidates<'a> {
+    sql: &'a SqlDb,
+    repo_ref: &'a str,
+    postings: HashMap<i64, HashSet<String>>,
+}
+
+impl Candidates<'_> {
+    async fn postings(&mut self, trigram: i64) -> anyhow::Result<&HashSet<String>> {
+        if !self.postings.contains_key(&trigram) {
+            let files = sqlx::query(
+                "SELECT file_path FROM trigram_postings WHERE repo_ref = ? AND trigram = ?",
+            )
+            .bind(self.repo_ref)
+            .bind(trigram)
+            .fetch_all(self.sql.as_ref())
+            .await?
+            .into_iter()
+            .map(|row| row.try_get("file_path"))
+            .collect::<Result<HashSet<String>, _>>()?;
+
+            self.postings.insert(trigram, files);
+        }
+
+        Ok(&self.postings[&trigram])
+    }
+
+    async fn evaluate(&mut self, filter: &Prefilter) -> anyhow::Result<Option<HashSet<String>>> {
+        let mut stack = vec![filter];
+        let mut leaves = vec![];
+        while let Some(filter) = stack.pop() {
+            match filter {
+                Prefilter::All(parts) | Prefilter::AnyOf(parts) => stack.extend(parts.iter()),
+                Prefilter::Literal(bytes) => leaves.push(bytes),
+                Prefilter::Any => {}
+            }
+        }
+
+        // fetch every posting list up front, so the evaluation itself
+        // doesn't need to await
+        for bytes in leaves {
+            for window in bytes.windows(3) {
+                self.postings(trigram(window)).await?;
+            }
+        }
+
+        Ok(self.resolve(filter))
+    }
+
+    fn resolve(&self, filter: &Prefilter) -> Option<HashSet<String>> {
+        match filter {
+            Prefilter::Any => None,
+            Prefilter::Literal(bytes) => intersect(
+                bytes
+                    .windows(3)
+                    .map(|window| Some(self.postings[&trigram(window)].clone())),
+            ),
+            Prefilter::All(parts) => intersect(parts.iter().map(|part| self.resolve(part))),
+            Prefilter::AnyOf(branches) => {
+                let mut union = HashSet::new();
+                for branch in branches {
+                    union.extend(self.resolve(branch)?);
+                }
+                Some(union)
+            }
+        }
+    }
+}
+
+fn intersect(sets: impl Iterator<Item = Option<HashSet<String>>>) -> Option<HashSet<String>> {
+    let mut result: Option<HashSet<String>> = None;
+    for set in sets.flatten() {
+        result = Some(match result {
+            None => set,
+            Some(result) => result.intersection(&set).cloned().collect(),
+        });
+    }
+    result
+}
+
+/// Lines of the files of `reporef` which match `pattern`, in path order.
+///
+/// `query` narrows the files down further with its `path:` filters.
+pub async fn search(
+    sql: &SqlDb,
+    reporef: &RepoRef,
+    pattern: &str,
+    query: Option<&ParsedQuery>,
+    limit: usize,
+) -> anyhow::Result<Vec<SnippetDocument>> {
+    let regex = Regex::new(pattern)?;
+    let prefilter = Prefilter::from_hir(&regex_syntax::parse(pattern)?).simplify();
+    debug!(?prefilter, pattern, "regex search");
+
+    let repo_ref = reporef.to_string();
+    let mut candidates = Candidates {
+        sql,
+        repo_ref: &repo_ref,
+        postings: HashMap::new(),
+    };
+    let narrowed = candidates.evaluate(&prefilter).await?;
+
+    let mut files = sqlx::query(
+        "SELECT file_path, relative_path FROM trigram_files WHERE repo_ref = ? \
+         ORDER BY relative_path",
+    )
+    .bind(&repo_ref)
+    .fetch_all(sql.as_ref())
+    .await?
+    .into_iter()
+    .map(|row| Ok((row.try_get("file_path")?, row.try_get("relative_path")?)))
+    .collect::<anyhow::Result<Vec<(String, String)>>>()?;
+
+    files.retain(|(file_path, relative_path)| {
+        narrowed.as_ref().map_or(true, |narrowed| narrowed.contains(file_path))
+            && query.map_or(true, |query| query.allows(Field::Path, relative_path))
+    });
+
+    let repo_name = reporef.indexed_name();
+    let mut matches = vec![];
+    for (file_path, relative_path) in files {
+        if matches.len() >= limit {
+            break;
+        }
+
+        let Some(text) = read(Path::new(&file_path), &relative_path).await else {
+            continue;
+        };
+
+        for (line_number, line) in text.lines().enumerate() {
+            if !regex.is_match(line) {
+                continue;
+            }
+
+            let mut content = line.to_owned();
+            if content.len() > MAX_LINE_LEN {
+                let mut end = MAX_LINE_LEN;
+                while !content.is_char_boundary(end) {
+                    end -= 1;
+                }
+                content.truncate(end);
+            }
+
+            matches.push(SnippetDocument {
+                relative_path: relative_path.clone(),
+                repo_name: repo_name.clone(),
+                repo_ref: repo_ref.clone(),
+                content,
+                line_start: line_number as u64,
+                line_end: line_number as u64,
+                score: 1.0,
+                context: None,
+            });
+
+            if matches.len() >= limit {
+                break;
+            }
+        }
+    }
+
+    Ok(matches)
+}
+
+/// The text of the file at `path` which its trigrams were taken from
+async fn read(path: &Path, relative_path: &str) -> Option<String> {
+    let bytes = tokio::fs::read(path).await.ok()?;
+    let text = encoding::decode(bytes)?.text;
+    Some(documents::searchable_text(relative_path, &text).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefilter(pattern: &str) -> Prefilter {
+        Prefilter::from_hir(&regex_syntax::parse(pattern).unwrap()).simplify()
+    }
+
+    fn literal(text: &str) -> Prefilter {
+        Prefilter::Literal(text.as_bytes().to_vec())
+    }
+
+    fn files(paths: &[&str]) -> HashSet<String> {
+        paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    #[test]
+    fn builds_prefilters() {
+        let cases = [
+            ("derive", literal("derive")),
+            ("fn\\s+derive_\\w+", literal("derive_")),
+            (
+                "foo.*bar",
+                Prefilter::All(vec![literal("foo"), literal("bar")]),
+            ),
+            ("(foo)+", literal("foo")),
+            ("(foo)?bar", literal("bar")),
+            (
+                "foo|bar",
+                Prefilter::AnyOf(vec![literal("foo"), literal("bar")]),
+            ),
+            (
+                "abc(def|ghi)",
+                Prefilter::All(vec![
+                    literal("abc"),
+                    Prefilter::AnyOf(vec![literal("def"), literal("ghi")]),
+                ]),
+            ),
+            ("héllo", literal("héllo")),
+            // too short, or optional, to narrow anything down
+            ("ab", Prefilter::Any),
+            ("a.b.c", Prefilter::Any),
+            ("(foo)*", Prefilter::Any),
+            ("foo|x.*", Prefilter::Any),
+            ("\\w+", Prefilter::Any),
+            ("", Prefilter::Any),
+        ];
+
+        for (pattern, expected) in cases {
+            assert_eq!(prefilter(pattern), expected, "{pattern}");
+        }
+    }
+
+    #[test]
+    fn folds_case() {
+        let cases = [
+            ("abc", "ABC", true),
+            ("Derive", "dERIVE", true),
+            ("abc", "abd", false),
+            ("ab", "", true),
+        ];
+
+        for (left, right, same) in cases {
+            assert_eq!(
+                trigrams(left) == trigrams(right),
+                same,
+                "{left} and {right}"
+            );
+        }
+    }
+
+    #[test]
+    fn intersects_candidates() {
+        let cases = [
+            (vec![], None),
+            (vec![None, None], None),
+            (vec![Some(files(&["a", "b"]))], Some(files(&["a", "b"]))),
+            (
+                vec![Some(files(&["a", "b"])), None, Some(files(&["b", "c"]))],
+                Some(files(&["b"])),
+            ),
+            (
+                vec![Some(files(&["a"])), Some(files(&["b"]))],
+                Some(files(&[])),
+            ),
+        ];
+
+        for (sets, expected) in cases {
+            let name = format!("{sets:?}");
+            assert_eq!(intersect(sets.into_iter()), expected, "{name}");
+        }
+    }
+}
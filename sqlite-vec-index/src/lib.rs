@@ -1,5 +1,8 @@
 //mod application;
+mod db;
 mod embedder;
 mod indexes;
+mod metrics;
+mod query;
 mod repo;
 mod semantic_search;
\ No newline at end of file
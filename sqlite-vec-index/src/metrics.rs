@@ -0,0 +1,277 @@
+// Process-wide counters and histograms for the indexer, rendered in the
+// Prometheus text exposition format.
+//
+// There are only a handful of metrics, so instead of pulling in a client
+// library we keep them as atomics in statics, and format them by hand.
+
+use std::{
+    fmt::Write,
+    path::Path,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+
+/// Latency buckets in seconds, from a fast search up to a slow batch on CPU
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Indexing a repository takes anywhere from seconds to hours
+const INDEX_BUCKETS: &[f64] = &[
+    1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0,
+];
+
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, value: i64) {
+        self.0.store(value, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct Histogram {
+    buckets: &'static [f64],
+    // not cumulative, that's done when rendering
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Self {
+        Self {
+            buckets,
+            counts: buckets.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(pos) = self.buckets.iter().position(|le| secs <= *le) {
+            self.counts[pos].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (le, count) in self.buckets.iter().zip(&self.counts) {
+            cumulative += count.load(Ordering::Relaxed);
+            _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {cumulative}");
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {count}");
+        _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+        _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+/// Why a file in the repository was not indexed
+#[derive(Clone, Copy)]
+pub enum SkipReason {
+    Unreadable,
+    Binary,
+    Unchanged,
+}
+
+impl SkipReason {
+    const ALL: [Self; 3] = [Self::Unreadable, Self::Binary, Self::Unchanged];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unreadable => "unreadable",
+            Self::Binary => "binary",
+            Self::Unchanged => "unchanged",
+        }
+    }
+}
+
+pub struct Metrics {
+    pub files_scanned: Counter,
+    files_skipped: [Counter; 3],
+
+    pub chunks_embedded: Counter,
+    pub chunks_reused: Counter,
+    pub chunks_deleted: Counter,
+
+    pub embedding_batch: Histogram,
+    pub embed_queue_depth: Gauge,
+
+    pub sync_queue_queued: Gauge,
+    pub sync_queue_active: Gauge,
+
+    index_duration: scc::HashMap<String, Histogram>,
+    pub search: Histogram,
+}
+
+pub static METRICS: Lazy<Metrics> = Lazy::new(|| Metrics {
+    files_scanned: Default::default(),
+    files_skipped: Default::default(),
+    chunks_embedded: Default::default(),
+    chunks_reused: Default::default(),
+    chunks_deleted: Default::default(),
+    embedding_batch: Histogram::new(LATENCY_BUCKETS),
+    embed_queue_depth: Default::default(),
+    sync_queue_queued: Default::default(),
+    sync_queue_active: Default::default(),
+    index_duration: Default::default(),
+    search: Histogram::new(LATENCY_BUCKETS),
+});
+
+impl Metrics {
+    pub fn file_skipped(&self, reason: SkipReason) {
+        self.files_skipped[reason as usize].inc();
+    }
+
+    /// Record the `(new, updated, deleted)` counts of a chunk cache commit.
+    pub fn chunks_committed(&self, (new, updated, deleted): (usize, usize, usize)) {
+        self.chunks_embedded.add(new as u64);
+        self.chunks_reused.add(updated as u64);
+        self.chunks_deleted.add(deleted as u64);
+    }
+
+    pub fn index_finished(&self, repo: &str, duration: Duration) {
+        self.index_duration
+            .entry(repo.to_owned())
+            .or_insert_with(|| Histogram::new(INDEX_BUCKETS))
+            .get()
+            .observe(duration);
+    }
+
+    /// Everything in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "codestory_files_scanned_total",
+            "counter",
+            "Files read while walking repositories",
+        );
+        _ = writeln!(out, "codestory_files_scanned_total {}", self.files_scanned.get());
+
+        header(
+            &mut out,
+            "codestory_files_skipped_total",
+            "counter",
+            "Files which were not indexed",
+        );
+        for reason in SkipReason::ALL {
+            _ = writeln!(
+                out,
+                "codestory_files_skipped_total{{reason=\"{}\"}} {}",
+                reason.as_str(),
+                self.files_skipped[reason as usize].get()
+            );
+        }
+
+        header(
+            &mut out,
+            "codestory_chunks_total",
+            "counter",
+            "Chunks by what happened to them when committing a file",
+        );
+        for (state, counter) in [
+            ("embedded", &self.chunks_embedded),
+            ("reused", &self.chunks_reused),
+            ("deleted", &self.chunks_deleted),
+        ] {
+            _ = writeln!(out, "codestory_chunks_total{{state=\"{state}\"}} {}", counter.get());
+        }
+
+        header(
+            &mut out,
+            "codestory_embedding_batch_seconds",
+            "histogram",
+            "Time to embed a batch of chunks",
+        );
+        self.embedding_batch
+            .render(&mut out, "codestory_embedding_batch_seconds", "");
+
+        header(&mut out, "codestory_embed_queue_depth", "gauge", "Chunks waiting to be embedded");
+        _ = writeln!(out, "codestory_embed_queue_depth {}", self.embed_queue_depth.get());
+
+        header(&mut out, "codestory_sync_queue_depth", "gauge", "Repositories in the sync queue");
+        _ = writeln!(
+            out,
+            "codestory_sync_queue_depth{{state=\"queued\"}} {}",
+            self.sync_queue_queued.get()
+        );
+        _ = writeln!(
+            out,
+            "codestory_sync_queue_depth{{state=\"active\"}} {}",
+            self.sync_queue_active.get()
+        );
+
+        header(
+            &mut out,
+            "codestory_index_duration_seconds",
+            "histogram",
+            "Time to index a repository",
+        );
+        let mut repos = vec![];
+        self.index_duration.scan(|repo, _| repos.push(repo.clone()));
+        repos.sort();
+        for repo in repos {
+            self.index_duration.read(&repo, |_, histogram| {
+                let labels = format!("repo=\"{}\"", escape(&repo));
+                histogram.render(&mut out, "codestory_index_duration_seconds", &labels);
+            });
+        }
+
+        header(&mut out, "codestory_search_seconds", "histogram", "Time to run a semantic search");
+        self.search.render(&mut out, "codestory_search_seconds", "");
+
+        out
+    }
+}
+
+/// Write the current metrics to `path`, for when there's nothing to scrape
+/// them from.
+pub async fn dump(path: &Path) -> std::io::Result<()> {
+    tokio::fs::write(path, METRICS.render()).await
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    _ = writeln!(out, "# HELP {name} {help}");
+    _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
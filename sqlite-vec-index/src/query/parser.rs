@@ -0,0 +1,620 @@
+//! The query language shared by the lexical and semantic searches.
+//!
+//! ```text
+//! lang:rust path:src/indexes repo:sidecar -path:tests "exact phrase" embed queue
+//! owner:@acme/search since:30d sort:recent retry logic
+//! ```
+//!
+//! Filters are `field:value`, with the value quoted if it has spaces in it,
+//! and a leading `-` negates a filter, a phrase or a word. Everything else,
+//! including a `word:` which isn't one of the [`FIELDS`], is the text we
+//! search for.
+//!
+//! `since:` and `sort:` aren't filters on a field, they narrow down and
+//! order the results by when their lines last changed, as far as blame
+//! knows.
+
+use std::fmt;
+
+/// Fields which can be filtered on
+pub const FIELDS: [&str; 7] = ["lang", "path", "repo", "owner", "author", "since", "sort"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Lang,
+    Path,
+    Repo,
+    /// A CODEOWNERS owner of the file
+    Owner,
+    /// The author of the last change, by name or email
+    Author,
+    Since,
+    Sort,
+}
+
+impl Field {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "lang" | "language" => Self::Lang,
+            "path" | "file" => Self::Path,
+            "repo" => Self::Repo,
+            "owner" | "team" => Self::Owner,
+            "author" => Self::Author,
+            "since" => Self::Since,
+            "sort" => Self::Sort,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Lang => "lang",
+            Self::Path => "path",
+            Self::Repo => "repo",
+            Self::Owner => "owner",
+            Self::Author => "author",
+            Self::Since => "since",
+            Self::Sort => "sort",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    /// Best match first
+    #[default]
+    Relevance,
+
+    /// Most recently changed first
+    Recent,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    #[error("the query is empty")]
+    Empty,
+
+    #[error("`{field}:` at position {position} needs a value, like `{field}:foo`")]
+    MissingValue { field: Field, position: usize },
+
+    #[error("the quote at position {position} is never closed")]
+    UnterminatedQuote { position: usize },
+
+    #[error("the query only excludes things, add some text or a filter to search for")]
+    OnlyNegations,
+
+    #[error("`{field}:{value}` at position {position} is not valid, expected {expected}")]
+    InvalidValue {
+        field: Field,
+        value: String,
+        position: usize,
+        expected: &'static str,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Filter {
+    pub field: Field,
+    pub value: String,
+    pub negated: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize)]
+pub struct ParsedQuery {
+    pub filters: Vec<Filter>,
+
+    /// Free text words, in the order they were written
+    pub terms: Vec<String>,
+
+    /// Quoted text which has to appear as it is
+    pub phrases: Vec<String>,
+
+    /// Words and phrases which must not appear
+    pub excluded: Vec<String>,
+
+    /// Only keep results changed within this many seconds
+    pub since_secs: Option<u64>,
+
+    pub sort: SortOrder,
+}
+
+impl ParsedQuery {
+    /// What the semantic index should embed
+    pub fn semantic_text(&self) -> String {
+        self.phrases
+            .iter()
+            .chain(self.terms.iter())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn has_text(&self) -> bool {
+        !self.terms.is_empty() || !self.phrases.is_empty()
+    }
+
+    fn values(&self, field: Field, negated: bool) -> impl Iterator<Item = &str> {
+        self.filters
+            .iter()
+            .filter(move |f| f.field == field && f.negated == negated)
+            .map(|f| f.value.as_str())
+    }
+
+    /// Whether a value passes the filters on `field`, at least one of the
+    /// positive filters has to match and none of the negated ones
+    pub fn allows(&self, field: Field, value: &str) -> bool {
+        let matches = |filter: &str| field_matches(field, filter, value);
+
+        let mut wanted = self.values(field, false).peekable();
+        let included = wanted.peek().is_none() || wanted.any(matches);
+        let excluded = self.values(field, true).any(matches);
+
+        included && !excluded
+    }
+
+    /// Like [`Self::allows`], for something with several values on
+    /// `field`: a positive filter has to match one of them, and a negated
+    /// one none of them
+    pub fn allows_any<'v>(
+        &self,
+        field: Field,
+        values: impl IntoIterator<Item = &'v str> + Clone,
+    ) -> bool {
+        let matches = |filter: &str| {
+            values
+                .clone()
+                .into_iter()
+                .any(|value| field_matches(field, filter, value))
+        };
+
+        let mut wanted = self.values(field, false).peekable();
+        let included = wanted.peek().is_none() || wanted.any(matches);
+        let excluded = self.values(field, true).any(matches);
+
+        included && !excluded
+    }
+
+    /// Whether something last changed at `unix_secs` passes `since:`, with
+    /// the current time as `now`
+    pub fn allows_time(&self, unix_secs: Option<i64>, now: i64) -> bool {
+        match (self.since_secs, unix_secs) {
+            (None, _) => true,
+            (Some(since), Some(time)) => time >= now - since as i64,
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Whether `text` has every phrase and none of the excluded text in it,
+    /// ignoring case
+    pub fn allows_text(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.phrases
+            .iter()
+            .all(|phrase| text.contains(&phrase.to_lowercase()))
+            && !self
+                .excluded
+                .iter()
+                .any(|excluded| text.contains(&excluded.to_lowercase()))
+    }
+}
+
+fn field_matches(field: Field, filter: &str, value: &str) -> bool {
+    match field {
+        Field::Lang => filter.eq_ignore_ascii_case(value),
+        // `path:src/indexes` matches anything under it, as does
+        // `path:indexes/caching.rs`
+        Field::Path => value.contains(filter.trim_start_matches("./")),
+        Field::Repo | Field::Author => value.to_lowercase().contains(&filter.to_lowercase()),
+        // `owner:search` matches `@acme/search`
+        Field::Owner => value
+            .trim_start_matches('@')
+            .to_lowercase()
+            .contains(&filter.trim_start_matches('@').to_lowercase()),
+        Field::Since | Field::Sort => false,
+    }
+}
+
+/// `30d`, `2w`, `6m` or `1y`, in seconds
+fn parse_age(value: &str) -> Option<u64> {
+    const DAY: u64 = 24 * 60 * 60;
+
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let (count, unit) = value.split_at(split);
+    let unit = match unit.to_ascii_lowercase().as_str() {
+        "h" => 60 * 60,
+        "d" => DAY,
+        "w" => 7 * DAY,
+        "m" => 30 * DAY,
+        "y" => 365 * DAY,
+        _ => return None,
+    };
+
+    count.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+pub fn parse(query: &str) -> Result<ParsedQuery, QueryError> {
+    let mut parsed = ParsedQuery::default();
+    let mut tokens = Tokens::new(query);
+
+    while let Some(token) = tokens.next()? {
+        match token {
+            Token::Filter {
+                field: field @ (Field::Since | Field::Sort),
+                value,
+                negated,
+                position,
+            } => {
+                let invalid = |expected| QueryError::InvalidValue {
+                    field,
+                    value: value.clone(),
+                    position,
+                    expected,
+                };
+
+                if field == Field::Since {
+                    const EXPECTED: &str = "an age like `30d`, `2w`, `6m` or `1y`";
+                    match parse_age(&value) {
+                        Some(secs) if !negated => parsed.since_secs = Some(secs),
+                        _ => return Err(invalid(EXPECTED)),
+                    }
+                } else {
+                    const EXPECTED: &str = "`recent` or `relevance`";
+                    parsed.sort = match value.to_ascii_lowercase().as_str() {
+                        "recent" if !negated => SortOrder::Recent,
+                        "relevance" if !negated => SortOrder::Relevance,
+                        _ => return Err(invalid(EXPECTED)),
+                    };
+                }
+            }
+            Token::Filter {
+                field,
+                value,
+                negated,
+                ..
+            } => parsed.filters.push(Filter {
+                field,
+                value,
+                negated,
+            }),
+            Token::Phrase { text, negated: true } | Token::Word { text, negated: true } => {
+                parsed.excluded.push(text)
+            }
+            Token::Phrase {
+                text,
+                negated: false,
+            } => parsed.phrases.push(text),
+            Token::Word {
+                text,
+                negated: false,
+            } => parsed.terms.push(text),
+        }
+    }
+
+    let searches_for_something = parsed.has_text()
+        || parsed.filters.iter().any(|f| !f.negated)
+        || parsed.since_secs.is_some();
+    if !searches_for_something {
+        return Err(if parsed.excluded.is_empty() && parsed.filters.is_empty() {
+            QueryError::Empty
+        } else {
+            QueryError::OnlyNegations
+        });
+    }
+
+    Ok(parsed)
+}
+
+enum Token {
+    Filter {
+        field: Field,
+        value: String,
+        negated: bool,
+        position: usize,
+    },
+    Phrase {
+        text: String,
+        negated: bool,
+    },
+    Word {
+        text: String,
+        negated: bool,
+    },
+}
+
+struct Tokens<'a> {
+    query: &'a str,
+    position: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(query: &'a str) -> Self {
+        Self { query, position: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.query[self.position..]
+    }
+
+    fn next(&mut self) -> Result<Option<Token>, QueryError> {
+        let trimmed = self.rest().trim_start();
+        self.position = self.query.len() - trimmed.len();
+        if trimmed.is_empty() {
+            return Ok(None);
+        }
+
+        let start = self.position;
+        let negated = trimmed.starts_with('-')
+            && trimmed[1..].starts_with(|c: char| !c.is_whitespace());
+        if negated {
+            self.position += 1;
+        }
+
+        if self.rest().starts_with('"') {
+            let text = self.quoted()?;
+            return Ok(Some(Token::Phrase { text, negated }));
+        }
+
+        let word_end = self
+            .rest()
+            .find(char::is_whitespace)
+            .unwrap_or(self.rest().len());
+        let word = &self.rest()[..word_end];
+
+        // `std::fmt`, `http://` and `TODO:` are text, not filters
+        if let Some((name, value)) = word.split_once(':') {
+            let field = Some(name)
+                .filter(|name| name.chars().all(|c| c.is_ascii_alphabetic()))
+                .filter(|_| !value.starts_with([':', '/']))
+                .and_then(Field::parse);
+
+            if let Some(field) = field {
+                self.position += name.len() + 1;
+                let value = if self.rest().starts_with('"') {
+                    self.quoted()?
+                } else {
+                    self.position += value.len();
+                    value.to_owned()
+                };
+
+                if value.is_empty() {
+                    return Err(QueryError::MissingValue {
+                        field,
+                        position: start,
+                    });
+                }
+
+                return Ok(Some(Token::Filter {
+                    field,
+                    value,
+                    negated,
+                    position: start,
+                }));
+            }
+        }
+
+        self.position += word.len();
+        Ok(Some(Token::Word {
+            text: word.to_owned(),
+            negated,
+        }))
+    }
+
+    /// Consume a quoted string starting at the current position, `\"`
+    /// escapes a quote
+    fn quoted(&mut self) -> Result<String, QueryError> {
+        let start = self.position;
+        let mut text = String::new();
+        let mut chars = self.rest().char_indices().skip(1);
+
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    if let Some((_, escaped)) = chars.next() {
+                        text.push(escaped);
+                    }
+                }
+                '"' => {
+                    self.position += idx + 1;
+                    return Ok(text);
+                }
+                c => text.push(c),
+            }
+        }
+
+        Err(QueryError::UnterminatedQuote { position: start })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(field: Field, value: &str, negated: bool) -> Filter {
+        Filter {
+            field,
+            value: value.to_owned(),
+            negated,
+        }
+    }
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_queries() {
+        let cases = [
+            (
+                "embed queue",
+                ParsedQuery {
+                    terms: words(&["embed", "queue"]),
+                    ..Default::default()
+                },
+            ),
+            (
+                "lang:rust -path:tests retry",
+                ParsedQuery {
+                    filters: vec![
+                        filter(Field::Lang, "rust", false),
+                        filter(Field::Path, "tests", true),
+                    ],
+                    terms: words(&["retry"]),
+                    ..Default::default()
+                },
+            ),
+            (
+                "LANGUAGE:go team:@acme/search",
+                ParsedQuery {
+                    filters: vec![
+                        filter(Field::Lang, "go", false),
+                        filter(Field::Owner, "@acme/search", false),
+                    ],
+                    ..Default::default()
+                },
+            ),
+            (
+                r#"path:"src/my dir" "exact phrase" -"not this" -skip"#,
+                ParsedQuery {
+                    filters: vec![filter(Field::Path, "src/my dir", false)],
+                    phrases: words(&["exact phrase"]),
+                    excluded: words(&["not this", "skip"]),
+                    ..Default::default()
+                },
+            ),
+            (
+                r#""say \"hi\"""#,
+                ParsedQuery {
+                    phrases: words(&["say \"hi\""]),
+                    ..Default::default()
+                },
+            ),
+            (
+                "since:2w sort:recent flaky",
+                ParsedQuery {
+                    terms: words(&["flaky"]),
+                    since_secs: Some(14 * 24 * 60 * 60),
+                    sort: SortOrder::Recent,
+                    ..Default::default()
+                },
+            ),
+            (
+                "std::fmt http://example.com a - b",
+                ParsedQuery {
+                    terms: words(&["std::fmt", "http://example.com", "a", "-", "b"]),
+                    ..Default::default()
+                },
+            ),
+            // unknown fields are text
+            (
+                "TODO:fix note: error:E0308 x1:y",
+                ParsedQuery {
+                    terms: words(&["TODO:fix", "note:", "error:E0308", "x1:y"]),
+                    ..Default::default()
+                },
+            ),
+            (
+                "-TODO:later lang:rust",
+                ParsedQuery {
+                    filters: vec![filter(Field::Lang, "rust", false)],
+                    excluded: words(&["TODO:later"]),
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        for (query, expected) in cases {
+            assert_eq!(parse(query), Ok(expected), "{query}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_queries() {
+        let cases = [
+            ("", QueryError::Empty),
+            ("   ", QueryError::Empty),
+            ("-foo -lang:rust", QueryError::OnlyNegations),
+            (
+                "retry lang:",
+                QueryError::MissingValue {
+                    field: Field::Lang,
+                    position: 6,
+                },
+            ),
+            (
+                r#"path:"src"#,
+                QueryError::UnterminatedQuote { position: 5 },
+            ),
+            (r#"foo "bar"#, QueryError::UnterminatedQuote { position: 4 }),
+            (
+                "since:soon",
+                QueryError::InvalidValue {
+                    field: Field::Since,
+                    value: "soon".to_owned(),
+                    position: 0,
+                    expected: "an age like `30d`, `2w`, `6m` or `1y`",
+                },
+            ),
+            (
+                "x sort:oldest",
+                QueryError::InvalidValue {
+                    field: Field::Sort,
+                    value: "oldest".to_owned(),
+                    position: 2,
+                    expected: "`recent` or `relevance`",
+                },
+            ),
+        ];
+
+        for (query, expected) in cases {
+            assert_eq!(parse(query), Err(expected), "{query}");
+        }
+    }
+
+    #[test]
+    fn filters_values() {
+        let cases = [
+            (
+                "path:src/indexes",
+                Field::Path,
+                "src/indexes/caching.rs",
+                true,
+            ),
+            ("path:./src", Field::Path, "src/lib.rs", true),
+            ("x -path:tests", Field::Path, "tests/parser.rs", false),
+            ("x -path:tests", Field::Path, "src/parser.rs", true),
+            ("lang:Rust", Field::Lang, "rust", true),
+            ("lang:rust lang:go", Field::Lang, "go", true),
+            ("lang:rust", Field::Lang, "python", false),
+            ("owner:search", Field::Owner, "@acme/search", true),
+            ("owner:@acme/search", Field::Owner, "@acme/search", true),
+            ("repo:Sidecar", Field::Repo, "local//code/sidecar", true),
+            ("foo", Field::Lang, "anything", true),
+        ];
+
+        for (query, field, value, allowed) in cases {
+            let parsed = parse(query).unwrap();
+            assert_eq!(parsed.allows(field, value), allowed, "{query} on {value}");
+        }
+    }
+
+    #[test]
+    fn parses_ages() {
+        let cases = [
+            ("12h", Some(12 * 60 * 60)),
+            ("30d", Some(30 * 24 * 60 * 60)),
+            ("1Y", Some(365 * 24 * 60 * 60)),
+            ("d", None),
+            ("30", None),
+            ("30x", None),
+            ("99999999999999999999y", None),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(parse_age(value), expected, "{value}");
+        }
+    }
+}
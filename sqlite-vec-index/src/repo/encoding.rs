@@ -0,0 +1,327 @@
+/*
+Files in a repository are not always valid UTF-8: older sources are often
+Latin-1 and Windows resource files tend to be UTF-16. We normalise all of
+them to UTF-8 here so chunking works on a `&str`, and keep enough
+information around to map offsets in the decoded text back to offsets in
+the original file on disk.
+*/
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16_LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16_BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+// How many bytes we look at when sniffing for BOM-less UTF-16 or binary data
+const SNIFF_LEN: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SourceEncoding {
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl SourceEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Utf8Bom => "utf-8-bom",
+            Self::Utf16Le => "utf-16le",
+            Self::Utf16Be => "utf-16be",
+            Self::Latin1 => "latin-1",
+        }
+    }
+}
+
+/// Maps byte offsets in the decoded UTF-8 buffer back to byte offsets in
+/// the original file.
+///
+/// We only store anchors where the two stop moving in lockstep, so a plain
+/// UTF-8 file has no anchors at all and a Latin-1 file only has anchors
+/// around its non-ASCII characters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OffsetMap {
+    // (decoded_offset, original_offset), sorted by decoded_offset
+    anchors: Vec<(u32, u32)>,
+}
+
+impl OffsetMap {
+    pub fn identity() -> Self {
+        Self::default()
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.anchors.is_empty()
+    }
+
+    /// Translate a byte offset in the decoded buffer into the byte offset
+    /// of the same position in the file on disk.
+    pub fn original_offset(&self, decoded: usize) -> usize {
+        let decoded = decoded as u32;
+        let idx = self.anchors.partition_point(|(d, _)| *d <= decoded);
+        if idx == 0 {
+            return decoded as usize;
+        }
+
+        let (anchor_decoded, anchor_original) = self.anchors[idx - 1];
+        (anchor_original + (decoded - anchor_decoded)) as usize
+    }
+
+    fn push(&mut self, decoded: usize, original: usize) {
+        let anchor = (decoded as u32, original as u32);
+        if self.anchors.last() != Some(&anchor) {
+            self.anchors.push(anchor);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DecodedBuffer {
+    pub text: String,
+    pub encoding: SourceEncoding,
+    pub offsets: OffsetMap,
+}
+
+/// Decode the raw contents of a file into UTF-8.
+///
+/// Returns `None` for content which looks binary, so the caller can skip it
+/// the same way it skipped unreadable files before.
+pub fn decode(bytes: Vec<u8>) -> Option<DecodedBuffer> {
+    if let Some(body) = bytes.strip_prefix(UTF8_BOM) {
+        // a BOM followed by invalid UTF-8 is treated like any other file
+        // which isn't valid UTF-8, further down
+        if let Ok(text) = std::str::from_utf8(body) {
+            let mut offsets = OffsetMap::identity();
+            offsets.push(0, UTF8_BOM.len());
+            return Some(DecodedBuffer {
+                text: text.to_owned(),
+                encoding: SourceEncoding::Utf8Bom,
+                offsets,
+            });
+        }
+    }
+
+    if bytes.starts_with(UTF16_LE_BOM) {
+        return decode_utf16(&bytes, UTF16_LE_BOM.len(), SourceEncoding::Utf16Le);
+    }
+
+    if bytes.starts_with(UTF16_BE_BOM) {
+        return decode_utf16(&bytes, UTF16_BE_BOM.len(), SourceEncoding::Utf16Be);
+    }
+
+    if let Some(encoding) = sniff_utf16(&bytes) {
+        return decode_utf16(&bytes, 0, encoding);
+    }
+
+    let bytes = match String::from_utf8(bytes) {
+        Ok(text) => {
+            return Some(DecodedBuffer {
+                text,
+                encoding: SourceEncoding::Utf8,
+                offsets: OffsetMap::identity(),
+            })
+        }
+        Err(err) => err.into_bytes(),
+    };
+
+    if looks_binary(&bytes) {
+        return None;
+    }
+
+    let skip = if bytes.starts_with(UTF8_BOM) {
+        UTF8_BOM.len()
+    } else {
+        0
+    };
+    Some(decode_latin1(&bytes, skip))
+}
+
+fn decode_utf16(bytes: &[u8], skip: usize, encoding: SourceEncoding) -> Option<DecodedBuffer> {
+    let body = &bytes[skip..];
+    let units = body.chunks_exact(2).map(|pair| match encoding {
+        SourceEncoding::Utf16Be => u16::from_be_bytes([pair[0], pair[1]]),
+        _ => u16::from_le_bytes([pair[0], pair[1]]),
+    });
+
+    let mut text = String::with_capacity(body.len() / 2);
+    let mut offsets = OffsetMap::identity();
+    let mut original = skip;
+
+    for decoded in char::decode_utf16(units) {
+        let (ch, width) = match decoded {
+            Ok(ch) => (ch, ch.len_utf16() * 2),
+            Err(_) => (char::REPLACEMENT_CHARACTER, 2),
+        };
+
+        // a NUL in the middle of UTF-16 text is as good a sign of binary
+        // data as it is for UTF-8
+        if ch == '\0' {
+            return None;
+        }
+
+        // no two characters line up byte for byte in UTF-16, so every
+        // character gets its own anchor
+        offsets.push(text.len(), original);
+        text.push(ch);
+        original += width;
+    }
+
+    Some(DecodedBuffer {
+        text,
+        encoding,
+        offsets,
+    })
+}
+
+fn decode_latin1(bytes: &[u8], skip: usize) -> DecodedBuffer {
+    let mut text = String::with_capacity(bytes.len());
+    let mut offsets = OffsetMap::identity();
+    if skip > 0 {
+        offsets.push(0, skip);
+    }
+    let mut needs_anchor = false;
+
+    for (original, &byte) in bytes.iter().enumerate().skip(skip) {
+        if byte.is_ascii() {
+            // ASCII is 1:1 between the two encodings, we only have to
+            // re-anchor right after a multi-byte character
+            if needs_anchor {
+                offsets.push(text.len(), original);
+                needs_anchor = false;
+            }
+        } else {
+            offsets.push(text.len(), original);
+            needs_anchor = true;
+        }
+        text.push(byte as char);
+    }
+
+    DecodedBuffer {
+        text,
+        encoding: SourceEncoding::Latin1,
+        offsets,
+    }
+}
+
+/// BOM-less UTF-16 shows up as ASCII text with every other byte being NUL,
+/// so look at which half of the code units is mostly zero.
+fn sniff_utf16(bytes: &[u8]) -> Option<SourceEncoding> {
+    let sample = &bytes[..bytes.len().min(SNIFF_LEN)];
+    if sample.len() < 4 || sample.len() % 2 != 0 {
+        return None;
+    }
+
+    let units = sample.len() / 2;
+    let even_zeros = sample.iter().step_by(2).filter(|b| **b == 0).count();
+    let odd_zeros = sample.iter().skip(1).step_by(2).filter(|b| **b == 0).count();
+
+    // at least 80% of one half is NUL and (almost) none of the other
+    if odd_zeros * 5 >= units * 4 && even_zeros * 20 <= units {
+        Some(SourceEncoding::Utf16Le)
+    } else if even_zeros * 5 >= units * 4 && odd_zeros * 20 <= units {
+        Some(SourceEncoding::Utf16Be)
+    } else {
+        None
+    }
+}
+
+fn looks_binary(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(SNIFF_LEN)];
+    sample.contains(&0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(parts: &[&[u8]]) -> Vec<u8> {
+        parts.concat()
+    }
+
+    #[test]
+    fn decodes_buffers() {
+        let cases = [
+            (
+                bytes(&[b"fn main() {}"]),
+                Some((SourceEncoding::Utf8, "fn main() {}")),
+            ),
+            (
+                bytes(&[UTF8_BOM, b"hi"]),
+                Some((SourceEncoding::Utf8Bom, "hi")),
+            ),
+            (
+                bytes(&[UTF16_LE_BOM, b"h\0i\0"]),
+                Some((SourceEncoding::Utf16Le, "hi")),
+            ),
+            (
+                bytes(&[UTF16_BE_BOM, b"\0h\0i"]),
+                Some((SourceEncoding::Utf16Be, "hi")),
+            ),
+            // no BOM, but every other byte is NUL
+            (
+                bytes(&[b"a\0b\0c\0d\0"]),
+                Some((SourceEncoding::Utf16Le, "abcd")),
+            ),
+            (
+                bytes(&[b"\0a\0b\0c\0d"]),
+                Some((SourceEncoding::Utf16Be, "abcd")),
+            ),
+            (
+                bytes(&[b"caf\xe9"]),
+                Some((SourceEncoding::Latin1, "caf\u{e9}")),
+            ),
+            // a UTF-8 BOM doesn't make the rest valid UTF-8
+            (
+                bytes(&[UTF8_BOM, b"a\xe9"]),
+                Some((SourceEncoding::Latin1, "a\u{e9}")),
+            ),
+            (bytes(&[b"\x89PNG\r\n\x1a\n\0\0"]), None),
+            (bytes(&[UTF16_LE_BOM, b"a\0\0\0"]), None),
+        ];
+
+        for (input, expected) in cases {
+            let decoded = decode(input.clone());
+            assert_eq!(
+                decoded
+                    .as_ref()
+                    .map(|buf| (buf.encoding, buf.text.as_str())),
+                expected,
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn maps_offsets() {
+        let cases = [
+            (bytes(&[b"abc"]), vec![(0, 0), (2, 2)]),
+            (bytes(&[UTF8_BOM, b"abc"]), vec![(0, 3), (2, 5)]),
+            // `é` is one byte on disk and two decoded
+            (bytes(&[b"caf\xe9 x"]), vec![(2, 2), (3, 3), (5, 4), (6, 5)]),
+            (bytes(&[UTF8_BOM, b"a\xe9b"]), vec![(0, 3), (1, 4), (3, 5)]),
+            (
+                bytes(&[UTF16_LE_BOM, b"h\0\xe9\0"]),
+                vec![(0, 2), (1, 4), (3, 6)],
+            ),
+            // a surrogate pair, four bytes either way
+            (
+                bytes(&[UTF16_LE_BOM, b"\x3d\xd8\x00\xdea\0"]),
+                vec![(0, 2), (4, 6)],
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let decoded = decode(input.clone()).unwrap();
+            for (offset, original) in expected {
+                assert_eq!(
+                    decoded.offsets.original_offset(offset),
+                    original,
+                    "{input:?} at {offset}"
+                );
+            }
+        }
+
+        assert!(decode(b"abc".to_vec()).unwrap().offsets.is_identity());
+    }
+}
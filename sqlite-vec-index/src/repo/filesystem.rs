@@ -3,45 +3,250 @@ We are going to look at the file system and iterate along all the files
 which might be present here
 */
 
-use std::path::{Path, PathBuf};
+use std::{
+    collections::{hash_map, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
-use ignore::WalkBuilder;
+use ignore::{DirEntry, WalkBuilder};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
 
 use crate::{
     application::background::SyncPipes,
+    metrics::{SkipReason, METRICS},
     repo::iterator::{RepositoryDirectory, RepositoryFile},
 };
 
-use super::iterator::{should_index_entry, FileSource, RepoDirectoryEntry};
+use super::{
+    encoding::decode,
+    iterator::{should_index_entry, FileSource, RepoDirectoryEntry},
+};
 
 pub const AVG_LINE_LEN: u64 = 30;
 pub const MAX_LINE_COUNT: u64 = 20000;
 pub const MAX_FILE_LEN: u64 = AVG_LINE_LEN * MAX_LINE_COUNT;
 
+/// Notebooks carry their outputs, often images, which are dropped before
+/// they're indexed, so they're allowed to be much larger
+pub const MAX_NOTEBOOK_LEN: u64 = 8 * MAX_FILE_LEN;
+
+fn max_file_len(path: &Path) -> u64 {
+    match path.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("ipynb") => MAX_NOTEBOOK_LEN,
+        _ => MAX_FILE_LEN,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Never follow symlinks
+    Never,
+
+    /// Follow symlinks which resolve to a path inside the repository root
+    #[default]
+    WithinRepo,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SubmodulePolicy {
+    /// Leave submodules and nested repositories out of the index
+    Skip,
+
+    /// Index submodules and nested repositories as their own `RepoRef`
+    #[default]
+    IndexSeparately,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalkPolicy {
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    #[serde(default)]
+    pub submodules: SubmodulePolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NestedKind {
+    /// A git submodule, its `.git` is a file pointing into the parent repo
+    Submodule,
+    /// A full repository checked out inside another one
+    Repository,
+}
+
+impl NestedKind {
+    fn detect(dir: &Path) -> Option<Self> {
+        let metadata = std::fs::symlink_metadata(dir.join(".git")).ok()?;
+        if metadata.is_file() {
+            Some(Self::Submodule)
+        } else if metadata.is_dir() {
+            Some(Self::Repository)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NestedRepository {
+    pub disk_path: PathBuf,
+    pub kind: NestedKind,
+}
+
 pub struct FileWalker {
     pub file_list: Vec<PathBuf>,
+    pub nested: Vec<NestedRepository>,
 }
 
 impl FileWalker {
-    pub fn index_directory(dir: impl AsRef<Path>) -> FileWalker {
+    pub fn index_directory(dir: impl AsRef<Path>, policy: &WalkPolicy) -> FileWalker {
+        let dir = dir.as_ref();
+        let nested = Arc::new(Mutex::new(vec![]));
+
+        let root = canonical_root(dir);
+
         // note: this WILL observe .gitignore files for the respective repos.
-        let walker = WalkBuilder::new(&dir)
-            .standard_filters(true)
-            .hidden(false)
-            .filter_entry(should_index_entry)
+        let walker = walk_builder(dir, policy)
+            .filter_entry({
+                let filter = RepoBoundary::new(dir, policy.symlinks, nested.clone());
+                move |de| should_index_entry(de) && filter.allows(de)
+            })
             .build();
 
-        let file_list = walker
+        // the same file can be reachable through several symlinks, we only
+        // want to index it once, and under its own path if we walk past it
+        let mut seen = HashMap::new();
+        let mut file_list: Vec<PathBuf> = vec![];
+        let entries = walker
             .filter_map(|de| match de {
                 Ok(de) => Some(de),
-                Err(_) => None,
+                Err(err) => {
+                    // this also catches symlink cycles, which `ignore` detects
+                    // for us while following links
+                    debug!(%err, "skipping entry");
+                    None
+                }
             })
             // Preliminarily ignore files that are very large, without reading the contents.
-            .filter(|de| matches!(de.metadata(), Ok(meta) if meta.len() < MAX_FILE_LEN))
-            .filter_map(|de| std::fs::canonicalize(de.into_path()).ok())
-            .collect();
+            .filter(|de| matches!(de.metadata(), Ok(meta) if meta.len() < max_file_len(de.path())));
+
+        for de in entries {
+            let Ok(canonical) = std::fs::canonicalize(de.path()) else {
+                continue;
+            };
 
-        Self { file_list }
+            // keep the path as walked rather than canonical, so it is always
+            // relative to `dir` even when reached through a symlink
+            let path = de.into_path();
+            let direct = path
+                .strip_prefix(dir)
+                .is_ok_and(|relative| root.join(relative) == canonical);
+
+            match seen.entry(canonical) {
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert((file_list.len(), direct));
+                    file_list.push(path);
+                }
+                hash_map::Entry::Occupied(mut entry) => {
+                    let (idx, seen_direct) = entry.get_mut();
+                    if direct && !*seen_direct {
+                        file_list[*idx] = path;
+                        *seen_direct = true;
+                    }
+                }
+            }
+        }
+
+        let nested = std::mem::take(&mut *nested.lock().unwrap());
+        Self { file_list, nested }
+    }
+}
+
+/// Find the submodules and nested repositories below `dir` without reading
+/// any of the files.
+pub fn nested_repositories(dir: impl AsRef<Path>, policy: &WalkPolicy) -> Vec<NestedRepository> {
+    let dir = dir.as_ref();
+    let nested = Arc::new(Mutex::new(vec![]));
+
+    walk_builder(dir, policy)
+        .filter_entry({
+            let filter = RepoBoundary::new(dir, policy.symlinks, nested.clone());
+            move |de| de.file_type().is_some_and(|ft| ft.is_dir()) && filter.allows(de)
+        })
+        .build()
+        .for_each(drop);
+
+    let nested = std::mem::take(&mut *nested.lock().unwrap());
+    nested
+}
+
+fn walk_builder(dir: &Path, policy: &WalkPolicy) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(dir);
+    builder
+        .standard_filters(true)
+        .hidden(false)
+        .follow_links(policy.symlinks == SymlinkPolicy::WithinRepo);
+    builder
+}
+
+fn canonical_root(dir: &Path) -> PathBuf {
+    std::fs::canonicalize(dir).unwrap_or_else(|_| dir.to_owned())
+}
+
+/// Keeps a walk inside a single repository: symlinks may not escape the
+/// repository root, or are skipped altogether, and we don't descend into
+/// submodules or nested repositories but record them instead.
+struct RepoBoundary {
+    root: PathBuf,
+    symlinks: SymlinkPolicy,
+    nested: Arc<Mutex<Vec<NestedRepository>>>,
+}
+
+impl RepoBoundary {
+    fn new(dir: &Path, symlinks: SymlinkPolicy, nested: Arc<Mutex<Vec<NestedRepository>>>) -> Self {
+        Self {
+            root: canonical_root(dir),
+            symlinks,
+            nested,
+        }
+    }
+
+    fn allows(&self, de: &DirEntry) -> bool {
+        // the repository root may well be a link itself
+        if de.depth() > 0 && de.path_is_symlink() {
+            // not following links only keeps the walk out of linked
+            // directories, a linked file would still be read through the link
+            if self.symlinks == SymlinkPolicy::Never {
+                debug!(path = ?de.path(), "skipping symlink");
+                return false;
+            }
+
+            match std::fs::canonicalize(de.path()) {
+                Ok(target) if target.starts_with(&self.root) => {}
+                _ => {
+                    debug!(path = ?de.path(), "skipping symlink leading outside the repository");
+                    return false;
+                }
+            }
+        }
+
+        // the root itself is obviously a repository, we only care about
+        // the ones below it
+        if de.depth() > 0 && de.file_type().is_some_and(|ft| ft.is_dir()) {
+            if let Some(kind) = NestedKind::detect(de.path()) {
+                debug!(path = ?de.path(), ?kind, "found nested repository");
+                self.nested.lock().unwrap().push(NestedRepository {
+                    disk_path: de.path().to_owned(),
+                    kind,
+                });
+                return false;
+            }
+        }
+
+        true
     }
 }
 
@@ -58,16 +263,27 @@ impl FileSource for FileWalker {
             .into_par_iter()
             .filter_map(|entry_disk_path| {
                 if entry_disk_path.is_file() {
-                    let buffer = match std::fs::read_to_string(&entry_disk_path) {
+                    let bytes = match std::fs::read(&entry_disk_path) {
                         Err(_) => {
+                            METRICS.file_skipped(SkipReason::Unreadable);
                             return None;
                         }
-                        Ok(buffer) => buffer,
+                        Ok(bytes) => bytes,
+                    };
+                    METRICS.files_scanned.inc();
+
+                    // binary files are skipped, everything else is normalised
+                    // to UTF-8 for chunking
+                    let Some(decoded) = decode(bytes) else {
+                        METRICS.file_skipped(SkipReason::Binary);
+                        return None;
                     };
                     Some(RepoDirectoryEntry::File(RepositoryFile {
-                        buffer,
+                        buffer: decoded.text,
                         path: entry_disk_path.to_string_lossy().to_string(),
                         pathbuf: entry_disk_path,
+                        encoding: decoded.encoding,
+                        offsets: decoded.offsets,
                     }))
                 } else if entry_disk_path.is_dir() {
                     Some(RepoDirectoryEntry::Dir(RepositoryDirectory {
@@ -77,7 +293,7 @@ impl FileSource for FileWalker {
                     Some(RepoDirectoryEntry::Other)
                 }
             })
-            .take_any_while(|_| !signal.is_cancelled())
+            .take_any_while(|_| signal.should_continue())
             .for_each(iterator);
     }
 }
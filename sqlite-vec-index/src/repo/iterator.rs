@@ -8,6 +8,8 @@ use std::{
 
 use crate::application::background::SyncPipes;
 
+use super::encoding::{OffsetMap, SourceEncoding};
+
 pub trait FileSource {
     fn len(&self) -> usize;
     fn for_each(self, signal: &SyncPipes, iterator: impl Fn(RepoDirectoryEntry) + Sync + Send);
@@ -29,6 +31,14 @@ impl RepoDirectoryEntry {
         }
     }
 
+    /// Offsets mapping the decoded `buffer()` back onto the file on disk
+    pub fn offsets(&self) -> Option<&OffsetMap> {
+        match self {
+            Self::File(file) => Some(&file.offsets),
+            _ => None,
+        }
+    }
+
     pub fn is_file(&self) -> bool {
         matches!(self, RepoDirectoryEntry::File(_))
     }
@@ -39,10 +49,20 @@ pub struct RepositoryDirectory {
     pub path: String,
 }
 
+#[derive(Debug)]
+pub struct RepositoryFile {
+    // always UTF-8, whatever the encoding of the file on disk
+    pub buffer: String,
+    pub path: String,
+    pub pathbuf: PathBuf,
+    pub encoding: SourceEncoding,
+    pub offsets: OffsetMap,
+}
 
 #[derive(Debug)]
 pub enum RepoDirectoryEntry {
     Dir(RepositoryDirectory),
+    File(RepositoryFile),
     Other,
 }
 
@@ -1,4 +1,6 @@
+pub mod encoding;
 pub mod filesystem;
 pub mod iterator;
+pub mod ownership;
 pub mod state;
 pub mod types;
@@ -0,0 +1,395 @@
+//! Who wrote a chunk, and who owns the file it's in.
+//!
+//! Blame comes from `git blame`, which is far quicker at it than walking the
+//! history ourselves, and owners from the repository's CODEOWNERS file.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use regex::Regex;
+use tracing::{debug, warn};
+
+/// Where GitHub and GitLab look for CODEOWNERS, in order
+const CODEOWNERS_PATHS: [&str; 3] = [".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+/// The last change to a chunk
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Blame {
+    pub commit_hash: String,
+    pub author_name: String,
+    pub author_email: String,
+    /// Unix seconds
+    pub timestamp: i64,
+}
+
+/// The blame of every line of a file, 0-based
+#[derive(Debug, Default)]
+pub struct FileBlame {
+    lines: Vec<Option<Arc<Blame>>>,
+}
+
+impl FileBlame {
+    /// Blame `relative_path` as it is in the working copy. Lines which
+    /// aren't committed yet have no blame.
+    pub fn read(disk_path: &Path, relative_path: &str) -> anyhow::Result<Self> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(disk_path)
+            .args(["blame", "--line-porcelain", "--", relative_path])
+            .output()
+            .context("failed to run git blame")?;
+
+        if !output.status.success() {
+            anyhow::bail!(
+                "git blame failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(Self::parse(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    fn parse(porcelain: &str) -> Self {
+        let mut lines = vec![];
+        let mut current: Option<Blame> = None;
+        // the same commit shows up for many lines, so they share an entry
+        let mut last: Option<Arc<Blame>> = None;
+
+        for line in porcelain.lines() {
+            if line.starts_with('\t') {
+                let blame = current.take().filter(|blame| {
+                    // `git blame` reports uncommitted lines under the null
+                    // hash
+                    !blame.commit_hash.bytes().all(|b| b == b'0')
+                });
+                let shared = match (blame, &last) {
+                    (Some(blame), Some(last)) if **last == blame => Some(last.clone()),
+                    (Some(blame), _) => Some(Arc::new(blame)),
+                    (None, _) => None,
+                };
+                if shared.is_some() {
+                    last = shared.clone();
+                }
+                lines.push(shared);
+                continue;
+            }
+
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match &mut current {
+                None if key.len() == 40 && key.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    current = Some(Blame {
+                        commit_hash: key.to_owned(),
+                        author_name: String::new(),
+                        author_email: String::new(),
+                        timestamp: 0,
+                    });
+                }
+                Some(blame) => match key {
+                    "author" => blame.author_name = value.to_owned(),
+                    "author-mail" => {
+                        blame.author_email = value.trim_matches(['<', '>']).to_owned()
+                    }
+                    "author-time" => blame.timestamp = value.parse().unwrap_or_default(),
+                    _ => {}
+                },
+                None => {}
+            }
+        }
+
+        Self { lines }
+    }
+
+    /// The most recent change to lines `start..=end`
+    pub fn range(&self, start: u64, end: u64) -> Option<Blame> {
+        self.lines
+            .get(start as usize..=(end as usize).min(self.lines.len().saturating_sub(1)))?
+            .iter()
+            .flatten()
+            .max_by_key(|blame| blame.timestamp)
+            .map(|blame| Blame::clone(blame))
+    }
+}
+
+struct OwnerRule {
+    pattern: Regex,
+    owners: Vec<String>,
+}
+
+/// The rules of a CODEOWNERS file, where the last matching rule wins
+#[derive(Default)]
+pub struct CodeOwners {
+    rules: Vec<OwnerRule>,
+}
+
+impl CodeOwners {
+    /// Load the repository's CODEOWNERS, or no rules if it has none
+    pub fn load(disk_path: &Path) -> Self {
+        let Some(path) = CODEOWNERS_PATHS
+            .iter()
+            .map(|path| disk_path.join(path))
+            .find(|path| path.is_file())
+        else {
+            return Self::default();
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text),
+            Err(err) => {
+                warn!(?err, ?path, "failed to read CODEOWNERS");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let rules = text
+            .lines()
+            .map(str::trim)
+            // `[Section]` headers are GitLab's, and don't match anything
+            .filter(|line| !line.is_empty() && !line.starts_with(['#', '[']))
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                let pattern = words.next()?;
+                let owners = words
+                    .take_while(|word| !word.starts_with('#'))
+                    .map(str::to_owned)
+                    .collect();
+
+                match Regex::new(&pattern_regex(pattern)) {
+                    Ok(pattern) => Some(OwnerRule { pattern, owners }),
+                    Err(err) => {
+                        debug!(?err, pattern, "skipping CODEOWNERS rule");
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        Self { rules }
+    }
+
+    pub fn owners(&self, relative_path: &str) -> Vec<String> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.pattern.is_match(relative_path))
+            .map(|rule| rule.owners.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Turn a gitignore style pattern into a regex over relative paths
+fn pattern_regex(pattern: &str) -> String {
+    let anchored = pattern.trim_end_matches('/').contains('/');
+    let pattern = pattern.trim_start_matches('/');
+
+    let mut regex = String::from(if anchored { "^" } else { "(^|/)" });
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` matches any number of directories, including none
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    // a pattern matches the path itself, or anything under it
+    if !regex.ends_with('/') {
+        regex.push_str("(/|$)");
+    }
+
+    regex
+}
+
+/// Blame and owners for the files of one repository
+pub struct Ownership {
+    disk_path: PathBuf,
+    owners: CodeOwners,
+}
+
+impl Ownership {
+    pub fn load(disk_path: &Path) -> Self {
+        Self {
+            disk_path: disk_path.to_owned(),
+            owners: CodeOwners::load(disk_path),
+        }
+    }
+
+    pub fn owners(&self, relative_path: &str) -> Vec<String> {
+        self.owners.owners(relative_path)
+    }
+
+    pub async fn blame(&self, relative_path: &str) -> Option<FileBlame> {
+        let disk_path = self.disk_path.clone();
+        let path = relative_path.to_owned();
+        let blame = tokio::task::spawn_blocking(move || FileBlame::read(&disk_path, &path)).await;
+
+        match blame {
+            Ok(Ok(blame)) => Some(blame),
+            Ok(Err(err)) => {
+                debug!(?err, relative_path, "no blame for file");
+                None
+            }
+            Err(err) => {
+                warn!(?err, relative_path, "blame task failed");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIRST: &str = "1111111111111111111111111111111111111111";
+    const SECOND: &str = "2222222222222222222222222222222222222222";
+    const UNCOMMITTED: &str = "0000000000000000000000000000000000000000";
+
+    fn porcelain(hash: &str, author: &str, time: i64, content: &str) -> String {
+        format!(
+            "{hash} 1 1 1\n\
+             author {author}\n\
+             author-mail <{author}@example.com>\n\
+             author-time {time}\n\
+             author-tz +0000\n\
+             summary change\n\
+             filename src/lib.rs\n\
+             \t{content}\n"
+        )
+    }
+
+    #[test]
+    fn parses_blame() {
+        let blame = FileBlame::parse(
+            &[
+                porcelain(FIRST, "alice", 100, "fn a() {}"),
+                porcelain(UNCOMMITTED, "Not Committed Yet", 300, "fn b() {}"),
+                porcelain(FIRST, "alice", 100, "fn c() {}"),
+                porcelain(SECOND, "bob", 200, "fn d() {}"),
+            ]
+            .concat(),
+        );
+
+        let cases = [
+            (0, Some(FIRST)),
+            (1, None),
+            (2, Some(FIRST)),
+            (3, Some(SECOND)),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(
+                blame.lines[line]
+                    .as_ref()
+                    .map(|blame| blame.commit_hash.as_str()),
+                expected,
+                "line {line}"
+            );
+        }
+
+        // lines of the same commit share their blame
+        assert!(Arc::ptr_eq(
+            blame.lines[0].as_ref().unwrap(),
+            blame.lines[2].as_ref().unwrap()
+        ));
+        assert_eq!(
+            blame.range(0, 0),
+            Some(Blame {
+                commit_hash: FIRST.to_owned(),
+                author_name: "alice".to_owned(),
+                author_email: "alice@example.com".to_owned(),
+                timestamp: 100,
+            })
+        );
+
+        let cases = [
+            ((0, 2), Some(FIRST)),
+            ((1, 1), None),
+            ((0, 3), Some(SECOND)),
+            ((2, 10), Some(SECOND)),
+            ((10, 12), None),
+        ];
+        for ((start, end), expected) in cases {
+            assert_eq!(
+                blame.range(start, end).map(|blame| blame.commit_hash),
+                expected.map(str::to_owned),
+                "{start}..={end}"
+            );
+        }
+    }
+
+    #[test]
+    fn matches_patterns() {
+        let cases = [
+            ("*.rs", "main.rs", true),
+            ("*.rs", "src/main.rs", true),
+            ("*.rs", "src/main.rsx", false),
+            // a leading or inner slash anchors at the root
+            ("/build", "build", true),
+            ("/build", "build/out.o", true),
+            ("/build", "src/build/out.o", false),
+            ("src/*.rs", "src/main.rs", true),
+            ("src/*.rs", "src/bin/main.rs", false),
+            ("src/*.rs", "lib/src/main.rs", false),
+            ("build", "src/build/out.o", true),
+            ("build", "builder/out.o", false),
+            // a trailing slash only matches directories
+            ("docs/", "docs/guide.md", true),
+            ("docs/", "src/docs/guide.md", true),
+            ("docs/", "docs", false),
+            ("**/tests", "tests/it.rs", true),
+            ("**/tests", "src/tests/it.rs", true),
+            ("**/tests", "src/mytests/it.rs", false),
+            ("docs/**", "docs/a/b.md", true),
+            ("docs/**", "docs", false),
+            ("src/**/mod.rs", "src/mod.rs", true),
+            ("src/**/mod.rs", "src/a/b/mod.rs", true),
+            ("?.txt", "a.txt", true),
+            ("?.txt", "ab.txt", false),
+        ];
+
+        for (pattern, path, expected) in cases {
+            let regex = Regex::new(&pattern_regex(pattern)).unwrap();
+            assert_eq!(regex.is_match(path), expected, "{pattern} {path}");
+        }
+    }
+
+    #[test]
+    fn last_rule_wins() {
+        let owners = CodeOwners::parse(
+            "# everything else\n\
+             *        @acme/everyone\n\
+             \n\
+             /docs/   @acme/docs @alice  # and the handbook\n\
+             *.rs     @acme/rust\n\
+             [Frontend]\n",
+        );
+
+        let cases = [
+            ("README.md", vec!["@acme/everyone"]),
+            ("docs/guide.md", vec!["@acme/docs", "@alice"]),
+            ("docs/example.rs", vec!["@acme/rust"]),
+            ("src/docs/guide.md", vec!["@acme/everyone"]),
+            ("src/main.rs", vec!["@acme/rust"]),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(owners.owners(path), expected, "{path}");
+        }
+
+        assert!(CodeOwners::default().owners("src/main.rs").is_empty());
+    }
+}
@@ -10,7 +10,8 @@ use anyhow::Context;
 use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 use tracing::info;
 
-use super::state::RepoError;
+use crate::semantic_search::vectors::VectorStorage;
+use super::{filesystem::WalkPolicy, state::RepoError};
 
 #[derive(Debug)]
 pub struct RepoMetadata {
@@ -163,6 +164,9 @@ pub enum SyncStatus {
     /// Active indexing in progress
     Indexing,
 
+    /// Indexing is on hold until resumed, nothing is lost
+    Paused,
+
     /// Successfully indexed
     Done,
 
@@ -185,6 +189,19 @@ pub struct Repository {
     pub sync_status: SyncStatus,
     pub last_commit_unix_secs: i64,
     pub last_index_unix_secs: u64,
+
+    /// How symlinks and nested repositories are treated while walking
+    #[serde(default)]
+    pub walk_policy: WalkPolicy,
+
+    /// How the embeddings of this repository are stored
+    #[serde(default)]
+    pub vector_storage: VectorStorage,
+
+    /// The repository this one was found nested in, if it was queued
+    /// because of it. It is removed along with its parent.
+    #[serde(default)]
+    pub parent: Option<RepoRef>,
 }
 
 impl Repository {
@@ -210,6 +227,9 @@ impl Repository {
             last_index_unix_secs: 0,
             last_commit_unix_secs: 0,
             disk_path,
+            walk_policy: Default::default(),
+            vector_storage: Default::default(),
+            parent: None,
         }
     }
 
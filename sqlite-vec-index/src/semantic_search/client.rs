@@ -1,58 +1,53 @@
-use std::{env, path::Path, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    env,
+    ops::RangeInclusive,
+    path::Path,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
-use rayon::prelude::ParallelIterator;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use tracing::{debug, error};
 
 use crate::{
     application::config::configuration::Configuration,
-    embedder::embedder::{Embedder, LocalEmbedder},
-    repo::types::RepoRef,
+    db::sqlite::SqlDb,
+    embedder::{
+        embedder::{Embedder, LocalEmbedder},
+        reranker::{CrossEncoder, Reranker},
+    },
+    metrics::METRICS,
+    query::parser::{self, Field, ParsedQuery, SortOrder},
+    repo::{
+        encoding::{self, OffsetMap},
+        types::{RepoRef, Repository},
+    },
 };
 
-use super::schema::Payload;
-
-const EMBEDDING_DIM: usize = 384;
+use super::{
+    context::{self, ContextOptions, HighlightKind},
+    documents::{self, DocumentChunk, DocumentKind},
+    query::{ModelPrefixes, QueryPipeline, QueryPlan, SynonymDictionary},
+    rerank::{self, RerankOptions},
+    schema::{ModelDescriptor, Payload, EMBEDDING_DIM},
+    vectors,
+};
 
 #[derive(Clone)]
 pub struct SemanticClient {
     embedder: Arc<dyn Embedder>,
-    //search_client: Arc<QdrantClient>,
+    reranker: Option<Arc<dyn Reranker>>,
+    query: Arc<QueryPipeline>,
+    sql: SqlDb,
     config: Arc<Configuration>,
 }
 
 impl SemanticClient {
-    pub async fn new(
-        config: Arc<Configuration>,
-    ) -> Option<Self> {
-        // let qdrant_config = QdrantClientConfig::from_url(&config.qdrant_url);
-        // let qdrant_client =
-        //     QdrantClient::new(Some(qdrant_config)).expect("client creation to not fail");
-
-        // debug!("qdrant client created");
-
-        // match qdrant_client.has_collection(&config.collection_name).await {
-        //     Ok(false) => {
-        //         let CollectionOperationResponse { result, time } =
-        //             create_collection(&config.collection_name, &qdrant_client)
-        //                 .await
-        //                 .unwrap();
-
-        //         debug!(
-        //             "Created collection {} in {}ms with result {}",
-        //             config.collection_name, time, result
-        //         );
-
-        //         assert!(result);
-        //     }
-        //     Ok(true) => {}
-        //     Err(e) => {
-        //         error!("{}", e.to_string());
-        //         return None;
-        //     }
-        // }
-
-        // TODO(skcd): we might want to create some indexes here, but we can
-        // figure that out as we keep hacking
+    pub async fn new(config: Arc<Configuration>, sql: SqlDb) -> Option<Self> {
+        // Vectors are stored in the `chunk_vectors*` tables of the same SQLite
+        // database as the caches, which the migrations have already created.
 
         // Now we first need to set the ort library up properly
         debug!("initializing ort dylib");
@@ -62,17 +57,45 @@ impl SemanticClient {
             debug!("embedder creation failed");
             return None;
         }
+
+        // re-ranking is optional, search works fine without it
+        let reranker = config.reranker_model_dir.as_ref().and_then(|model_dir| {
+            match CrossEncoder::new(model_dir) {
+                Ok(reranker) => Some(Arc::new(reranker) as Arc<dyn Reranker>),
+                Err(err) => {
+                    error!(?err, ?model_dir, "failed to load re-ranker");
+                    None
+                }
+            }
+        });
+
+        // a broken dictionary only costs us the expansion
+        let synonyms = config.synonyms_path.as_ref().and_then(|path| {
+            SynonymDictionary::load(path)
+                .map_err(|err| error!(?err, ?path, "failed to load synonyms"))
+                .ok()
+        });
+
+        let model_name = config
+            .model_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let query = QueryPipeline {
+            prefixes: ModelPrefixes::for_model(&model_name),
+            synonyms,
+            max_variants: config.query_variants,
+        };
+
         Some(Self {
             embedder: Arc::new(embedder.expect("is_err check above")),
-            //search_client: Arc::new(qdrant_client),
+            reranker,
+            query: Arc::new(query),
+            sql,
             config,
         })
     }
 
-    // pub fn qdrant_client(&self) -> &QdrantClient {
-    //     &self.search_client
-    // }
-
     pub fn collection_name(&self) -> &str {
         &self.config.collection_name
     }
@@ -85,27 +108,53 @@ impl SemanticClient {
         self.embedder.clone()
     }
 
+    pub fn has_reranker(&self) -> bool {
+        self.reranker.is_some()
+    }
+
+    /// The prefixes documents and queries are embedded with
+    pub fn prefixes(&self) -> &ModelPrefixes {
+        &self.query.prefixes
+    }
+
+    /// What we embed for `query`, and the terms to match it lexically with
+    pub fn plan_query(&self, query: &str) -> QueryPlan {
+        self.query.plan(query)
+    }
+
+    pub fn model_descriptor(&self) -> ModelDescriptor {
+        ModelDescriptor {
+            name: self
+                .config
+                .model_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            dimensions: EMBEDDING_DIM,
+        }
+    }
+
     pub async fn delete_points_for_hash(
         &self,
         repo_ref: &str,
         paths: impl Iterator<Item = String>,
     ) {
-        let repo_filter = make_kv_keyword_filter("repo_ref", repo_ref).into();
-        let file_filter = paths
-            .map(|p| make_kv_keyword_filter("content_hash", &p).into())
-            .collect::<Vec<_>>();
-
-        let selector = Filter {
-            must: vec![repo_filter],
-            should: file_filter,
-            ..Default::default()
+        let content_hashes = paths.collect::<Vec<_>>();
+        let deleted = async {
+            let mut tx = self.sql.begin().await?;
+            if content_hashes.is_empty() {
+                vectors::delete_for_repo(&mut tx, repo_ref).await?;
+            } else {
+                vectors::delete_for_content_hashes(&mut tx, repo_ref, &content_hashes).await?;
+            }
+            tx.commit().await?;
+            anyhow::Ok(())
         }
-        .into();
+        .await;
 
-        let _ = self
-            .qdrant_client()
-            .delete_points(&self.config.collection_name, &selector, None)
-            .await;
+        if let Err(err) = deleted {
+            error!(?err, repo_ref, "failed to delete vectors");
+        }
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -119,46 +168,73 @@ impl SemanticClient {
         lang_str: &'a str,
         branches: &'a [String],
         file_extension: Option<&'a str>,
+        offsets: &'a OffsetMap,
     ) -> impl ParallelIterator<Item = (String, Payload)> + 'a {
-        let spans = self
-            .language_parsing
-            .chunk_file(relative_path, buffer, file_extension);
-        debug!(chunk_count = spans.len(), relative_path, "found chunks");
-        spans.iter().for_each(|span| {
-            debug!(?span, relative_path, "span content");
+        // documentation is split by its own structure, everything else by
+        // the language parsers
+        let chunks = match DocumentKind::for_path(relative_path) {
+            Some(kind) => documents::chunk(kind, buffer),
+            None => self
+                .language_parsing
+                .chunk_file(relative_path, buffer, file_extension)
+                .into_iter()
+                .filter_map(|span| {
+                    Some(DocumentChunk {
+                        text: span.data?.to_owned(),
+                        start_line: span.start,
+                        end_line: span.end,
+                        heading_path: vec![],
+                        cell: None,
+                    })
+                })
+                .collect(),
+        };
+        debug!(chunk_count = chunks.len(), relative_path, "found chunks");
+        chunks.iter().for_each(|chunk| {
+            debug!(?chunk, relative_path, "chunk content");
         });
 
-        spans
-            .into_par_iter()
-            .filter(|span| span.data.is_some())
-            .map(move |span| {
-                let data_content = span.data.unwrap();
-                let data = format!("{repo_name}\t{relative_path}\n{}", data_content);
-                let payload = Payload {
-                    repo_name: repo_name.to_owned(),
-                    repo_ref: repo_ref.to_owned(),
-                    relative_path: relative_path.to_owned(),
-                    content_hash: file_cache_key.to_string(),
-                    text: data_content.to_owned(),
-                    lang: lang_str.to_ascii_lowercase(),
-                    branches: branches.to_owned(),
-                    start_line: span.start as u64,
-                    end_line: span.end as u64,
-                    ..Default::default()
-                };
-
-                (data, payload)
-            })
+        // the buffer might have been re-encoded to UTF-8, so byte offsets are
+        // computed on the buffer and then mapped back onto the file on disk
+        let line_starts = std::iter::once(0)
+            .chain(buffer.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect::<Vec<_>>();
+        let line_offset = move |line: usize| {
+            offsets.original_offset(line_starts.get(line).copied().unwrap_or(buffer.len()))
+        };
+
+        chunks.into_par_iter().map(move |chunk| {
+            let payload = Payload {
+                repo_name: repo_name.to_owned(),
+                repo_ref: repo_ref.to_owned(),
+                relative_path: relative_path.to_owned(),
+                content_hash: file_cache_key.to_string(),
+                lang: lang_str.to_ascii_lowercase(),
+                branches: branches.to_owned(),
+                start_line: chunk.start_line as u64,
+                end_line: chunk.end_line as u64,
+                start_byte: line_offset(chunk.start_line) as u64,
+                end_byte: line_offset(chunk.end_line + 1) as u64,
+                text: chunk.text,
+                heading_path: chunk.heading_path,
+                cell_kind: chunk.cell,
+                ..Default::default()
+            };
+            let data = chunk_data(
+                self.prefixes(),
+                repo_name,
+                relative_path,
+                &payload.embedding_text(),
+            );
+
+            (data, payload)
+        })
     }
 
     pub async fn delete_collection(&self) -> anyhow::Result<()> {
-        // There might be race conditions here with the qdrant binary and we might
-        // not end up deleting the collection which we are tracking, so we should
-        // ideally be careful about that.
-        let _ = self
-            .qdrant_client()
-            .delete_collection(&self.config.collection_name)
-            .await?;
+        let mut tx = self.sql.begin().await?;
+        vectors::delete_all(&mut tx).await?;
+        tx.commit().await?;
         Ok(())
     }
 
@@ -170,89 +246,429 @@ impl SemanticClient {
         offset: u64,
         threshold: f32,
         get_more: bool,
+        rerank: Option<RerankOptions>,
     ) -> anyhow::Result<Vec<Payload>> {
-        let vector = self.embedder.embed(&query)?;
+        let vector = self.embedder.embed(&self.prefixes().query(query))?;
 
         // TODO: Remove the need for `retrieve_more`. It's here because:
         // In /q `limit` is the maximum number of results returned (the actual number will often be lower due to deduplication)
         // In /answer we want to retrieve `limit` results exactly
-        let results = self
+        let wanted = if get_more { limit * 2 } else { limit }; // Retrieve double `limit` and deduplicate
+        let mut results = self
             .search_with(
                 query,
                 reporef,
-                vector.clone(),
-                if get_more { limit * 2 } else { limit }, // Retrieve double `limit` and deduplicate
+                vector,
+                rerank_depth(wanted, rerank),
                 offset,
                 threshold,
             )
-            .await
-            .map(|raw| {
-                raw.into_iter()
-                    .map(Payload::from_qdrant)
-                    .collect::<Vec<_>>()
-            })?;
+            .await?;
         // We should also deduplicate things here, when required
         // TODO(skcd): deduplicate the snippets here and also rank them properly
         // with how much more relevant they are
+        if let Some(options) = rerank {
+            results = self.rerank(query, results, options).await;
+        }
+
+        results.truncate(wanted as usize);
         Ok(results)
     }
 
-    pub async fn search_with<'a>(
+    /// Parse `query` and search the repositories of `repos` its `repo:`
+    /// filters allow, keeping the chunks which pass the rest of its filters.
+    pub async fn search_parsed(
+        &self,
+        query: &str,
+        repos: &[RepoRef],
+        limit: u64,
+        threshold: f32,
+    ) -> anyhow::Result<Vec<Payload>> {
+        let parsed = parser::parse(query)?;
+        self.search_filtered(&parsed, repos, limit, threshold).await
+    }
+
+    pub async fn search_filtered(
+        &self,
+        query: &ParsedQuery,
+        repos: &[RepoRef],
+        limit: u64,
+        threshold: f32,
+    ) -> anyhow::Result<Vec<Payload>> {
+        // filters are applied after the KNN, so we ask for more to be left
+        // with enough
+        const FILTER_OVERSAMPLE: u64 = 4;
+
+        // a query of nothing but filters still needs something to embed
+        let text = match query.semantic_text() {
+            text if text.is_empty() => query
+                .filters
+                .iter()
+                .filter(|f| !f.negated)
+                .map(|f| f.value.as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+            text => text,
+        };
+        let vector = self.embedder.embed(&self.prefixes().query(&text))?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
+
+        let mut results = vec![];
+        for reporef in repos {
+            let allowed = query.allows(Field::Repo, &reporef.indexed_name())
+                || query.allows(Field::Repo, &reporef.to_string());
+            if !allowed {
+                continue;
+            }
+
+            let found = self
+                .search_with(
+                    &text,
+                    reporef,
+                    vector.clone(),
+                    limit * FILTER_OVERSAMPLE,
+                    0,
+                    threshold,
+                )
+                .await?;
+            results.extend(
+                found
+                    .into_iter()
+                    .filter(|payload| payload.matches(query, now)),
+            );
+        }
+
+        results.sort_by(|a, b| {
+            let score = |p: &Payload| p.score.unwrap_or_default();
+            score(b).total_cmp(&score(a))
+        });
+        results.truncate(limit as usize);
+
+        // the most relevant results, newest first, rather than the newest
+        // results however poorly they match
+        if query.sort == SortOrder::Recent {
+            results.sort_by_key(|payload| Reverse(payload.last_changed()));
+        }
+
+        Ok(results)
+    }
+
+    /// Search with every variant of `query` the pipeline comes up with, and
+    /// merge the results with reciprocal rank fusion.
+    ///
+    /// A chunk found by several variants keeps the best similarity score any
+    /// of them gave it.
+    pub async fn search_expanded<'a>(
         &self,
         query: &'a str,
         reporef: &'a RepoRef,
-        vector: Vec<f32>,
         limit: u64,
-        offset: u64,
         threshold: f32,
-    ) -> anyhow::Result<Vec<ScoredPoint>> {
-        let response = self
-            .qdrant_client()
-            .search_points(&SearchPoints {
-                limit,
-                vector,
-                collection_name: self.config.collection_name.to_string(),
-                offset: Some(offset),
-                score_threshold: Some(threshold),
-                with_payload: Some(WithPayloadSelector {
-                    selector_options: Some(with_payload_selector::SelectorOptions::Enable(true)),
-                }),
-                filter: Some(Filter {
-                    must: build_conditions(query, reporef),
-                    ..Default::default()
-                }),
-                with_vectors: Some(WithVectorsSelector {
-                    selector_options: Some(with_vectors_selector::SelectorOptions::Enable(true)),
-                }),
-                ..Default::default()
-            })
-            .await?;
+        rerank: Option<RerankOptions>,
+    ) -> anyhow::Result<Vec<Payload>> {
+        // the usual constant for RRF, keeps a single first place from
+        // dominating the merged ranking
+        const RRF_K: f32 = 60.0;
+
+        let plan = self.plan_query(query);
+        let variants = plan.variants.iter().map(String::as_str).collect::<Vec<_>>();
+        let vectors = self.embedder.batch_embed(variants).await?;
+        let depth = rerank_depth(limit, rerank);
+
+        let mut fused: HashMap<String, (f32, Payload)> = HashMap::new();
+        for (variant, vector) in plan.variants.iter().zip(vectors) {
+            let results = self
+                .search_with(variant, reporef, vector, depth, 0, threshold)
+                .await?;
+
+            for (rank, payload) in results.into_iter().enumerate() {
+                let Some(id) = payload.id.clone() else {
+                    continue;
+                };
+
+                let contribution = 1.0 / (RRF_K + rank as f32 + 1.0);
+                match fused.get_mut(&id) {
+                    Some((score, best)) => {
+                        *score += contribution;
+                        if payload.score > best.score {
+                            *best = payload;
+                        }
+                    }
+                    None => {
+                        fused.insert(id, (contribution, payload));
+                    }
+                }
+            }
+        }
+
+        let mut results = fused.into_values().collect::<Vec<_>>();
+        results.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        let mut results = results
+            .into_iter()
+            .take(depth as usize)
+            .map(|(_, payload)| payload)
+            .collect();
+
+        debug!(variants = plan.variants.len(), "searched query variants");
+
+        if let Some(options) = rerank {
+            results = self.rerank(query, results, options).await;
+        }
+
+        results.truncate(limit as usize);
+        Ok(results)
+    }
+
+    /// Re-order the top of `results` with the cross-encoder.
+    ///
+    /// Returns the results in the bi-encoder's order if no re-ranker is
+    /// configured, or it fails.
+    pub async fn rerank(
+        &self,
+        query: &str,
+        results: Vec<Payload>,
+        options: RerankOptions,
+    ) -> Vec<Payload> {
+        let Some(ref reranker) = self.reranker else {
+            debug!("no re-ranker configured, keeping bi-encoder order");
+            return results;
+        };
+
+        let reranker = Arc::clone(reranker);
+        let query = query.to_owned();
+        let texts = results
+            .iter()
+            .take(options.top_n)
+            .map(|payload| payload.text.clone())
+            .collect::<Vec<_>>();
+
+        let scores = tokio::task::spawn_blocking(move || {
+            rerank::score(reranker.as_ref(), &query, &texts, options)
+        })
+        .await;
+
+        match scores {
+            Ok(scores) => rerank::apply(results, scores),
+            Err(err) => {
+                error!(?err, "re-ranker failed, keeping bi-encoder order");
+                results
+            }
+        }
+    }
+
+    /// Attach the code around every result, read from the repository's
+    /// working copy.
+    ///
+    /// Results whose file is gone keep `context` unset.
+    pub async fn expand_context(
+        &self,
+        query: &str,
+        repo: &Repository,
+        results: &mut [Payload],
+        options: ContextOptions,
+    ) -> anyhow::Result<()> {
+        let terms = self.plan_query(query).lexical_terms;
+        for payload in results.iter_mut() {
+            payload.context = context::expand(
+                &repo.disk_path,
+                &payload.relative_path,
+                &payload.text,
+                payload.start_line,
+                payload.end_line,
+                &terms,
+                &options,
+            );
+        }
+
+        let Some(window) = options.similar_window.filter(|w| *w > 0) else {
+            return Ok(());
+        };
+
+        let query = self.embedder.embed(&self.prefixes().query(query))?;
+        for payload in results.iter_mut() {
+            let Some(ref mut context) = payload.context else {
+                continue;
+            };
+
+            let lines = context.chunk_lines().collect::<Vec<_>>();
+            let windows = lines
+                .chunks(window)
+                .map(|lines| {
+                    let text = lines.iter().map(|(_, text)| *text).collect::<Vec<_>>();
+                    let first = lines[0].0;
+                    (first..first + lines.len() as u64, text.join("\n"))
+                })
+                .filter(|(_, text)| !text.trim().is_empty())
+                .collect::<Vec<_>>();
+
+            // nothing to pick between
+            if windows.len() < 2 {
+                continue;
+            }
+
+            let documents = windows
+                .iter()
+                .map(|(_, text)| self.prefixes().document(text))
+                .collect::<Vec<_>>();
+            let embeddings = self
+                .embedder
+                .batch_embed(documents.iter().map(String::as_str).collect())
+                .await?;
+
+            let best = windows
+                .into_iter()
+                .zip(embeddings)
+                .map(|((lines, _), embedding)| {
+                    (lines, vectors::cosine_similarity(&query, &embedding))
+                })
+                .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+            if let Some((lines, _)) = best {
+                context.highlight_lines(lines, HighlightKind::Similar);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Chunks in `targets` which are similar to lines `lines` of
+    /// `relative_path` in `reporef`, best first.
+    ///
+    /// The stored vectors of the chunks overlapping the range are used as
+    /// the query. If there are none, because the file isn't indexed yet or
+    /// changed too much, the range is read from `source` and embedded.
+    /// Chunks overlapping the range itself are never returned.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn find_similar(
+        &self,
+        source: &Repository,
+        reporef: &RepoRef,
+        relative_path: &str,
+        lines: RangeInclusive<u64>,
+        targets: &[RepoRef],
+        limit: u64,
+        threshold: f32,
+    ) -> anyhow::Result<Vec<Payload>> {
+        let repo_str = reporef.to_string();
+        let seeds = vectors::chunks_overlapping(
+            &self.sql,
+            &repo_str,
+            relative_path,
+            *lines.start(),
+            *lines.end(),
+        )
+        .await?;
+
+        let mut queries = seeds
+            .into_iter()
+            .filter_map(|payload| payload.embedding)
+            .collect::<Vec<_>>();
+
+        if queries.is_empty() {
+            let bytes = tokio::fs::read(source.disk_path.join(relative_path)).await?;
+            let buffer = encoding::decode(bytes)
+                .ok_or_else(|| anyhow::anyhow!("{relative_path} is not a text file"))?;
+            let text = buffer
+                .text
+                .lines()
+                .skip(*lines.start() as usize)
+                .take((lines.end() - lines.start()) as usize + 1)
+                .collect::<Vec<_>>()
+                .join("\n");
+            if text.trim().is_empty() {
+                anyhow::bail!("lines {lines:?} of {relative_path} are empty");
+            }
+
+            let data = chunk_data(self.prefixes(), &reporef.indexed_name(), relative_path, &text);
+            queries.push(self.embedder.embed(&data)?);
+        }
+
+        let is_source = |payload: &Payload| {
+            payload.repo_ref == repo_str
+                && payload.relative_path == relative_path
+                && payload.start_line <= *lines.end()
+                && payload.end_line >= *lines.start()
+        };
+
+        // the neighbours of a chunk include the source span and its
+        // neighbours within the span, so ask for enough to cover those
+        let span_len = lines.end() - lines.start() + 1;
+        let k = limit + span_len.min(limit);
+
+        let mut best: HashMap<String, Payload> = HashMap::new();
+        for target in targets {
+            let target = target.to_string();
+            for query in queries.iter() {
+                for payload in vectors::search(&self.sql, &target, query, k).await? {
+                    if is_source(&payload) || payload.score.unwrap_or_default() < threshold {
+                        continue;
+                    }
+                    let Some(id) = payload.id.clone() else {
+                        continue;
+                    };
+
+                    match best.get(&id) {
+                        Some(existing) if existing.score >= payload.score => {}
+                        _ => {
+                            best.insert(id, payload);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut results = best.into_values().collect::<Vec<_>>();
+        results.sort_by(|a, b| {
+            let score = |p: &Payload| p.score.unwrap_or_default();
+            score(b).total_cmp(&score(a))
+        });
+        results.truncate(limit as usize);
 
-        Ok(response.result)
+        Ok(results)
     }
 
+    pub async fn search_with<'a>(
+        &self,
+        _query: &'a str,
+        reporef: &'a RepoRef,
+        vector: Vec<f32>,
+        limit: u64,
+        offset: u64,
+        threshold: f32,
+    ) -> anyhow::Result<Vec<Payload>> {
+        let started = Instant::now();
+
+        // sqlite-vec has no notion of an offset, so we ask for enough
+        // neighbours to cover it and skip the first ones ourselves
+        let results = vectors::search(&self.sql, &reporef.to_string(), &vector, limit + offset)
+            .await?
+            .into_iter()
+            .skip(offset as usize)
+            .filter(|payload| payload.score.unwrap_or_default() >= threshold)
+            .collect();
+
+        METRICS.search.observe(started.elapsed());
+        Ok(results)
+    }
 }
 
-fn build_conditions<'a>(parsed_query: &'a str, reporef: &'a RepoRef) -> Vec<Condition> {
-    vec![Filter {
-        must: vec![make_kv_keyword_filter("repo_ref", &reporef.to_string()).into()],
-        ..Default::default()
-    }]
-    .into_iter()
-    .map(Into::into)
-    .collect()
+/// How many results to fetch for `limit`, so the re-ranker has its whole
+/// `top_n` to pick from before the results are cut to size.
+fn rerank_depth(limit: u64, rerank: Option<RerankOptions>) -> u64 {
+    rerank.map_or(limit, |options| limit.max(options.top_n as u64))
 }
 
-fn make_kv_keyword_filter(key: &str, value: &str) -> FieldCondition {
-    let key = key.to_owned();
-    let value = value.to_owned();
-    FieldCondition {
-        key,
-        r#match: Some(Match {
-            match_value: MatchValue::Keyword(value).into(),
-        }),
-        ..Default::default()
-    }
+/// The text we embed for a chunk, which carries the location of the chunk
+/// along with its content, behind the model's document prefix.
+pub(crate) fn chunk_data(
+    prefixes: &ModelPrefixes,
+    repo_name: &str,
+    relative_path: &str,
+    text: &str,
+) -> String {
+    prefixes.document(&format!("{repo_name}\t{relative_path}\n{text}"))
 }
 
 /// Initialize the `ORT_DYLIB_PATH` variable, consumed by the `ort` crate.
@@ -275,23 +691,3 @@ fn init_ort_dylib(dylib_dir: impl AsRef<Path>) {
         }
     }
 }
-
-// pub(super) async fn create_collection(
-//     name: &str,
-//     //qdrant: &QdrantClient,
-// ) -> anyhow::Result<CollectionOperationResponse> {
-//     qdrant
-//         .create_collection(&CreateCollection {
-//             collection_name: name.to_string(),
-//             vectors_config: Some(VectorsConfig {
-//                 config: Some(vectors_config::Config::Params(VectorParams {
-//                     size: EMBEDDING_DIM as u64,
-//                     distance: Distance::Cosine.into(),
-//                     on_disk: Some(true),
-//                     ..Default::default()
-//                 })),
-//             }),
-//             ..Default::default()
-//         })
-//         .await
-// }
@@ -0,0 +1,291 @@
+//! Expanding a search result with the code around it.
+//!
+//! A chunk is read back from the working copy rather than the index, so we
+//! can show the lines around it and the signature of whatever it sits in.
+//! The file may have changed since it was indexed, in which case we try to
+//! find the chunk again before giving up and marking the context as stale.
+
+use std::{ops::Range, path::Path};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::repo::encoding;
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub struct ContextOptions {
+    /// Lines to include before and after the chunk
+    pub lines: usize,
+
+    /// Lines per window when looking for the part of the chunk most similar
+    /// to the query, `None` skips the embedding round trip
+    pub similar_window: Option<usize>,
+}
+
+impl Default for ContextOptions {
+    fn default() -> Self {
+        Self {
+            lines: 5,
+            similar_window: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HighlightKind {
+    /// One of the query's terms
+    Term,
+    /// The lines of the chunk most similar to the query
+    Similar,
+}
+
+/// A highlighted byte range of `ResultContext::text`
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Highlight {
+    pub kind: HighlightKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Signature {
+    pub line: u64,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ResultContext {
+    /// The chunk with the surrounding lines, as they are on disk now
+    pub text: String,
+    pub start_line: u64,
+    pub end_line: u64,
+
+    /// Where the chunk itself is now, which differs from the indexed lines
+    /// if code was added or removed above it
+    pub chunk_start_line: u64,
+    pub chunk_end_line: u64,
+
+    /// The enclosing function or class, if the chunk doesn't start with it
+    pub signature: Option<Signature>,
+    pub highlights: Vec<Highlight>,
+
+    /// The chunk isn't on the lines it was indexed from anymore
+    pub changed: bool,
+
+    /// The file changed since it was indexed and the chunk couldn't be
+    /// found in it again, so the context is around the indexed lines
+    pub stale: bool,
+}
+
+impl ResultContext {
+    /// Lines of the chunk, with their line numbers
+    pub(crate) fn chunk_lines(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.text
+            .lines()
+            .zip(self.start_line..)
+            .filter(|(_, line)| (self.chunk_start_line..=self.chunk_end_line).contains(line))
+            .map(|(text, line)| (line, text))
+    }
+
+    /// Highlight lines `lines` of the file
+    pub(crate) fn highlight_lines(&mut self, lines: Range<u64>, kind: HighlightKind) {
+        let mut offset = 0;
+        let (mut start, mut end) = (None, None);
+        for (line, text) in (self.start_line..).zip(self.text.split_inclusive('\n')) {
+            if line == lines.start {
+                start = Some(offset);
+            }
+            offset += text.trim_end_matches('\n').len();
+            if line + 1 == lines.end {
+                end = Some(offset);
+                break;
+            }
+            offset += 1;
+        }
+
+        if let (Some(start), Some(end)) = (start, end) {
+            self.highlights.push(Highlight { kind, start, end });
+            self.highlights.sort_by_key(|h| (h.start, h.end));
+        }
+    }
+}
+
+/// Read `relative_path` from the working copy and build the context for a
+/// chunk indexed as `text` on lines `start_line..=end_line`.
+///
+/// Returns `None` if the file is gone or can't be read as text anymore.
+pub fn expand(
+    disk_path: &Path,
+    relative_path: &str,
+    text: &str,
+    start_line: u64,
+    end_line: u64,
+    terms: &[String],
+    options: &ContextOptions,
+) -> Option<ResultContext> {
+    let bytes = std::fs::read(disk_path.join(relative_path)).ok()?;
+    let buffer = encoding::decode(bytes)?;
+    let lines = buffer.text.lines().collect::<Vec<_>>();
+    if lines.is_empty() {
+        return None;
+    }
+
+    let chunk = text.lines().collect::<Vec<_>>();
+    let (chunk_start, stale) = match locate(&lines, &chunk, start_line as usize) {
+        Some(start) => (start, false),
+        None => ((start_line as usize).min(lines.len() - 1), true),
+    };
+    let chunk_len = if stale {
+        (end_line - start_line) as usize + 1
+    } else {
+        chunk.len().max(1)
+    };
+    let chunk_end = (chunk_start + chunk_len - 1).min(lines.len() - 1);
+
+    let first = chunk_start.saturating_sub(options.lines);
+    let last = (chunk_end + options.lines).min(lines.len() - 1);
+
+    let mut context = ResultContext {
+        text: lines[first..=last].join("\n"),
+        start_line: first as u64,
+        end_line: last as u64,
+        chunk_start_line: chunk_start as u64,
+        chunk_end_line: chunk_end as u64,
+        signature: enclosing_signature(&lines, chunk_start),
+        highlights: vec![],
+        changed: stale || chunk_start as u64 != start_line,
+        stale,
+    };
+
+    context.highlights = term_highlights(&context.text, terms);
+    Some(context)
+}
+
+/// Where `chunk` starts in `lines` now, preferring the match closest to
+/// where it was indexed.
+fn locate(lines: &[&str], chunk: &[&str], indexed_start: usize) -> Option<usize> {
+    if chunk.is_empty() || chunk.len() > lines.len() {
+        return None;
+    }
+
+    let matches_at = |start: usize| {
+        lines[start..start + chunk.len()]
+            .iter()
+            .zip(chunk)
+            .all(|(a, b)| a.trim_end() == b.trim_end())
+    };
+
+    let last_start = lines.len() - chunk.len();
+    if indexed_start <= last_start && matches_at(indexed_start) {
+        return Some(indexed_start);
+    }
+
+    (0..=last_start)
+        .filter(|start| matches_at(*start))
+        .min_by_key(|start| start.abs_diff(indexed_start))
+}
+
+/// The closest line above `line` that looks like it opens a definition, at
+/// a lower indentation than the chunk.
+fn enclosing_signature(lines: &[&str], line: usize) -> Option<Signature> {
+    static DEFINITION: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(
+            r"^\s*(?:(?:pub(?:\([^)]*\))?|export|default|public|private|protected|internal|static|abstract|final|async|unsafe|extern|override|virtual)\s+)*(?:fn|def|class|struct|enum|trait|impl|interface|function|func|mod|module|namespace|object|type)\b",
+        )
+        .unwrap()
+    });
+
+    let indent = |text: &str| text.len() - text.trim_start().len();
+
+    // a chunk which opens with the definition needs no signature
+    if DEFINITION.is_match(lines[line]) {
+        return None;
+    }
+
+    let mut max_indent = lines[line..]
+        .iter()
+        .find(|text| !text.trim().is_empty())
+        .map(|text| indent(text))
+        .unwrap_or_default();
+
+    // walk outwards through the blocks the chunk is nested in
+    for idx in (0..line).rev() {
+        if max_indent == 0 {
+            break;
+        }
+
+        let text = lines[idx];
+        if text.trim().is_empty() || indent(text) >= max_indent {
+            continue;
+        }
+
+        if DEFINITION.is_match(text) {
+            return Some(Signature {
+                line: idx as u64,
+                text: text.trim().to_owned(),
+            });
+        }
+        max_indent = indent(text);
+    }
+
+    None
+}
+
+/// Terms shorter than this are left alone, they'd light up everything
+const MIN_HIGHLIGHT_LEN: usize = 3;
+
+/// Words of natural language queries which say nothing about the code
+const STOP_WORDS: &[&str] = &[
+    "and", "are", "but", "can", "does", "for", "from", "has", "have", "how", "into", "not", "the",
+    "that", "this", "use", "uses", "was", "what", "when", "where", "which", "who", "why", "with",
+];
+
+/// Every case-insensitive hit of one of `terms` in `text`, as a whole word.
+///
+/// The words of an identifier count as words too, so `user` is found in
+/// `getUserName` and `user_id`.
+fn term_highlights(text: &str, terms: &[String]) -> Vec<Highlight> {
+    let terms = terms
+        .iter()
+        .filter(|term| term.chars().count() >= MIN_HIGHLIGHT_LEN)
+        .filter(|term| !STOP_WORDS.contains(&term.to_lowercase().as_str()))
+        .map(|term| regex::escape(term))
+        .collect::<Vec<_>>();
+    if terms.is_empty() {
+        return vec![];
+    }
+
+    let Ok(pattern) = Regex::new(&format!("(?i){}", terms.join("|"))) else {
+        return vec![];
+    };
+
+    pattern
+        .find_iter(text)
+        .filter(|m| {
+            let before = text[..m.start()].chars().next_back();
+            let after = text[m.end()..].chars().next();
+            let first = m.as_str().chars().next();
+            let last = m.as_str().chars().next_back();
+            word_boundary(before, first) && word_boundary(last, after)
+        })
+        .map(|m| Highlight {
+            kind: HighlightKind::Term,
+            start: m.start(),
+            end: m.end(),
+        })
+        .collect()
+}
+
+/// Whether a word may end after `left` and start at `right`, the same way
+/// identifiers are split into words for the query.
+fn word_boundary(left: Option<char>, right: Option<char>) -> bool {
+    let (Some(left), Some(right)) = (left, right) else {
+        return true;
+    };
+
+    !left.is_alphanumeric()
+        || !right.is_alphanumeric()
+        || left.is_alphabetic() != right.is_alphabetic()
+        || (left.is_lowercase() && right.is_uppercase())
+}
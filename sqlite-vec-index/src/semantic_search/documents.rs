@@ -0,0 +1,397 @@
+//! Chunking for prose and notebooks.
+//!
+//! Code is chunked by the language parsers, which know nothing about
+//! documentation. Markdown and reStructuredText are split into sections
+//! instead, each of which remembers the headings it sits under, so a chunk
+//! deep in a `Configuration > Logging` section can still be found by a
+//! question about configuring logging. Notebooks are split by cell, with
+//! their outputs dropped, as those are mostly images and noise.
+
+use std::borrow::Cow;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::value::RawValue;
+
+/// Sections longer than this are split further, at blank lines if possible
+const MAX_SECTION_LINES: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentKind {
+    Markdown,
+    ReStructuredText,
+    Notebook,
+}
+
+impl DocumentKind {
+    pub fn for_path(relative_path: &str) -> Option<Self> {
+        let (_, ext) = relative_path.rsplit_once('.')?;
+        match ext.to_ascii_lowercase().as_str() {
+            "md" | "markdown" | "mdx" => Some(Self::Markdown),
+            "rst" => Some(Self::ReStructuredText),
+            "ipynb" => Some(Self::Notebook),
+            _ => None,
+        }
+    }
+}
+
+/// The text of the file at `relative_path` which the lexical index gets to
+/// see, which is all of it, except for the outputs of notebooks.
+pub fn searchable_text<'a>(relative_path: &str, buffer: &'a str) -> Cow<'a, str> {
+    match DocumentKind::for_path(relative_path) {
+        Some(DocumentKind::Notebook) => strip_outputs(buffer),
+        _ => Cow::Borrowed(buffer),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CellKind {
+    Code,
+    Markdown,
+}
+
+/// A chunk of a document, with inclusive 0-based line numbers into the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentChunk {
+    pub text: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    /// The titles of the enclosing sections, outermost first
+    pub heading_path: Vec<String>,
+    pub cell: Option<CellKind>,
+}
+
+pub fn chunk(kind: DocumentKind, buffer: &str) -> Vec<DocumentChunk> {
+    match kind {
+        DocumentKind::Markdown => sections(buffer, markdown_heading),
+        DocumentKind::ReStructuredText => sections(buffer, rst_heading()),
+        DocumentKind::Notebook => cells(buffer),
+    }
+}
+
+/// A heading found at some line: its level, title, and how many lines it
+/// takes up
+struct Heading {
+    level: usize,
+    title: String,
+    lines: usize,
+}
+
+fn sections(
+    buffer: &str,
+    mut heading_at: impl FnMut(&[&str], usize) -> Option<Heading>,
+) -> Vec<DocumentChunk> {
+    let lines = buffer.lines().collect::<Vec<_>>();
+    let mut chunks = vec![];
+
+    // (level, title) of the headings enclosing the current line
+    let mut path: Vec<(usize, String)> = vec![];
+    let mut start = 0;
+    let mut in_fence = false;
+    let mut line = 0;
+
+    while line < lines.len() {
+        if is_fence(lines[line]) {
+            in_fence = !in_fence;
+        }
+
+        let heading = (!in_fence).then(|| heading_at(&lines, line)).flatten();
+        let Some(heading) = heading else {
+            line += 1;
+            continue;
+        };
+
+        push_section(&mut chunks, &lines, start, line, &path);
+
+        while path.last().is_some_and(|(level, _)| *level >= heading.level) {
+            path.pop();
+        }
+        path.push((heading.level, heading.title));
+
+        // the heading goes with its section, so the section's text names
+        // what it's about
+        start = line;
+        line += heading.lines;
+    }
+
+    push_section(&mut chunks, &lines, start, lines.len(), &path);
+    chunks
+}
+
+/// Add the lines `start..end` as one or more chunks
+fn push_section(
+    chunks: &mut Vec<DocumentChunk>,
+    lines: &[&str],
+    start: usize,
+    end: usize,
+    path: &[(usize, String)],
+) {
+    let heading_path = path.iter().map(|(_, title)| title.clone()).collect::<Vec<_>>();
+
+    let mut start = start;
+    while start < end {
+        let mut stop = end.min(start + MAX_SECTION_LINES);
+        if stop < end {
+            // prefer breaking between paragraphs
+            if let Some(blank) = (start + MAX_SECTION_LINES / 2..stop)
+                .rev()
+                .find(|&line| lines[line].trim().is_empty())
+            {
+                stop = blank + 1;
+            }
+        }
+
+        let section = &lines[start..stop];
+        if section.iter().any(|line| !line.trim().is_empty()) {
+            chunks.push(DocumentChunk {
+                text: section.join("\n"),
+                start_line: start,
+                end_line: stop - 1,
+                heading_path: heading_path.clone(),
+                cell: None,
+            });
+        }
+
+        start = stop;
+    }
+}
+
+fn is_fence(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+fn markdown_heading(lines: &[&str], line: usize) -> Option<Heading> {
+    static ATX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^ {0,3}(#{1,6})\s+(.*?)[\s#]*$").unwrap());
+
+    if let Some(captures) = ATX.captures(lines[line]) {
+        return Some(Heading {
+            level: captures[1].len(),
+            title: captures[2].to_owned(),
+            lines: 1,
+        });
+    }
+
+    // setext headings are underlined with `=` or `-`
+    let title = lines[line].trim();
+    let underline = lines.get(line + 1)?.trim();
+    if title.is_empty() || underline.is_empty() || title.starts_with(['-', '*', '>', '|']) {
+        return None;
+    }
+
+    let level = if underline.chars().all(|c| c == '=') {
+        1
+    } else if underline.chars().all(|c| c == '-') && underline.len() >= 2 {
+        2
+    } else {
+        return None;
+    };
+
+    Some(Heading {
+        level,
+        title: title.to_owned(),
+        lines: 2,
+    })
+}
+
+/// reStructuredText has no fixed heading levels, they're given by the order
+/// in which the underline styles first show up in the document.
+fn rst_heading() -> impl FnMut(&[&str], usize) -> Option<Heading> {
+    let mut styles: Vec<(char, bool)> = vec![];
+
+    move |lines, line| {
+        let adornment = |text: &str| {
+            let text = text.trim_end();
+            let first = text.chars().next()?;
+            (text.len() >= 2
+                && first.is_ascii_punctuation()
+                && text.chars().all(|c| c == first))
+            .then_some(first)
+        };
+
+        // an overline, the title, then an underline of the same character
+        let overlined = adornment(lines[line]).and_then(|c| {
+            let title = lines.get(line + 1)?.trim();
+            (!title.is_empty() && adornment(lines.get(line + 2)?) == Some(c))
+                .then(|| (c, true, title, 3))
+        });
+
+        let (c, overline, title, taken) = match overlined {
+            Some(found) => found,
+            None => {
+                let title = lines[line].trim_end();
+                let underline = lines.get(line + 1)?;
+                let c = adornment(underline)?;
+                // the underline has to be at least as long as the title,
+                // which tells it apart from a line of dashes after a
+                // paragraph
+                if title.trim().is_empty()
+                    || title.starts_with(char::is_whitespace)
+                    || adornment(title).is_some()
+                    || underline.trim_end().chars().count() < title.chars().count()
+                {
+                    return None;
+                }
+                (c, false, title, 2)
+            }
+        };
+
+        let level = match styles.iter().position(|style| *style == (c, overline)) {
+            Some(index) => index + 1,
+            None => {
+                styles.push((c, overline));
+                styles.len()
+            }
+        };
+
+        Some(Heading {
+            level,
+            title: title.to_owned(),
+            lines: taken,
+        })
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct Notebook {
+    #[serde(default)]
+    cells: Vec<Cell>,
+}
+
+#[derive(serde::Deserialize)]
+struct Cell {
+    cell_type: String,
+    #[serde(default)]
+    source: Source,
+}
+
+/// nbformat allows the source as one string, or as a list of lines
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Source {
+    Text(String),
+    Lines(Vec<String>),
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+impl Source {
+    fn lines(&self) -> Vec<&str> {
+        match self {
+            Self::Text(text) => text.split_inclusive('\n').collect(),
+            Self::Lines(lines) => lines.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct NotebookOutputs<'a> {
+    #[serde(default, borrow)]
+    cells: Vec<CellOutputs<'a>>,
+}
+
+#[derive(serde::Deserialize)]
+struct CellOutputs<'a> {
+    #[serde(default, borrow)]
+    outputs: Option<&'a RawValue>,
+}
+
+/// The notebook in `buffer` with the outputs of its cells emptied.
+///
+/// Every output keeps the newlines it had, so the lines of the sources stay
+/// where they are. Anything that doesn't parse as a notebook is left alone.
+fn strip_outputs(buffer: &str) -> Cow<'_, str> {
+    let Ok(notebook) = serde_json::from_str::<NotebookOutputs<'_>>(buffer) else {
+        return Cow::Borrowed(buffer);
+    };
+
+    let mut stripped = String::new();
+    let mut cursor = 0;
+    for outputs in notebook.cells.iter().filter_map(|cell| cell.outputs) {
+        let raw = outputs.get();
+        // the raw value borrows from `buffer`, which tells us where it is
+        let start = raw.as_ptr() as usize - buffer.as_ptr() as usize;
+
+        stripped.push_str(&buffer[cursor..start]);
+        stripped.push('[');
+        stripped.extend(std::iter::repeat('\n').take(raw.matches('\n').count()));
+        stripped.push(']');
+        cursor = start + raw.len();
+    }
+
+    if cursor == 0 {
+        return Cow::Borrowed(buffer);
+    }
+
+    stripped.push_str(&buffer[cursor..]);
+    Cow::Owned(stripped)
+}
+
+/// Markdown and code cells, one chunk each.
+///
+/// Line numbers point into the notebook's JSON, found by looking for each
+/// line of the cell's source as it's encoded there. Notebooks written by
+/// Jupyter have every line of the source on a line of its own, for anything
+/// else the lines are a best guess.
+fn cells(buffer: &str) -> Vec<DocumentChunk> {
+    let Ok(notebook) = serde_json::from_str::<Notebook>(buffer) else {
+        return vec![];
+    };
+
+    let line_of = |byte: usize| buffer[..byte].matches('\n').count();
+
+    // markdown cells carry the headings the code cells below them sit under
+    let mut path: Vec<(usize, String)> = vec![];
+    let mut cursor = 0;
+    let mut chunks = vec![];
+
+    for cell in notebook.cells {
+        let kind = match cell.cell_type.as_str() {
+            "code" => CellKind::Code,
+            "markdown" => CellKind::Markdown,
+            _ => continue,
+        };
+
+        let source = cell.source.lines();
+        let text = source.concat();
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        let mut start_byte = None;
+        for line in &source {
+            let encoded = serde_json::to_string(line).unwrap_or_default();
+            if let Some(found) = buffer[cursor..].find(&encoded) {
+                start_byte.get_or_insert(cursor + found);
+                cursor += found + encoded.len();
+            }
+        }
+
+        if kind == CellKind::Markdown {
+            let lines = text.lines().collect::<Vec<_>>();
+            for line in 0..lines.len() {
+                if let Some(heading) = markdown_heading(&lines, line) {
+                    while path.last().is_some_and(|(level, _)| *level >= heading.level) {
+                        path.pop();
+                    }
+                    path.push((heading.level, heading.title));
+                }
+            }
+        }
+
+        chunks.push(DocumentChunk {
+            text,
+            start_line: line_of(start_byte.unwrap_or(cursor)),
+            end_line: line_of(cursor),
+            heading_path: path.iter().map(|(_, title)| title.clone()).collect(),
+            cell: Some(kind),
+        });
+    }
+
+    chunks
+}
@@ -0,0 +1,405 @@
+//! Find code which is duplicated within a repository.
+//!
+//! Chunks with the same text, give or take whitespace, are grouped first.
+//! The remaining chunks are then joined with their nearest neighbours above
+//! a similarity threshold, which finds the copies somebody has already
+//! started to edit. Clusters are reported per pair of files, so the largest
+//! duplicated regions can be tackled first.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    path::Path,
+};
+
+use sqlx::Row;
+use tracing::{debug, info};
+
+use crate::{db::sqlite::SqlDb, repo::types::RepoRef};
+
+use super::{schema::Payload, vectors};
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub struct DuplicateOptions {
+    /// Minimum cosine similarity for two chunks to count as duplicates
+    pub threshold: f32,
+
+    /// Nearest neighbours to look at for every chunk
+    pub neighbours: u64,
+
+    /// Chunks shorter than this many lines are ignored, they're mostly
+    /// boilerplate
+    pub min_lines: u64,
+}
+
+impl Default for DuplicateOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 0.95,
+            neighbours: 8,
+            min_lines: 4,
+        }
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkLocation {
+    pub relative_path: String,
+    pub start_line: u64,
+    pub end_line: u64,
+}
+
+impl ChunkLocation {
+    fn lines(&self) -> u64 {
+        self.end_line - self.start_line + 1
+    }
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct DuplicateCluster {
+    /// Every chunk in the cluster has the same text
+    pub exact: bool,
+    /// The lowest similarity which joined the cluster together
+    pub min_similarity: f32,
+    pub chunks: Vec<ChunkLocation>,
+}
+
+/// A duplicated line range, `start_line..=end_line` in both files
+#[derive(serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct DuplicatedRegion {
+    pub left: (u64, u64),
+    pub right: (u64, u64),
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct FilePair {
+    pub left: String,
+    pub right: String,
+    /// Lines of `left` which are duplicated in `right`
+    pub duplicated_lines: u64,
+    pub regions: Vec<DuplicatedRegion>,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct DuplicateReport {
+    pub repo_ref: String,
+    pub options: DuplicateOptions,
+    pub chunks: usize,
+    pub clusters: Vec<DuplicateCluster>,
+    /// Largest duplicated regions first
+    pub file_pairs: Vec<FilePair>,
+}
+
+impl DuplicateReport {
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        _ = writeln!(out, "# Duplicated code in `{}`\n", self.repo_ref);
+        _ = writeln!(
+            out,
+            "{} clusters among {} chunks, similarity threshold {}.\n",
+            self.clusters.len(),
+            self.chunks,
+            self.options.threshold
+        );
+
+        if self.file_pairs.is_empty() {
+            return out;
+        }
+
+        _ = writeln!(out, "| Duplicated lines | File | File | Regions |");
+        _ = writeln!(out, "| ---: | --- | --- | --- |");
+        for pair in self.file_pairs.iter() {
+            let regions = pair
+                .regions
+                .iter()
+                .map(|r| {
+                    format!(
+                        "{}-{} ↔ {}-{}",
+                        r.left.0 + 1,
+                        r.left.1 + 1,
+                        r.right.0 + 1,
+                        r.right.1 + 1
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            _ = writeln!(
+                out,
+                "| {} | `{}` | `{}` | {regions} |",
+                pair.duplicated_lines, pair.left, pair.right
+            );
+        }
+
+        out
+    }
+
+    /// Write the report to `dir` as `duplicates.json` and `duplicates.md`.
+    pub async fn write(&self, dir: &Path) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(dir).await?;
+        tokio::fs::write(dir.join("duplicates.json"), serde_json::to_vec_pretty(self)?).await?;
+        tokio::fs::write(dir.join("duplicates.md"), self.to_markdown()).await?;
+        Ok(())
+    }
+}
+
+struct StoredChunk {
+    id: String,
+    location: ChunkLocation,
+    text_hash: blake3::Hash,
+    embedding: Vec<f32>,
+}
+
+pub async fn report(
+    sql: &SqlDb,
+    reporef: &RepoRef,
+    options: DuplicateOptions,
+) -> anyhow::Result<DuplicateReport> {
+    let repo_ref = reporef.to_string();
+    let chunks = sqlx::query(
+        "SELECT chunk_id, payload, full_embedding FROM chunk_vectors_all WHERE repo_ref = ?",
+    )
+    .bind(&repo_ref)
+    .fetch_all(sql.as_ref())
+    .await?
+    .into_iter()
+    .map(|row| {
+        let payload: Payload = serde_json::from_str(row.try_get("payload")?)?;
+        Ok(StoredChunk {
+            id: row.try_get("chunk_id")?,
+            text_hash: normalized_hash(&payload.text),
+            embedding: vectors::decode(row.try_get("full_embedding")?),
+            location: ChunkLocation {
+                relative_path: payload.relative_path,
+                start_line: payload.start_line,
+                end_line: payload.end_line,
+            },
+        })
+    })
+    .collect::<anyhow::Result<Vec<_>>>()?
+    .into_iter()
+    .filter(|chunk| chunk.location.lines() >= options.min_lines)
+    .collect::<Vec<_>>();
+
+    let index = chunks
+        .iter()
+        .enumerate()
+        .map(|(idx, chunk)| (chunk.id.as_str(), idx))
+        .collect::<HashMap<_, _>>();
+
+    let mut clusters = UnionFind::new(chunks.len());
+    let mut min_similarity = vec![1f32; chunks.len()];
+
+    // exact copies first, they don't need a vector search
+    let mut by_hash: HashMap<blake3::Hash, usize> = HashMap::new();
+    for (idx, chunk) in chunks.iter().enumerate() {
+        match by_hash.get(&chunk.text_hash) {
+            Some(&first) if !overlaps(&chunks[first].location, &chunk.location) => {
+                clusters.union(first, idx);
+            }
+            Some(_) => {}
+            None => {
+                by_hash.insert(chunk.text_hash, idx);
+            }
+        }
+    }
+
+    let mut edges = vec![];
+    for (idx, chunk) in chunks.iter().enumerate() {
+        let neighbours =
+            vectors::search(sql, &repo_ref, &chunk.embedding, options.neighbours + 1).await?;
+
+        for neighbour in neighbours {
+            let score = neighbour.score.unwrap_or_default();
+            let Some(&other) = neighbour.id.as_deref().and_then(|id| index.get(id)) else {
+                continue;
+            };
+
+            if other == idx
+                || score < options.threshold
+                || overlaps(&chunk.location, &chunks[other].location)
+            {
+                continue;
+            }
+
+            edges.push((idx.min(other), idx.max(other), score));
+        }
+    }
+    edges.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+    edges.dedup_by(|a, b| (a.0, a.1) == (b.0, b.1));
+
+    for (a, b, _) in edges.iter() {
+        clusters.union(*a, *b);
+    }
+
+    let mut members: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for idx in 0..chunks.len() {
+        members.entry(clusters.find(idx)).or_default().push(idx);
+    }
+    for (a, _, score) in edges.iter() {
+        let root = clusters.find(*a);
+        min_similarity[root] = min_similarity[root].min(*score);
+    }
+
+    let mut report_clusters = members
+        .iter()
+        .filter(|(_, members)| members.len() > 1)
+        .map(|(root, members)| DuplicateCluster {
+            exact: members
+                .iter()
+                .all(|idx| chunks[*idx].text_hash == chunks[members[0]].text_hash),
+            min_similarity: min_similarity[*root],
+            chunks: members
+                .iter()
+                .map(|idx| chunks[*idx].location.clone())
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+
+    report_clusters.sort_by(|a, b| {
+        let lines = |c: &DuplicateCluster| c.chunks.iter().map(ChunkLocation::lines).sum::<u64>();
+        lines(b).cmp(&lines(a))
+    });
+
+    let file_pairs = file_pairs(&chunks, members.values());
+    info!(
+        repo_ref,
+        chunks = chunks.len(),
+        clusters = report_clusters.len(),
+        file_pairs = file_pairs.len(),
+        "built duplicate report"
+    );
+
+    Ok(DuplicateReport {
+        repo_ref,
+        options,
+        chunks: chunks.len(),
+        clusters: report_clusters,
+        file_pairs,
+    })
+}
+
+/// Every pair of chunks in the same cluster duplicates the lines of both,
+/// grouped by the files they are in.
+fn file_pairs<'a>(
+    chunks: &[StoredChunk],
+    clusters: impl Iterator<Item = &'a Vec<usize>>,
+) -> Vec<FilePair> {
+    let mut pairs: BTreeMap<(String, String), Vec<DuplicatedRegion>> = BTreeMap::new();
+
+    for members in clusters {
+        for (i, a) in members.iter().enumerate() {
+            for b in members[i + 1..].iter() {
+                let (a, b) = (&chunks[*a].location, &chunks[*b].location);
+                if overlaps(a, b) {
+                    continue;
+                }
+
+                let (left, right) = if a.relative_path <= b.relative_path {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+
+                pairs
+                    .entry((left.relative_path.clone(), right.relative_path.clone()))
+                    .or_default()
+                    .push(DuplicatedRegion {
+                        left: (left.start_line, left.end_line),
+                        right: (right.start_line, right.end_line),
+                    });
+            }
+        }
+    }
+
+    let mut pairs = pairs
+        .into_iter()
+        .map(|((left, right), regions)| {
+            let regions = merge_regions(regions);
+            FilePair {
+                duplicated_lines: regions.iter().map(|r| r.left.1 - r.left.0 + 1).sum(),
+                left,
+                right,
+                regions,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    pairs.sort_by(|a, b| b.duplicated_lines.cmp(&a.duplicated_lines));
+    debug!(pairs = pairs.len(), "grouped duplicates by file pair");
+    pairs
+}
+
+/// Join regions which are adjacent or overlapping on both sides, so a
+/// duplicated function shows up once instead of chunk by chunk.
+fn merge_regions(mut regions: Vec<DuplicatedRegion>) -> Vec<DuplicatedRegion> {
+    regions.sort_by_key(|r| (r.left, r.right));
+
+    let mut merged: Vec<DuplicatedRegion> = vec![];
+    for region in regions {
+        match merged.last_mut() {
+            Some(last)
+                if region.left.0 <= last.left.1 + 1
+                    && region.right.0 <= last.right.1 + 1
+                    && region.right.1 + 1 >= last.right.0 =>
+            {
+                last.left.1 = last.left.1.max(region.left.1);
+                last.right.0 = last.right.0.min(region.right.0);
+                last.right.1 = last.right.1.max(region.right.1);
+            }
+            _ => merged.push(region),
+        }
+    }
+
+    merged
+}
+
+fn overlaps(a: &ChunkLocation, b: &ChunkLocation) -> bool {
+    a.relative_path == b.relative_path && a.start_line <= b.end_line && b.start_line <= a.end_line
+}
+
+/// Identical chunks, ignoring indentation and blank lines
+fn normalized_hash(text: &str) -> blake3::Hash {
+    let mut hash = blake3::Hasher::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        hash.update(line.as_bytes());
+        hash.update(b"\n");
+    }
+    hash.finalize()
+}
+
+struct UnionFind {
+    parent: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        Self {
+            parent: (0..len).collect(),
+        }
+    }
+
+    fn find(&mut self, idx: usize) -> usize {
+        let mut root = idx;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+
+        // path compression
+        let mut idx = idx;
+        while self.parent[idx] != root {
+            let next = self.parent[idx];
+            self.parent[idx] = root;
+            idx = next;
+        }
+
+        root
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parent[b.max(a)] = a.min(b);
+        }
+    }
+}
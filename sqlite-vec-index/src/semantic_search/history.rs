@@ -0,0 +1,484 @@
+//! Semantic search over the commit history of a repository.
+//!
+//! Every commit's message is embedded together with the paths it touched,
+//! and optionally a short summary of the changes to each file, so a
+//! question like "when did we change the retry logic" can find the commit
+//! even if its message doesn't say "retry". Commits are only ever added,
+//! each sync picks up the ones we haven't seen yet.
+//!
+//! Like `vectors`, this uses the unchecked `sqlx::query` for `commit_vectors`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use gix::bstr::ByteSlice;
+use once_cell::sync::Lazy;
+use sqlx::{Row, Sqlite};
+use tracing::{debug, info};
+
+use crate::{db::sqlite::SqlDb, repo::types::RepoRef};
+
+use super::{client::SemanticClient, vectors};
+
+/// Commits embedded in one transaction
+const BATCH_COMMITS: usize = 32;
+
+/// At most this many paths go into the text embedded for a commit message
+const MAX_MESSAGE_PATHS: usize = 20;
+
+/// One indexing run per repository at a time, otherwise two runs which
+/// started from the same known commits insert the same entries twice
+static INDEXING: Lazy<scc::HashMap<String, Arc<tokio::sync::Mutex<()>>>> =
+    Lazy::new(Default::default);
+
+#[derive(Debug, Clone)]
+pub struct HistoryOptions {
+    /// Commits indexed in a single sync, newest first
+    pub max_commits: usize,
+
+    /// Also embed a summary of the changes to every file a commit touched
+    pub diffs: bool,
+
+    /// Changed lines kept in each file's summary
+    pub max_diff_lines: usize,
+}
+
+impl Default for HistoryOptions {
+    fn default() -> Self {
+        Self {
+            max_commits: 1000,
+            diffs: false,
+            max_diff_lines: 40,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CommitRecord {
+    pub commit_hash: String,
+    pub author_name: String,
+    pub author_email: String,
+    /// Unix seconds
+    pub committed_at: i64,
+    /// The first line of the message
+    pub summary: String,
+    pub message: String,
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CommitMatch {
+    #[serde(flatten)]
+    pub commit: CommitRecord,
+    pub score: f32,
+    /// Set when the best match was the changes to this file, rather than
+    /// the commit message
+    pub relative_path: Option<String>,
+}
+
+/// The changes a commit made to a single file
+struct FileChange {
+    relative_path: String,
+    summary: String,
+}
+
+struct WalkedCommit {
+    record: CommitRecord,
+    changes: Vec<FileChange>,
+}
+
+impl WalkedCommit {
+    fn message_text(&self) -> String {
+        let record = &self.record;
+        let paths = record
+            .paths
+            .iter()
+            .take(MAX_MESSAGE_PATHS)
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        format!("{}\n\n{paths}", record.message.trim())
+    }
+
+    fn change_text(&self, change: &FileChange) -> String {
+        format!(
+            "{}\n{}\n{}",
+            self.record.summary, change.relative_path, change.summary
+        )
+    }
+}
+
+/// Embed the commits of `reporef` which aren't indexed yet, returning how
+/// many were added.
+pub async fn index(
+    sql: &SqlDb,
+    semantic: &SemanticClient,
+    reporef: &RepoRef,
+    disk_path: &Path,
+    options: &HistoryOptions,
+) -> anyhow::Result<usize> {
+    let repo_ref = reporef.to_string();
+    let lock = INDEXING
+        .entry_async(repo_ref.clone())
+        .await
+        .or_default()
+        .get()
+        .clone();
+    let _indexing = lock.lock().await;
+
+    let known = sqlx::query("SELECT commit_hash FROM commits WHERE repo_ref = ?")
+        .bind(&repo_ref)
+        .fetch_all(sql.as_ref())
+        .await?
+        .into_iter()
+        .map(|row| row.try_get("commit_hash"))
+        .collect::<Result<HashSet<String>, _>>()?;
+
+    let walked = {
+        let disk_path = disk_path.to_owned();
+        let options = options.clone();
+        tokio::task::spawn_blocking(move || walk(&disk_path, &known, &options)).await??
+    };
+    debug!(?reporef, commits = walked.len(), "walked commit history");
+
+    let embedder = semantic.get_embedder();
+    let prefixes = semantic.prefixes();
+
+    for batch in walked.chunks(BATCH_COMMITS) {
+        // (entry id, commit hash, relative path, text)
+        let mut entries = vec![];
+        for commit in batch {
+            let hash = &commit.record.commit_hash;
+            entries.push((
+                hash.clone(),
+                hash,
+                None,
+                prefixes.document(&commit.message_text()),
+            ));
+
+            for change in &commit.changes {
+                entries.push((
+                    entry_id(hash, &change.relative_path),
+                    hash,
+                    Some(change.relative_path.as_str()),
+                    prefixes.document(&commit.change_text(change)),
+                ));
+            }
+        }
+
+        let embeddings = embedder
+            .batch_embed(entries.iter().map(|(.., text)| text.as_str()).collect())
+            .await?;
+
+        let mut tx = sql.begin().await?;
+        for commit in batch {
+            insert_commit(&mut tx, &repo_ref, &commit.record).await?;
+        }
+        for ((id, hash, relative_path, _), embedding) in entries.iter().zip(&embeddings) {
+            sqlx::query(
+                "INSERT INTO commit_vectors (entry_id, repo_ref, commit_hash, embedding, relative_path) \
+                 VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(id)
+            .bind(&repo_ref)
+            .bind(*hash)
+            .bind(vectors::encode(embedding))
+            .bind(*relative_path)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+    }
+
+    info!(?reporef, commits = walked.len(), "indexed commit history");
+    Ok(walked.len())
+}
+
+fn entry_id(commit_hash: &str, relative_path: &str) -> String {
+    let mut hash = blake3::Hasher::new();
+    hash.update(commit_hash.as_bytes());
+    hash.update(relative_path.as_bytes());
+    hash.finalize().to_hex().to_string()
+}
+
+async fn insert_commit(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+    record: &CommitRecord,
+) -> anyhow::Result<()> {
+    let paths = serde_json::to_string(&record.paths)?;
+    sqlx::query! {
+        "INSERT OR REPLACE INTO commits \
+         (repo_ref, commit_hash, author_name, author_email, committed_at, summary, message, paths) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        repo_ref,
+        record.commit_hash,
+        record.author_name,
+        record.author_email,
+        record.committed_at,
+        record.summary,
+        record.message,
+        paths,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn delete_for_repo(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM commit_vectors WHERE repo_ref = ?")
+        .bind(repo_ref)
+        .execute(&mut **tx)
+        .await?;
+
+    sqlx::query! {
+        "DELETE FROM commits WHERE repo_ref = ?",
+        repo_ref,
+    }
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// The commits reachable from `HEAD` which aren't in `known`, newest first
+fn walk(
+    disk_path: &Path,
+    known: &HashSet<String>,
+    options: &HistoryOptions,
+) -> anyhow::Result<Vec<WalkedCommit>> {
+    let repo = gix::open(disk_path).context("failed to open git repo")?;
+    let head = repo.head_commit()?;
+
+    let mut walked = vec![];
+    for info in head.ancestors().all()? {
+        if walked.len() >= options.max_commits {
+            break;
+        }
+
+        let info = info?;
+        let commit_hash = info.id.to_string();
+        if known.contains(&commit_hash) {
+            continue;
+        }
+
+        let commit = info.object()?;
+        let author = commit.author()?;
+        let message = commit.message_raw_sloppy().to_str_lossy().into_owned();
+        let summary = message.lines().next().unwrap_or_default().to_owned();
+
+        let tree = commit.tree()?;
+        let parent_tree = match commit.parent_ids().next() {
+            Some(parent) => parent.object()?.into_commit().tree()?,
+            None => repo.empty_tree(),
+        };
+
+        let mut changes = vec![];
+        parent_tree
+            .changes()?
+            .track_path()
+            .for_each_to_obtain_tree(&tree, |change| {
+                use gix::object::tree::diff::{change::Event, Action};
+
+                let relative_path = change.location.to_str_lossy().into_owned();
+                let (old, new) = match change.event {
+                    Event::Addition { entry_mode, id } if entry_mode.is_blob() => {
+                        (None, Some(id))
+                    }
+                    Event::Deletion { entry_mode, id } if entry_mode.is_blob() => {
+                        (Some(id), None)
+                    }
+                    Event::Modification {
+                        previous_id,
+                        entry_mode,
+                        id,
+                        ..
+                    } if entry_mode.is_blob() => (Some(previous_id), Some(id)),
+                    _ => return Ok::<_, anyhow::Error>(Action::Continue),
+                };
+
+                let summary = if options.diffs {
+                    let read = |id: Option<gix::Id<'_>>| -> anyhow::Result<Vec<u8>> {
+                        Ok(match id {
+                            Some(id) => id.object()?.detach().data,
+                            None => vec![],
+                        })
+                    };
+                    summarize(&read(old)?, &read(new)?, options.max_diff_lines)
+                } else {
+                    String::new()
+                };
+
+                changes.push(FileChange {
+                    relative_path,
+                    summary,
+                });
+                Ok(Action::Continue)
+            })?;
+
+        let paths = changes
+            .iter()
+            .map(|change| change.relative_path.clone())
+            .collect();
+        if !options.diffs {
+            changes.clear();
+        }
+
+        walked.push(WalkedCommit {
+            record: CommitRecord {
+                commit_hash,
+                author_name: author.name.to_str_lossy().into_owned(),
+                author_email: author.email.to_str_lossy().into_owned(),
+                committed_at: commit.time()?.seconds,
+                summary,
+                message,
+                paths,
+            },
+            changes,
+        });
+    }
+
+    Ok(walked)
+}
+
+/// The lines added and removed between `old` and `new`, ignoring their
+/// order. Enough to tell what a change was about, not to apply it.
+fn summarize(old: &[u8], new: &[u8], max_lines: usize) -> String {
+    if old.contains(&0) || new.contains(&0) {
+        return "binary file".to_owned();
+    }
+
+    let (old, new) = (old.to_str_lossy(), new.to_str_lossy());
+    let mut remaining = HashMap::<&str, isize>::new();
+    for line in old.lines() {
+        *remaining.entry(line.trim()).or_default() += 1;
+    }
+
+    let mut added = vec![];
+    for line in new.lines() {
+        let count = remaining.entry(line.trim()).or_default();
+        if *count > 0 {
+            *count -= 1;
+        } else if !line.trim().is_empty() {
+            added.push(line.trim());
+        }
+    }
+
+    let mut removed = vec![];
+    for line in old.lines() {
+        let count = remaining.entry(line.trim()).or_default();
+        if *count > 0 && !line.trim().is_empty() {
+            *count -= 1;
+            removed.push(line.trim());
+        }
+    }
+
+    let lines = added
+        .iter()
+        .map(|line| format!("+ {line}"))
+        .chain(removed.iter().map(|line| format!("- {line}")))
+        .take(max_lines);
+
+    let mut summary = format!("+{} -{}", added.len(), removed.len());
+    for line in lines {
+        summary.push('\n');
+        summary.push_str(&line);
+    }
+
+    summary
+}
+
+/// Commits of `reporef` best matching `query`, best first
+pub async fn search(
+    sql: &SqlDb,
+    semantic: &SemanticClient,
+    reporef: &RepoRef,
+    query: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<CommitMatch>> {
+    let repo_ref = reporef.to_string();
+    let vector = semantic
+        .get_embedder()
+        .embed(&semantic.prefixes().query(query))?;
+
+    // a commit can match through its message and several files, so ask
+    // for more than we need
+    let rows = sqlx::query(
+        "SELECT commit_hash, relative_path, distance FROM commit_vectors \
+         WHERE embedding MATCH ? AND k = ? AND repo_ref = ? \
+         ORDER BY distance",
+    )
+    .bind(vectors::encode(&vector))
+    .bind((limit * 4) as i64)
+    .bind(&repo_ref)
+    .fetch_all(sql.as_ref())
+    .await?;
+
+    // the best scoring entry of every commit, in order
+    let mut best: Vec<(String, Option<String>, f32)> = vec![];
+    for row in rows {
+        let commit_hash: String = row.try_get("commit_hash")?;
+        if best.iter().any(|(hash, ..)| *hash == commit_hash) {
+            continue;
+        }
+
+        let distance: f64 = row.try_get("distance")?;
+        best.push((commit_hash, row.try_get("relative_path")?, 1.0 - distance as f32));
+        if best.len() >= limit {
+            break;
+        }
+    }
+
+    let mut matches = vec![];
+    for (commit_hash, relative_path, score) in best {
+        let Some(commit) = commit(sql, &repo_ref, &commit_hash).await? else {
+            continue;
+        };
+
+        matches.push(CommitMatch {
+            commit,
+            score,
+            relative_path,
+        });
+    }
+
+    Ok(matches)
+}
+
+async fn commit(
+    sql: &SqlDb,
+    repo_ref: &str,
+    commit_hash: &str,
+) -> anyhow::Result<Option<CommitRecord>> {
+    let row = sqlx::query! {
+        "SELECT commit_hash, author_name, author_email, committed_at, summary, message, paths \
+         FROM commits WHERE repo_ref = ? AND commit_hash = ?",
+        repo_ref,
+        commit_hash,
+    }
+    .fetch_optional(sql.as_ref())
+    .await?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    Ok(Some(CommitRecord {
+        commit_hash: row.commit_hash,
+        author_name: row.author_name,
+        author_email: row.author_email,
+        committed_at: row.committed_at,
+        summary: row.summary,
+        message: row.message,
+        paths: serde_json::from_str(&row.paths)?,
+    }))
+}
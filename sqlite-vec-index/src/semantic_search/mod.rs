@@ -1,3 +1,12 @@
 pub mod client;
-pub mod payload_helpers;
+pub mod context;
+pub mod documents;
+pub mod duplicates;
+pub mod history;
+pub mod query;
+pub mod reader;
+pub mod recall;
+pub mod rerank;
 pub mod schema;
+pub mod vectors;
+pub mod writer;
@@ -0,0 +1,206 @@
+//! Turning a user's query into what we embed and match on.
+//!
+//! Some embedding models are trained with an instruction in front of queries
+//! and documents, and do noticeably worse without it. On top of that, code
+//! queries are full of identifiers, which the lexical side only matches when
+//! they're split into words, and which the embedder does better on when it
+//! also sees a spelled out variant of the query.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+/// What goes in front of queries and documents before embedding them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModelPrefixes {
+    pub query: String,
+    pub document: String,
+}
+
+impl ModelPrefixes {
+    /// The prefixes a model was trained with, going by the name of its
+    /// directory. Models we don't know about get none.
+    pub fn for_model(name: &str) -> Self {
+        let name = name.to_lowercase();
+        let (query, document) = if name.contains("e5") {
+            ("query: ", "passage: ")
+        } else if name.contains("bge") {
+            ("Represent this sentence for searching relevant passages: ", "")
+        } else if name.contains("nomic") {
+            ("search_query: ", "search_document: ")
+        } else {
+            ("", "")
+        };
+
+        Self {
+            query: query.to_owned(),
+            document: document.to_owned(),
+        }
+    }
+
+    pub fn query(&self, query: &str) -> String {
+        format!("{}{query}", self.query)
+    }
+
+    pub fn document(&self, document: &str) -> String {
+        format!("{}{document}", self.document)
+    }
+}
+
+/// Groups of interchangeable terms, read from a local file.
+///
+/// Every line is a comma separated group, like `auth, authentication, login`.
+/// Empty lines and lines starting with `#` are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct SynonymDictionary {
+    groups: Vec<Vec<String>>,
+    by_term: HashMap<String, usize>,
+}
+
+impl SynonymDictionary {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self::parse(&std::fs::read_to_string(path)?))
+    }
+
+    pub fn parse(source: &str) -> Self {
+        let mut dictionary = Self::default();
+        for line in source.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let group = line
+                .split(',')
+                .map(|term| term.trim().to_lowercase())
+                .filter(|term| !term.is_empty())
+                .collect::<Vec<_>>();
+
+            if group.len() < 2 {
+                continue;
+            }
+
+            let idx = dictionary.groups.len();
+            for term in group.iter() {
+                dictionary.by_term.entry(term.clone()).or_insert(idx);
+            }
+            dictionary.groups.push(group);
+        }
+
+        dictionary
+    }
+
+    /// The other terms in the group of `term`
+    pub fn synonyms(&self, term: &str) -> impl Iterator<Item = &str> {
+        let term = term.to_lowercase();
+        self.by_term
+            .get(&term)
+            .map(|idx| self.groups[*idx].as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter(move |other| **other != term)
+            .map(String::as_str)
+    }
+}
+
+/// Split the identifiers in `text` into lowercase words.
+///
+/// `parseHTTPResponse`, `parse_http_response` and `parse-http-response` all
+/// become `parse http response`.
+pub fn split_identifiers(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .flat_map(split_word)
+        .collect()
+}
+
+fn split_word(token: &str) -> Vec<String> {
+    let chars = token.chars().collect::<Vec<_>>();
+    let mut words = vec![];
+    let mut current = String::new();
+
+    for (i, c) in chars.iter().enumerate() {
+        let prev = i.checked_sub(1).map(|p| chars[p]);
+        let next = chars.get(i + 1);
+
+        let boundary = match prev {
+            None => false,
+            Some(prev) => {
+                // fooBar, foo2, 2foo
+                (prev.is_lowercase() && c.is_uppercase())
+                    || (prev.is_alphabetic() != c.is_alphabetic())
+                    // HTTPResponse splits before the `R`
+                    || (prev.is_uppercase()
+                        && c.is_uppercase()
+                        && next.is_some_and(|n| n.is_lowercase()))
+            }
+        };
+
+        if boundary && !current.is_empty() {
+            words.push(std::mem::take(&mut current).to_lowercase());
+        }
+        current.push(*c);
+    }
+
+    if !current.is_empty() {
+        words.push(current.to_lowercase());
+    }
+
+    words
+}
+
+/// Everything we do with a single query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryPlan {
+    /// What to embed, the original query always comes first
+    pub variants: Vec<String>,
+
+    /// Words for the lexical side, with identifiers split up
+    pub lexical_terms: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QueryPipeline {
+    pub prefixes: ModelPrefixes,
+    pub synonyms: Option<SynonymDictionary>,
+    pub max_variants: usize,
+}
+
+impl QueryPipeline {
+    pub fn plan(&self, query: &str) -> QueryPlan {
+        let terms = split_identifiers(query);
+        let mut variants = vec![query.to_owned()];
+
+        let mut push = |variant: String| {
+            if variants.len() < self.max_variants.max(1) && !variants.contains(&variant) {
+                variants.push(variant);
+            }
+        };
+
+        // the spelled out version helps when the query is mostly identifiers
+        push(terms.join(" "));
+
+        if let Some(ref synonyms) = self.synonyms {
+            for (i, term) in terms.iter().enumerate() {
+                for synonym in synonyms.synonyms(term) {
+                    let mut expanded = terms.clone();
+                    expanded[i] = synonym.to_owned();
+                    push(expanded.join(" "));
+                }
+            }
+        }
+
+        let mut seen = HashSet::new();
+        let lexical_terms = terms
+            .into_iter()
+            .filter(|term| seen.insert(term.clone()))
+            .collect();
+
+        QueryPlan {
+            variants: variants
+                .into_iter()
+                .map(|variant| self.prefixes.query(&variant))
+                .collect(),
+            lexical_terms,
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Searching an index another process is writing to.
+//!
+//! Editors, the CLI and the server all want to search the index the sync
+//! process keeps up to date. A [`SearchReader`] opens the database with
+//! read-only connections, so it can't write by accident, and thanks to WAL
+//! mode sees the last committed state without waiting on a sync that's
+//! halfway through a repository.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+
+use crate::{
+    application::config::configuration::Configuration,
+    db::sqlite::{self, ActiveWriter, SqlDb, WriterLock},
+    indexes::{
+        snippet::SnippetDocument,
+        symbols::{self, Symbol, SymbolMatch},
+        trigram,
+    },
+    query::parser::ParsedQuery,
+    repo::types::RepoRef,
+};
+
+use super::{
+    client::SemanticClient,
+    history::{self, CommitMatch},
+    schema::Payload,
+};
+
+#[derive(Clone)]
+pub struct SearchReader {
+    semantic: SemanticClient,
+    sql: SqlDb,
+    db_path: PathBuf,
+}
+
+impl SearchReader {
+    pub async fn open(config: Arc<Configuration>, db_path: &Path) -> anyhow::Result<Self> {
+        let sql = sqlite::open_reader(db_path).await?;
+        let semantic = SemanticClient::new(config, sql.clone())
+            .await
+            .context("failed to load the embedder")?;
+
+        Ok(Self {
+            semantic,
+            sql,
+            db_path: db_path.to_owned(),
+        })
+    }
+
+    /// The process keeping the index up to date, if any. Without one,
+    /// results stay as they are until the next sync.
+    pub fn writer(&self) -> anyhow::Result<Option<ActiveWriter>> {
+        Ok(WriterLock::holder(&self.db_path)?)
+    }
+
+    /// Chunks of `repos` matching `query`, filters and all
+    pub async fn search(
+        &self,
+        query: &str,
+        repos: &[RepoRef],
+        limit: u64,
+        threshold: f32,
+    ) -> anyhow::Result<Vec<Payload>> {
+        self.semantic
+            .search_parsed(query, repos, limit, threshold)
+            .await
+    }
+
+    /// Commits of `reporef` whose message, or changes, best match `query`
+    pub async fn search_history(
+        &self,
+        reporef: &RepoRef,
+        query: &str,
+        limit: usize,
+    ) -> anyhow::Result<Vec<CommitMatch>> {
+        history::search(&self.sql, &self.semantic, reporef, query, limit).await
+    }
+
+    /// Lines of `reporef` matching the regex `pattern`, in the files
+    /// `query`'s `path:` filters allow
+    pub async fn regex_search(
+        &self,
+        reporef: &RepoRef,
+        pattern: &str,
+        query: Option<&ParsedQuery>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<SnippetDocument>> {
+        trigram::search(&self.sql, reporef, pattern, query, limit).await
+    }
+
+    /// Where symbols called `name` are defined in `reporef`
+    pub async fn find_symbol(
+        &self,
+        reporef: &RepoRef,
+        name: &str,
+        mode: SymbolMatch,
+        limit: usize,
+    ) -> anyhow::Result<Vec<Symbol>> {
+        symbols::lookup(&self.sql, reporef, name, mode, limit).await
+    }
+}
@@ -0,0 +1,176 @@
+//! Compare the recall of the different [`VectorStorage`] options on the
+//! vectors of a repository, so the memory saved by quantising can be
+//! weighed against the results it loses.
+//!
+//! A sample of the repository's own chunks is used as queries, and the
+//! exact top-k over the full precision vectors is the ground truth.
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+use sqlx::Row;
+
+use crate::{db::sqlite::SqlDb, repo::types::RepoRef};
+
+use super::vectors::{self, VectorStorage, RESCORE_OVERSAMPLE};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RecallRow {
+    pub storage: VectorStorage,
+
+    /// Fraction of the exact top-k found by the first stage alone
+    pub recall_first_stage: f32,
+
+    /// Fraction of the exact top-k found after rescoring the oversampled
+    /// candidates at full precision
+    pub recall_rescored: f32,
+
+    pub bytes_per_vector: usize,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct RecallReport {
+    pub repo_ref: String,
+    pub samples: usize,
+    pub k: usize,
+
+    /// The storage the repository currently uses
+    pub current: VectorStorage,
+    pub rows: Vec<RecallRow>,
+}
+
+pub async fn report(
+    sql: &SqlDb,
+    reporef: &RepoRef,
+    samples: usize,
+    k: usize,
+) -> anyhow::Result<RecallReport> {
+    let repo_ref = reporef.to_string();
+    let current = vectors::stored_as(sql.as_ref(), &repo_ref).await?;
+
+    let queries = sqlx::query(
+        "SELECT chunk_id, full_embedding FROM chunk_vectors_all \
+         WHERE repo_ref = ? AND full_embedding IS NOT NULL \
+         ORDER BY random() LIMIT ?",
+    )
+    .bind(&repo_ref)
+    .bind(samples as i64)
+    .fetch_all(sql.as_ref())
+    .await?
+    .into_iter()
+    .map(|row| Ok(row.try_get::<Vec<u8>, _>("full_embedding")?))
+    .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut rows = VectorStorage::ALL
+        .into_iter()
+        .map(|storage| RecallRow {
+            storage,
+            recall_first_stage: 0.0,
+            recall_rescored: 0.0,
+            bytes_per_vector: storage.bytes_per_vector(),
+        })
+        .collect::<Vec<_>>();
+
+    for query in queries.iter() {
+        let exact = ranked(sql, &repo_ref, VectorStorage::Float, query, k).await?;
+        if exact.is_empty() {
+            continue;
+        }
+
+        for row in rows.iter_mut() {
+            let candidates =
+                ranked(sql, &repo_ref, row.storage, query, k * RESCORE_OVERSAMPLE as usize)
+                    .await?;
+
+            let first_stage = candidates.iter().take(k).cloned().collect::<Vec<_>>();
+            let rescored = rescore(sql, query, &candidates, k).await?;
+
+            row.recall_first_stage += overlap(&exact, &first_stage);
+            row.recall_rescored += overlap(&exact, &rescored);
+        }
+    }
+
+    if !queries.is_empty() {
+        for row in rows.iter_mut() {
+            row.recall_first_stage /= queries.len() as f32;
+            row.recall_rescored /= queries.len() as f32;
+        }
+    }
+
+    Ok(RecallReport {
+        repo_ref,
+        samples: queries.len(),
+        k,
+        current,
+        rows,
+    })
+}
+
+/// The `limit` closest chunk ids to `query`, the way `storage` would
+/// rank them.
+///
+/// This runs a brute force scan with the same distance functions the
+/// `vec0` tables use, so the repository doesn't have to be converted to
+/// measure a storage option.
+async fn ranked(
+    sql: &SqlDb,
+    repo_ref: &str,
+    storage: VectorStorage,
+    query: &[u8],
+    limit: usize,
+) -> anyhow::Result<Vec<String>> {
+    let distance = match storage {
+        VectorStorage::Float => "vec_distance_cosine(full_embedding, ?1)",
+        VectorStorage::Int8 => {
+            "vec_distance_l2(vec_quantize_int8(full_embedding, 'unit'), \
+             vec_quantize_int8(?1, 'unit'))"
+        }
+        VectorStorage::Binary => {
+            "vec_distance_hamming(vec_quantize_binary(full_embedding), vec_quantize_binary(?1))"
+        }
+    };
+
+    sqlx::query(&format!(
+        "SELECT chunk_id FROM chunk_vectors_all \
+         WHERE repo_ref = ?2 AND full_embedding IS NOT NULL \
+         ORDER BY {distance} LIMIT ?3"
+    ))
+    .bind(query)
+    .bind(repo_ref)
+    .bind(limit as i64)
+    .fetch_all(sql.as_ref())
+    .await?
+    .into_iter()
+    .map(|row| Ok(row.try_get("chunk_id")?))
+    .collect()
+}
+
+async fn rescore(
+    sql: &SqlDb,
+    query: &[u8],
+    candidates: &[String],
+    k: usize,
+) -> anyhow::Result<Vec<String>> {
+    let query = vectors::decode(query);
+    let full = vectors::full_precision(sql, candidates).await?;
+
+    let mut scored = candidates
+        .iter()
+        .map(|id| {
+            let score = full
+                .get(id)
+                .map(|embedding| vectors::cosine_similarity(&query, embedding))
+                .unwrap_or_default();
+            (id.clone(), score)
+        })
+        .collect::<Vec<_>>();
+
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Ok(scored.into_iter().take(k).map(|(id, _)| id).collect())
+}
+
+fn overlap(exact: &[String], found: &[String]) -> f32 {
+    let found = found.iter().collect::<HashSet<_>>();
+    let hits = exact.iter().filter(|id| found.contains(id)).count();
+    hits as f32 / exact.len() as f32
+}
@@ -0,0 +1,93 @@
+//! Second stage ranking of semantic search results with a cross-encoder.
+//!
+//! The cross-encoder is a lot more accurate than comparing embeddings, and
+//! a lot slower, so it only looks at the top of the results, and stops
+//! once it runs out of time. Whatever it didn't get to keeps the order the
+//! bi-encoder gave it.
+
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error};
+
+use crate::embedder::reranker::Reranker;
+
+use super::schema::Payload;
+
+/// How many pairs we score between checking the budget
+const RERANK_BATCH: usize = 8;
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub struct RerankOptions {
+    /// Re-rank at most this many of the top results
+    pub top_n: usize,
+
+    /// Stop scoring once this much time has passed, the batch in progress
+    /// still finishes
+    pub budget: Duration,
+}
+
+impl Default for RerankOptions {
+    fn default() -> Self {
+        Self {
+            top_n: 20,
+            budget: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Cross-encoder scores for the first `top_n` of `texts`, as many as fit
+/// in the budget.
+///
+/// A failing cross-encoder stops the scoring the same way running out of
+/// time does.
+pub(crate) fn score(
+    reranker: &dyn Reranker,
+    query: &str,
+    texts: &[String],
+    options: RerankOptions,
+) -> Vec<f32> {
+    let started = Instant::now();
+    let top_n = options.top_n.min(texts.len());
+
+    let mut scores = Vec::with_capacity(top_n);
+    for batch in texts[..top_n].chunks(RERANK_BATCH) {
+        if started.elapsed() >= options.budget {
+            break;
+        }
+
+        let batch = batch.iter().map(String::as_str).collect::<Vec<_>>();
+        match reranker.score(query, &batch) {
+            Ok(batch_scores) => scores.extend(batch_scores),
+            Err(err) => {
+                error!(?err, "re-ranker failed, keeping bi-encoder order");
+                break;
+            }
+        }
+    }
+
+    debug!(
+        scored = scores.len(),
+        top_n,
+        elapsed = ?started.elapsed(),
+        "re-ranked search results"
+    );
+
+    scores
+}
+
+/// Order the results which got a score from [`score`] by it.
+pub(crate) fn apply(mut results: Vec<Payload>, scores: Vec<f32>) -> Vec<Payload> {
+    let scored = scores.len().min(results.len());
+    for (payload, score) in results.iter_mut().zip(scores) {
+        payload.rerank_score = Some(score);
+    }
+
+    // only the prefix we got to is reordered, a partial score can't be
+    // compared with the bi-encoder's
+    results[..scored].sort_by(|a, b| {
+        let score = |p: &Payload| p.rerank_score.unwrap_or(f32::MIN);
+        score(b).total_cmp(&score(a))
+    });
+
+    results
+}
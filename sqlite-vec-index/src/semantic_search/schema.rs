@@ -1,79 +1,125 @@
-// //! Every change in this file will trigger a reset of the databases.
-// //! Use with care.
-// //!
-// use qdrant_client::{
-//     prelude::QdrantClient,
-//     qdrant::{
-//         vectors_config, CollectionOperationResponse, CreateCollection, Distance, ScoredPoint,
-//         VectorParams, VectorsConfig,
-//     },
-// };
+//! Every change in this file will trigger a reset of the databases.
+//! Use with care.
+//!
+use crate::embedder::embedder::Embedding;
 
-// pub(super) const EMBEDDING_DIM: usize = 384;
-// pub type Embedding = Vec<f32>;
+use crate::{
+    query::parser::{Field, ParsedQuery},
+    repo::ownership::Blame,
+};
 
-// #[derive(Default, Clone, Debug, serde::Deserialize, serde::Serialize)]
-// pub struct Payload {
-//     pub lang: String,
-//     pub repo_name: String,
-//     pub repo_ref: String,
-//     pub relative_path: String,
-//     pub content_hash: String,
-//     pub text: String,
-//     pub start_line: u64,
-//     pub end_line: u64,
-//     pub start_byte: u64,
-//     pub end_byte: u64,
-//     pub branches: Vec<String>,
-//     // One of the things I am worried about here is if we are on a dirty set
-//     // and don't have all the values yet, what would commit-hash mean in that
-//     // case? maybe we can always look at the state of the file at the latest
-//     // commit-hash and call it a day?
-//     pub commit_hash: String,
+use super::{context::ResultContext, documents::CellKind};
 
-//     #[serde(skip)]
-//     pub id: Option<String>,
-//     #[serde(skip)]
-//     pub embedding: Option<Embedding>,
-//     #[serde(skip)]
-//     pub score: Option<f32>,
-// }
+pub(crate) const EMBEDDING_DIM: usize = 384;
 
-// impl PartialEq for Payload {
-//     fn eq(&self, other: &Self) -> bool {
-//         self.lang == other.lang
-//             && self.repo_name == other.repo_name
-//             && self.repo_ref == other.repo_ref
-//             && self.relative_path == other.relative_path
-//             && self.content_hash == other.content_hash
-//             && self.text == other.text
-//             && self.start_line == other.start_line
-//             && self.end_line == other.end_line
-//             && self.start_byte == other.start_byte
-//             && self.end_byte == other.end_byte
-//             && self.branches == other.branches
+/// Identifies the model which produced a set of embeddings, vectors from
+/// two different descriptors can not be mixed.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ModelDescriptor {
+    pub name: String,
+    pub dimensions: usize,
+}
 
-//         // ignoring deserialized fields that will not exist on a newly
-//         // created payload
-//     }
-// }
+#[derive(Default, Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Payload {
+    pub lang: String,
+    pub repo_name: String,
+    pub repo_ref: String,
+    pub relative_path: String,
+    pub content_hash: String,
+    pub text: String,
+    pub start_line: u64,
+    pub end_line: u64,
+    pub start_byte: u64,
+    pub end_byte: u64,
+    pub branches: Vec<String>,
+    // One of the things I am worried about here is if we are on a dirty set
+    // and don't have all the values yet, what would commit-hash mean in that
+    // case? maybe we can always look at the state of the file at the latest
+    // commit-hash and call it a day?
+    pub commit_hash: String,
+    /// Titles of the sections a chunk of documentation sits under,
+    /// outermost first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub heading_path: Vec<String>,
+    /// Set on the chunks of notebooks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cell_kind: Option<CellKind>,
+    /// The most recent change to the chunk's lines, when indexed with blame
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blame: Option<Blame>,
+    /// The CODEOWNERS owners of the file at the time it was indexed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub owners: Vec<String>,
 
-// pub(super) async fn create_collection(
-//     name: &str,
-//     qdrant: &QdrantClient,
-// ) -> anyhow::Result<CollectionOperationResponse> {
-//     qdrant
-//         .create_collection(&CreateCollection {
-//             collection_name: name.to_string(),
-//             vectors_config: Some(VectorsConfig {
-//                 config: Some(vectors_config::Config::Params(VectorParams {
-//                     size: EMBEDDING_DIM as u64,
-//                     distance: Distance::Cosine.into(),
-//                     on_disk: Some(true),
-//                     ..Default::default()
-//                 })),
-//             }),
-//             ..Default::default()
-//         })
-//         .await
-// }
+    #[serde(skip)]
+    pub id: Option<String>,
+    #[serde(skip)]
+    pub embedding: Option<Embedding>,
+    #[serde(skip)]
+    pub score: Option<f32>,
+    /// Set when the result went through the cross-encoder
+    #[serde(skip)]
+    pub rerank_score: Option<f32>,
+    /// Set when the result was expanded with the code around it
+    #[serde(skip)]
+    pub context: Option<ResultContext>,
+}
+
+impl Payload {
+    /// Whether the chunk passes the filters of `query`, with `now` in unix
+    /// seconds for `since:`
+    pub fn matches(&self, query: &ParsedQuery, now: i64) -> bool {
+        let authors = self
+            .blame
+            .iter()
+            .flat_map(|blame| [blame.author_name.as_str(), blame.author_email.as_str()]);
+
+        query.allows(Field::Lang, &self.lang)
+            && query.allows(Field::Path, &self.relative_path)
+            && (query.allows(Field::Repo, &self.repo_name)
+                || query.allows(Field::Repo, &self.repo_ref))
+            && query.allows_any(Field::Owner, self.owners.iter().map(String::as_str))
+            && query.allows_any(Field::Author, authors)
+            && query.allows_time(self.blame.as_ref().map(|blame| blame.timestamp), now)
+            && query.allows_text(&self.text)
+    }
+
+    /// When the chunk last changed, if it was indexed with blame
+    pub fn last_changed(&self) -> Option<i64> {
+        self.blame.as_ref().map(|blame| blame.timestamp)
+    }
+
+    /// The chunk's text as it gets embedded, behind the headings it sits
+    /// under
+    pub fn embedding_text(&self) -> String {
+        if self.heading_path.is_empty() {
+            return self.text.clone();
+        }
+
+        format!("{}\n{}", self.heading_path.join(" > "), self.text)
+    }
+}
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Self) -> bool {
+        self.lang == other.lang
+            && self.repo_name == other.repo_name
+            && self.repo_ref == other.repo_ref
+            && self.relative_path == other.relative_path
+            && self.content_hash == other.content_hash
+            && self.text == other.text
+            && self.start_line == other.start_line
+            && self.end_line == other.end_line
+            && self.start_byte == other.start_byte
+            && self.end_byte == other.end_byte
+            && self.branches == other.branches
+            && self.heading_path == other.heading_path
+            && self.cell_kind == other.cell_kind
+            && self.blame == other.blame
+            && self.owners == other.owners
+
+        // ignoring deserialized fields that will not exist on a newly
+        // created payload
+    }
+}
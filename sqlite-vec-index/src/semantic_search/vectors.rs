@@ -0,0 +1,458 @@
+//! Chunk embeddings live in a sqlite-vec `vec0` virtual table next to the
+//! caches, so they can be written in the same transaction as the
+//! `chunk_cache` rows describing them.
+//!
+//! A repository's vectors are stored either at full precision in
+//! `chunk_vectors`, or quantised in `chunk_vectors_int8` / `chunk_vectors_bit`.
+//! Quantised vectors keep their full precision version in
+//! `chunk_vectors_full`, which we use to rescore the candidates of the
+//! quantised KNN search. `chunk_vectors_all` is a view over all of them.
+//!
+//! We use the unchecked `sqlx::query` here, since the query checker has
+//! no idea about `vec0` tables.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{Row, Sqlite};
+
+use crate::{db::sqlite::SqlDb, embedder::embedder::Embedding};
+
+use super::schema::{Payload, EMBEDDING_DIM};
+
+/// How many candidates per requested result the quantised first stage
+/// returns for rescoring
+pub(crate) const RESCORE_OVERSAMPLE: u64 = 4;
+
+const FULL_PRECISION_TABLE: &str = "chunk_vectors_full";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum VectorStorage {
+    /// f32 vectors, searched directly
+    #[default]
+    Float,
+
+    /// int8 vectors for the first stage, rescored at full precision
+    Int8,
+
+    /// Binary vectors for the first stage, rescored at full precision
+    Binary,
+}
+
+impl VectorStorage {
+    pub const ALL: [VectorStorage; 3] = [Self::Float, Self::Int8, Self::Binary];
+
+    pub fn is_quantised(&self) -> bool {
+        !matches!(self, Self::Float)
+    }
+
+    /// Size of a single vector in the KNN table
+    pub fn bytes_per_vector(&self) -> usize {
+        match self {
+            Self::Float => EMBEDDING_DIM * 4,
+            Self::Int8 => EMBEDDING_DIM,
+            Self::Binary => EMBEDDING_DIM / 8,
+        }
+    }
+
+    pub(crate) fn table(&self) -> &'static str {
+        match self {
+            Self::Float => "chunk_vectors",
+            Self::Int8 => "chunk_vectors_int8",
+            Self::Binary => "chunk_vectors_bit",
+        }
+    }
+
+    /// SQL expression turning a bound f32 vector into what the table stores
+    pub(crate) fn quantize(&self) -> &'static str {
+        match self {
+            Self::Float => "?",
+            Self::Int8 => "vec_quantize_int8(?, 'unit')",
+            Self::Binary => "vec_quantize_binary(?)",
+        }
+    }
+}
+
+/// Register sqlite-vec with every SQLite connection opened from now on.
+///
+/// This has to run before the pool behind `SqlDb` is created, otherwise
+/// the migrations creating `chunk_vectors` will fail.
+pub fn register_sqlite_vec() {
+    use rusqlite::ffi::sqlite3_auto_extension;
+    use sqlite_vec::sqlite3_vec_init;
+
+    unsafe {
+        sqlite3_auto_extension(Some(std::mem::transmute::<*const (), _>(
+            sqlite3_vec_init as *const (),
+        )));
+    }
+}
+
+pub(crate) fn encode(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+pub(crate) fn decode(blob: &[u8]) -> Embedding {
+    blob.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+pub(crate) fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let (mut dot, mut norm_a, mut norm_b) = (0f32, 0f32, 0f32);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+pub(crate) async fn insert(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    storage: VectorStorage,
+    id: &str,
+    embedding: &[f32],
+    payload: &Payload,
+) -> anyhow::Result<()> {
+    let (table, vector) = (storage.table(), storage.quantize());
+    sqlx::query(&format!(
+        "INSERT INTO {table} (chunk_id, repo_ref, content_hash, embedding, payload) \
+         VALUES (?, ?, ?, {vector}, ?)"
+    ))
+    .bind(id)
+    .bind(&payload.repo_ref)
+    .bind(&payload.content_hash)
+    .bind(encode(embedding))
+    .bind(serde_json::to_string(payload)?)
+    .execute(&mut **tx)
+    .await?;
+
+    if storage.is_quantised() {
+        sqlx::query(&format!(
+            "INSERT INTO {FULL_PRECISION_TABLE} (chunk_id, repo_ref, content_hash, embedding) \
+             VALUES (?, ?, ?, ?)"
+        ))
+        .bind(id)
+        .bind(&payload.repo_ref)
+        .bind(&payload.content_hash)
+        .bind(encode(embedding))
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    Ok(())
+}
+
+// deletes don't need to know where a repository's vectors are stored, so
+// they go through every table
+fn all_tables() -> impl Iterator<Item = &'static str> {
+    VectorStorage::ALL
+        .into_iter()
+        .map(|storage| storage.table())
+        .chain([FULL_PRECISION_TABLE])
+}
+
+pub(crate) async fn delete(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    ids: &[String],
+) -> anyhow::Result<()> {
+    for table in all_tables() {
+        for id in ids {
+            sqlx::query(&format!("DELETE FROM {table} WHERE chunk_id = ?"))
+                .bind(id)
+                .execute(&mut **tx)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete every vector which belongs to one of the file-level
+/// `content_hashes` in the repository.
+pub(crate) async fn delete_for_content_hashes(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+    content_hashes: &[String],
+) -> anyhow::Result<()> {
+    for table in all_tables() {
+        for content_hash in content_hashes {
+            sqlx::query(&format!(
+                "DELETE FROM {table} WHERE repo_ref = ? AND content_hash = ?"
+            ))
+            .bind(repo_ref)
+            .bind(content_hash)
+            .execute(&mut **tx)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn delete_for_repo(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+) -> anyhow::Result<()> {
+    for table in all_tables() {
+        sqlx::query(&format!("DELETE FROM {table} WHERE repo_ref = ?"))
+            .bind(repo_ref)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn delete_all(tx: &mut sqlx::Transaction<'_, Sqlite>) -> anyhow::Result<()> {
+    for table in all_tables() {
+        sqlx::query(&format!("DELETE FROM {table}"))
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn set_commit_hash(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    ids: &[String],
+    commit_hash: &str,
+) -> anyhow::Result<()> {
+    for storage in VectorStorage::ALL {
+        let table = storage.table();
+        for id in ids {
+            sqlx::query(&format!(
+                "UPDATE {table} SET payload = json_set(payload, '$.commit_hash', ?) \
+                 WHERE chunk_id = ?"
+            ))
+            .bind(commit_hash)
+            .bind(id)
+            .execute(&mut **tx)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Where the vectors of a repository are currently stored.
+///
+/// A repository only ever has vectors in one of the tables, since
+/// [`convert`] moves all of them at once.
+pub(crate) async fn stored_as<'c, E>(executor: E, repo_ref: &str) -> anyhow::Result<VectorStorage>
+where
+    E: sqlx::Executor<'c, Database = Sqlite>,
+{
+    let stored: Option<String> = sqlx::query_scalar(
+        "SELECT 'int8' FROM chunk_vectors_int8 WHERE repo_ref = ?1 \
+         UNION ALL SELECT 'binary' FROM chunk_vectors_bit WHERE repo_ref = ?1 \
+         LIMIT 1",
+    )
+    .bind(repo_ref)
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(match stored.as_deref() {
+        Some("int8") => VectorStorage::Int8,
+        Some("binary") => VectorStorage::Binary,
+        _ => VectorStorage::Float,
+    })
+}
+
+/// Move the vectors of a repository into a different storage.
+///
+/// Since we always keep vectors at full precision somewhere, this never
+/// needs to embed anything again.
+pub(crate) async fn convert(
+    tx: &mut sqlx::Transaction<'_, Sqlite>,
+    repo_ref: &str,
+    to: VectorStorage,
+) -> anyhow::Result<usize> {
+    if stored_as(&mut **tx, repo_ref).await? == to {
+        return Ok(0);
+    }
+
+    let rows = sqlx::query(
+        "SELECT chunk_id, payload, full_embedding FROM chunk_vectors_all WHERE repo_ref = ?",
+    )
+    .bind(repo_ref)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    delete_for_repo(tx, repo_ref).await?;
+    for row in rows.iter() {
+        let payload: Payload = serde_json::from_str(row.try_get("payload")?)?;
+        let embedding = decode(row.try_get("full_embedding")?);
+        insert(tx, to, row.try_get("chunk_id")?, &embedding, &payload).await?;
+    }
+
+    Ok(rows.len())
+}
+
+/// K-nearest-neighbour search within a single repository.
+///
+/// Scores are cosine similarities, so higher is better.
+pub(crate) async fn search(
+    sql: &SqlDb,
+    repo_ref: &str,
+    vector: &[f32],
+    k: u64,
+) -> anyhow::Result<Vec<Payload>> {
+    let storage = stored_as(sql.as_ref(), repo_ref).await?;
+    if storage.is_quantised() {
+        return search_quantised(sql, storage, repo_ref, vector, k).await;
+    }
+
+    let rows = sqlx::query(
+        "SELECT chunk_id, distance, embedding, payload FROM chunk_vectors \
+         WHERE embedding MATCH ? AND k = ? AND repo_ref = ? \
+         ORDER BY distance",
+    )
+    .bind(encode(vector))
+    .bind(k as i64)
+    .bind(repo_ref)
+    .fetch_all(sql.as_ref())
+    .await?;
+
+    rows.into_iter()
+        .map(|row| {
+            let mut payload: Payload = serde_json::from_str(row.try_get("payload")?)?;
+            let distance: f64 = row.try_get("distance")?;
+
+            payload.id = Some(row.try_get("chunk_id")?);
+            payload.embedding = Some(decode(row.try_get("embedding")?));
+            payload.score = Some(1.0 - distance as f32);
+            Ok(payload)
+        })
+        .collect()
+}
+
+/// Run the KNN over the quantised vectors, then rescore the candidates
+/// with their full precision vectors.
+async fn search_quantised(
+    sql: &SqlDb,
+    storage: VectorStorage,
+    repo_ref: &str,
+    vector: &[f32],
+    k: u64,
+) -> anyhow::Result<Vec<Payload>> {
+    let (table, query) = (storage.table(), storage.quantize());
+    let candidates = sqlx::query(&format!(
+        "SELECT chunk_id, payload FROM {table} \
+         WHERE embedding MATCH {query} AND k = ? AND repo_ref = ? \
+         ORDER BY distance"
+    ))
+    .bind(encode(vector))
+    .bind((k * RESCORE_OVERSAMPLE) as i64)
+    .bind(repo_ref)
+    .fetch_all(sql.as_ref())
+    .await?;
+
+    let ids = candidates
+        .iter()
+        .map(|row| row.try_get::<String, _>("chunk_id"))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let full = full_precision(sql, &ids).await?;
+
+    let mut results = candidates
+        .into_iter()
+        .map(|row| {
+            let mut payload: Payload = serde_json::from_str(row.try_get("payload")?)?;
+            let id: String = row.try_get("chunk_id")?;
+            let embedding = full.get(&id).cloned().unwrap_or_default();
+
+            payload.score = Some(cosine_similarity(vector, &embedding));
+            payload.embedding = Some(embedding);
+            payload.id = Some(id);
+            Ok(payload)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    results.sort_by(|a, b| {
+        let score = |p: &Payload| p.score.unwrap_or_default();
+        score(b).total_cmp(&score(a))
+    });
+    results.truncate(k as usize);
+    Ok(results)
+}
+
+/// The stored chunks of `relative_path` which overlap lines
+/// `start_line..=end_line`, with their full precision vectors.
+pub(crate) async fn chunks_overlapping(
+    sql: &SqlDb,
+    repo_ref: &str,
+    relative_path: &str,
+    start_line: u64,
+    end_line: u64,
+) -> anyhow::Result<Vec<Payload>> {
+    sqlx::query(
+        "SELECT chunk_id, payload, full_embedding FROM chunk_vectors_all \
+         WHERE repo_ref = ? AND json_extract(payload, '$.relative_path') = ? \
+         AND json_extract(payload, '$.start_line') <= ? \
+         AND json_extract(payload, '$.end_line') >= ? \
+         ORDER BY json_extract(payload, '$.start_line')",
+    )
+    .bind(repo_ref)
+    .bind(relative_path)
+    .bind(end_line as i64)
+    .bind(start_line as i64)
+    .fetch_all(sql.as_ref())
+    .await?
+    .into_iter()
+    .map(|row| {
+        let mut payload: Payload = serde_json::from_str(row.try_get("payload")?)?;
+        payload.id = Some(row.try_get("chunk_id")?);
+        payload.embedding = Some(decode(row.try_get("full_embedding")?));
+        Ok(payload)
+    })
+    .collect()
+}
+
+/// The full precision vectors of `ids`, wherever they are stored.
+///
+/// These are looked up one by one by primary key, going through the view
+/// would scan every table behind it.
+pub(crate) async fn full_precision(
+    sql: &SqlDb,
+    ids: &[String],
+) -> anyhow::Result<HashMap<String, Embedding>> {
+    let mut conn = sql.acquire().await?;
+    let mut output = HashMap::with_capacity(ids.len());
+
+    for id in ids {
+        let full = sqlx::query(&format!(
+            "SELECT embedding FROM {FULL_PRECISION_TABLE} WHERE chunk_id = ?"
+        ))
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        // vectors stored as floats have no copy in the full precision table
+        let row = match full {
+            Some(row) => Some(row),
+            None => {
+                sqlx::query(&format!(
+                    "SELECT embedding FROM {} WHERE chunk_id = ?",
+                    VectorStorage::Float.table()
+                ))
+                .bind(id)
+                .fetch_optional(&mut *conn)
+                .await?
+            }
+        };
+
+        if let Some(row) = row {
+            output.insert(id.clone(), decode(row.try_get("embedding")?));
+        }
+    }
+
+    Ok(output)
+}
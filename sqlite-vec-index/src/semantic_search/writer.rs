@@ -0,0 +1,52 @@
+//! The process keeping an index up to date.
+//!
+//! Only one process gets to write to the database, see
+//! [`sqlite`](crate::db::sqlite). A [`SearchWriter`] is what that process
+//! builds its application from: the writer's pool, with the semantic client
+//! and the tantivy indexes on top of it. The writer lock is part of it, so
+//! the application has to hold on to it for as long as it runs.
+
+use std::{path::Path, sync::Arc};
+
+use crate::{
+    application::config::configuration::Configuration,
+    db::sqlite::{self, SqlDb, WriterDb},
+    indexes::Indexes,
+};
+
+use super::client::SemanticClient;
+
+pub struct SearchWriter {
+    /// Missing if the embedder couldn't be loaded, which leaves us with the
+    /// lexical indexes
+    pub semantic: Option<SemanticClient>,
+    pub indexes: Arc<Indexes>,
+    db: WriterDb,
+}
+
+impl SearchWriter {
+    /// Open the database at `db_path` for writing, failing with
+    /// [`DbError::WriterActive`](sqlite::DbError::WriterActive) if another
+    /// process is writing to it already.
+    pub async fn open(config: Arc<Configuration>, db_path: &Path) -> anyhow::Result<Self> {
+        let db = sqlite::open_writer(db_path).await?;
+        let semantic = SemanticClient::new(config.clone(), db.sql.clone()).await;
+        let indexes = Indexes::new(&config, db.sql.clone(), semantic.clone()).await?;
+
+        Ok(Self {
+            semantic,
+            indexes: Arc::new(indexes),
+            db,
+        })
+    }
+
+    /// The writer's pool. Clones of it must not outlive `self`, as nothing
+    /// keeps other writers out once the lock is gone.
+    pub fn sql(&self) -> &SqlDb {
+        &self.db.sql
+    }
+
+    pub fn lock_path(&self) -> &Path {
+        self.db.lock.path()
+    }
+}